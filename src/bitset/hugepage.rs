@@ -0,0 +1,450 @@
+// hugepage.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ptr;
+use std::slice;
+use std::marker::{Send, Sync};
+use std::sync::atomic::{AtomicU64, Ordering};
+use libc::{self, c_void};
+
+use super::{PageSize, Ones};
+use crate::numa_threadpool::Workers;
+
+// see stable.rs for API documentation
+
+#[cfg(feature = "numa")]
+use std::io;
+#[cfg(feature = "numa")]
+use libc::{c_ulong, c_uint, c_int};
+#[cfg(feature = "numa")]
+use super::NodePlacement;
+#[cfg(feature = "numa")]
+use crate::topology::Topology;
+
+const BITS: usize = 64;
+
+/// A bitset backed by an anonymous mapping, using the largest page size the system
+/// will give us. Since the filters are far larger than the TLB reach of regular pages,
+/// hugepages make a large difference to the (memory-bound) runtime.
+pub struct BitSet {
+    arena: *mut AtomicU64,
+    words: usize,
+    mapped: usize,
+    page_size: PageSize
+}
+
+unsafe impl Send for BitSet {}
+unsafe impl Sync for BitSet {}
+
+impl Drop for BitSet {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.arena as *mut c_void,
+                self.mapped
+            );
+        }
+    }
+}
+
+#[cfg(feature = "numa")]
+const MPOL_BIND: c_int = 2;
+#[cfg(feature = "numa")]
+const MPOL_INTERLEAVE: c_int = 3;
+
+fn round_up(len: usize, page: usize) -> usize {
+    len.div_ceil(page) * page
+}
+
+fn map_anonymous(len: usize, flags: libc::c_int) -> Option<*mut c_void> {
+    use libc::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, MAP_FAILED};
+
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_ANONYMOUS | MAP_PRIVATE | flags,
+            -1, 0
+        )
+    };
+
+    if ptr == MAP_FAILED {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+/// Maps at least `len` zeroed bytes, trying 1 GiB hugepages, then 2 MiB hugepages,
+/// then transparent hugepages, then regular pages.
+/// Returns the mapping, the number of bytes actually mapped, and the page size we got.
+fn allocate(len: usize) -> (*mut c_void, usize, PageSize) {
+    use libc::{MAP_HUGETLB, MAP_HUGE_1GB, MAP_HUGE_2MB, MADV_HUGEPAGE};
+
+    let explicit = [
+        (PageSize::Huge1G, MAP_HUGETLB | MAP_HUGE_1GB),
+        (PageSize::Huge2M, MAP_HUGETLB | MAP_HUGE_2MB)
+    ];
+
+    for &(page_size, flags) in explicit.iter() {
+        // don't burn a whole hugepage on a small set
+        if len < page_size.bytes() {
+            continue;
+        }
+
+        let mapped = round_up(len, page_size.bytes());
+        if let Some(ptr) = map_anonymous(mapped, flags) {
+            return (ptr, mapped, page_size);
+        }
+    }
+
+    let mapped = round_up(len, PageSize::Regular.bytes());
+    let ptr = map_anonymous(mapped, 0).unwrap_or_else(||
+        panic!("Out of memory: couldn't map {} bytes", mapped)
+    );
+
+    if mapped >= PageSize::Huge2M.bytes()
+        && unsafe { libc::madvise(ptr, mapped, MADV_HUGEPAGE) } == 0
+    {
+        return (ptr, mapped, PageSize::Transparent);
+    }
+
+    (ptr, mapped, PageSize::Regular)
+}
+
+impl BitSet {
+    pub fn new(capacity: usize) -> Self {
+        let words = capacity.div_ceil(BITS).max(1);
+        let (ptr, mapped, page_size) = allocate(words * (BITS / 8));
+
+        BitSet { arena: ptr as *mut AtomicU64, words, mapped, page_size }
+    }
+
+    pub fn from_words(words: &[u64]) -> Self {
+        let bitset = BitSet::new(words.len() * BITS);
+        super::copy_words(bitset.as_words(), words);
+
+        bitset
+    }
+
+    /// the kind of pages backing this bitset
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// the number of bits the set can hold (the requested capacity, rounded up to a whole word)
+    pub fn capacity(&self) -> usize {
+        self.words * BITS
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count_ones() == 0
+    }
+
+    pub fn as_words(&self) -> &[AtomicU64] {
+        unsafe { slice::from_raw_parts(self.arena, self.words) }
+    }
+
+    pub fn clear(&self) {
+        super::clear_words(self.as_words());
+    }
+
+    pub fn insert(&self, index: usize) {
+        let block = index / BITS;
+        let bit   = index % BITS;
+
+        self.as_words()[block].fetch_or(1 << bit, Ordering::Relaxed);
+    }
+
+    pub fn insert_exclusive(&self, index: usize) {
+        let block = index / BITS;
+        let bit   = index % BITS;
+
+        let word = &self.as_words()[block];
+        word.store(word.load(Ordering::Relaxed) | (1u64 << bit), Ordering::Relaxed);
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        let block = index / BITS;
+        let bit   = index % BITS;
+
+        self.as_words()[block].load(Ordering::Relaxed) & (1 << bit) != 0
+    }
+
+    #[inline(always)]
+    pub fn prefetch(&self, index: usize) {
+        super::prefetch_word(&self.as_words()[index / BITS]);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        super::count_words(self.as_words())
+    }
+
+    pub fn iter_ones(&self) -> Ones<'_> {
+        Ones::new(self.as_words())
+    }
+
+    pub fn union_with(&self, other: &Self) {
+        super::union_words(self.as_words(), other.as_words());
+    }
+
+    pub fn intersect_with(&self, other: &Self) {
+        super::intersect_words(self.as_words(), other.as_words());
+    }
+
+    pub fn cross_or(&mut self, other: &mut Self, workers: &Workers) {
+        use std::time::Instant;
+        use std::slice::from_raw_parts_mut;
+
+        assert_eq!(self.words, other.words);
+
+        let now = Instant::now();
+
+        let pool = workers.install(|_| ());
+
+        // 128MiB jobs
+        const JOB_WORDS : usize = (128usize << 20) / (BITS / 8);
+
+        let mut offset = 0;
+        while offset < self.words {
+            let len = JOB_WORDS.min(self.words - offset);
+
+            // we hold both bitsets mutably, so nobody else can be touching the words
+            let slice_a = unsafe {from_raw_parts_mut((self.arena as *mut u64).add(offset), len)};
+            let slice_b = unsafe {from_raw_parts_mut((other.arena as *mut u64).add(offset), len)};
+
+            pool.execute(move|_| {
+                cross_or_slice(slice_a, slice_b);
+            });
+
+            offset += len;
+        }
+
+        // the slices borrow from both bitsets, so every task has to be done before we return
+        pool.join().unwrap_or_else(|panic| panic!("[cross_or] {}", panic));
+
+        let elapsed = now.elapsed();
+        println!("Merge elapsed: {}s, {}ms", elapsed.as_secs(), elapsed.subsec_millis());
+    }
+}
+
+#[cfg(feature = "numa")]
+const WORD_BITS: usize = 8 * std::mem::size_of::<c_ulong>();
+
+/// an mbind nodemask with the bits of `node_ids` set, and no longer than it has to be
+#[cfg(feature = "numa")]
+fn nodemask(node_ids: &[u32]) -> Vec<c_ulong> {
+    let words = node_ids.iter().map(|&node| node as usize / WORD_BITS + 1).max().unwrap_or(1);
+    let mut nodemask = vec![0 as c_ulong; words];
+    for &node in node_ids.iter() {
+        let node = node as usize;
+        nodemask[node / WORD_BITS] |= 1 << (node % WORD_BITS);
+    }
+
+    nodemask
+}
+
+#[cfg(feature = "numa")]
+impl BitSet {
+    /// Creates a bitset whose memory lives on NUMA node `node_id`. The mapping is bound to
+    /// the node before any page is touched and then prefaulted in parallel, so nothing ever
+    /// has to be migrated. If the kernel refuses the binding we carry on with a warning;
+    /// `check_node` reports where the pages really ended up.
+    pub fn new_on_node(capacity: usize, node_id: u32) -> Self {
+        let bitset = BitSet::new(capacity);
+
+        if let Err(err) = bitset.bind(MPOL_BIND, &nodemask(&[node_id])) {
+            println!("Warning: couldn't bind bitset to node {}: {}", node_id, err);
+        }
+        bitset.prefault();
+
+        bitset
+    }
+
+    /// Creates a bitset whose pages are spread round-robin over every online node, so that
+    /// no single node's memory or bandwidth is the limit.
+    pub fn new_interleaved(capacity: usize) -> Self {
+        let bitset = BitSet::new(capacity);
+
+        // mbind refuses a mask with nodes that aren't online
+        let nodemask = nodemask(&Topology::discover().node_ids());
+
+        if let Err(err) = bitset.bind(MPOL_INTERLEAVE, &nodemask) {
+            println!("Warning: couldn't interleave bitset: {}", err);
+        }
+        bitset.prefault();
+
+        bitset
+    }
+
+    /// applies the memory policy `mode` over `nodemask` to the whole mapping
+    fn bind(&self, mode: c_int, nodemask: &[c_ulong]) -> io::Result<()> {
+        // the kernel expects one more than the number of bits in the mask
+        let maxnode = (nodemask.len() * WORD_BITS + 1) as c_ulong;
+
+        let rv = unsafe {
+            libc::syscall(libc::SYS_mbind, self.arena as *mut c_void, self.mapped as c_ulong,
+                mode, nodemask.as_ptr(), maxnode, 0 as c_uint)
+        };
+
+        if rv != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// writes to every page from all CPUs at once, so the (bound) allocation happens now
+    /// and in parallel, rather than on first insert
+    fn prefault(&self) {
+        let words = self.as_words();
+        let page_words = self.page_size.bytes() / (BITS / 8);
+        let n_pages = words.len().div_ceil(page_words);
+
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let per_thread = n_pages.div_ceil(threads);
+
+        std::thread::scope(|scope| {
+            for first in (0..n_pages).step_by(per_thread) {
+                scope.spawn(move || {
+                    for page in first..(first + per_thread).min(n_pages) {
+                        // the words are already zero, but any write faults the page in
+                        words[page * page_words].store(0, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Asks the kernel which node each page is on, and reports any that aren't on `node_id`.
+    /// Regular pages are sampled once per 2MiB.
+    pub fn check_node(&self, node_id: u32) -> NodePlacement {
+        let stride = self.page_size.bytes().max(PageSize::Huge2M.bytes());
+        let base = self.arena as *mut u8;
+
+        let mut pages: Vec<*mut c_void> = (0..self.mapped).step_by(stride)
+            .map(|offset| unsafe { base.add(offset) } as *mut c_void)
+            .collect();
+        let mut status: Vec<c_int> = vec![0; pages.len()];
+
+        // with no target nodes, move_pages only reports where each page is
+        let rv = unsafe {
+            libc::syscall(libc::SYS_move_pages, 0, pages.len() as c_ulong, pages.as_mut_ptr(),
+                ptr::null::<c_int>(), status.as_mut_ptr(), 0)
+        };
+
+        if rv != 0 {
+            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            status.iter_mut().for_each(|page_status| *page_status = -errno);
+        }
+
+        let misplaced = status.iter().enumerate()
+            .filter(|&(_, &page_node)| page_node != node_id as c_int)
+            .map(|(page, &page_node)| (page * stride, page_node))
+            .collect();
+
+        NodePlacement { node: node_id, pages_checked: pages.len(), misplaced }
+    }
+}
+
+fn cross_or_slice(a: &mut [u64], b: &mut [u64]) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx512f") {
+            unsafe {cross_or_avx512(a, b)};
+            return;
+        } else if is_x86_feature_detected!("avx2") {
+            unsafe {cross_or_avx2(a,b)};
+            return;
+        }
+    }
+
+    cross_or_impl(a, b);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn cross_or_avx2(a: &mut [u64], b: &mut [u64]) {
+    cross_or_impl(a, b);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn cross_or_avx512(a: &mut [u64], b: &mut [u64]) {
+    cross_or_impl(a, b);
+}
+
+fn cross_or_impl(slice_a: &mut [u64], slice_b: &mut [u64]) {
+    // optimizer hint
+    if slice_a.len() != slice_b.len() {
+        unreachable!();
+    }
+
+    for (a, b) in slice_a.iter_mut().zip(slice_b.iter_mut()) {
+        let val = *a | *b;
+        *a = val;
+        *b = val;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "numa")]
+    #[test]
+    pub fn test_nodes() {
+        let bitset = BitSet::new_on_node(8usize << 30, 0);
+        let placement = bitset.check_node(0);
+
+        assert!(placement.pages_checked > 0);
+        assert!(placement.is_local(), "{:?}", placement);
+    }
+
+    #[cfg(feature = "numa")]
+    #[test]
+    pub fn test_interleaved() {
+        let bitset = BitSet::new_interleaved(1usize << 30);
+        bitset.insert(12345);
+
+        assert!(bitset.contains(12345));
+        assert!(bitset.check_node(0).pages_checked > 0);
+    }
+
+    #[cfg(feature = "numa")]
+    #[test]
+    pub fn test_nodemask() {
+        assert_eq!(nodemask(&[0]), vec![1]);
+        assert_eq!(nodemask(&[0, 1, 3]), vec![0b1011]);
+        assert_eq!(nodemask(&[1, WORD_BITS as u32 + 2]), vec![0b10, 0b100]);
+    }
+
+    #[test]
+    pub fn test_small_set_uses_regular_pages() {
+        let bitset = BitSet::new(128);
+        assert_eq!(bitset.page_size(), PageSize::Regular);
+    }
+
+    #[test]
+    pub fn test_cross_or() {
+        let mut bitset1 = BitSet::new(1usize << 30);
+        let mut bitset2 = BitSet::new(1usize << 30);
+
+        for i in 0..16 {
+            bitset1.insert(i << 26);
+            bitset2.insert((i << 26) + 1);
+        }
+
+        bitset1.cross_or(&mut bitset2, &Workers::new());
+
+        for i in 0..16 {
+            for bitset in [&bitset1, &bitset2].iter() {
+                assert!(bitset.contains(i << 26));
+                assert!(bitset.contains((i << 26) + 1));
+                assert!(!bitset.contains((i << 26) + 2));
+            }
+        }
+    }
+}
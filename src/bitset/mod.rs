@@ -1,18 +1,327 @@
 // mod.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 mod stable;
 
-#[cfg(feature = "unstable")]
-mod unstable;
+#[cfg(target_os = "linux")]
+mod hugepage;
 
-#[cfg(feature = "unstable")]
-pub use self::unstable::*;
+#[cfg(target_os = "linux")]
+pub use self::hugepage::*;
 
-#[cfg(not(feature = "unstable"))]
+#[cfg(not(target_os = "linux"))]
 pub use self::stable::*;
 
-#[cfg(not(all(feature = "unstable", feature = "numa")))]
+/// The kind of memory backing a bitset, from most to least preferred
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    /// explicit 1 GiB hugetlb pages
+    Huge1G,
+    /// explicit 2 MiB hugetlb pages
+    Huge2M,
+    /// regular pages, with transparent hugepages requested through madvise
+    Transparent,
+    /// regular pages
+    Regular
+}
+
+impl PageSize {
+    /// the size of a single page, in bytes
+    pub fn bytes(&self) -> usize {
+        match self {
+            PageSize::Huge1G => 1 << 30,
+            PageSize::Huge2M => 1 << 21,
+            PageSize::Transparent | PageSize::Regular => 1 << 12
+        }
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self {
+            PageSize::Huge1G => "1GiB hugepages",
+            PageSize::Huge2M => "2MiB hugepages",
+            PageSize::Transparent => "transparent hugepages",
+            PageSize::Regular => "regular pages"
+        };
+
+        write!(f, "{}", desc)
+    }
+}
+
+/// Where the kernel actually put a bitset's memory, as reported by `BitSet::check_node`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodePlacement {
+    /// the node the memory was meant to be on
+    pub node: u32,
+    /// how many pages were asked about
+    pub pages_checked: usize,
+    /// (byte offset, node) for each checked page that isn't on `node`. The node is a
+    /// negative errno when the kernel couldn't say, e.g. -ENOENT for a page not yet faulted in.
+    pub misplaced: Vec<(usize, i32)>
+}
+
+impl NodePlacement {
+    pub fn is_local(&self) -> bool {
+        self.misplaced.is_empty()
+    }
+}
+
+impl fmt::Display for NodePlacement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.pages_checked == 0 {
+            return write!(f, "placement not checked");
+        }
+
+        write!(f, "{}/{} pages on node {}",
+            self.pages_checked - self.misplaced.len(), self.pages_checked, self.node)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "numa")))]
 impl BitSet {
-    pub fn on_node(self, _node_id: u32) -> Self { self }
+    pub fn new_on_node(capacity: usize, _node_id: u32) -> Self {
+        BitSet::new(capacity)
+    }
+
+    pub fn new_interleaved(capacity: usize) -> Self {
+        BitSet::new(capacity)
+    }
+
+    pub fn check_node(&self, node_id: u32) -> NodePlacement {
+        NodePlacement { node: node_id, pages_checked: 0, misplaced: Vec::new() }
+    }
+}
+
+/// Iterator over the indices of the set bits in a slice of words, in increasing order.
+/// Words are loaded as the iterator reaches them, so concurrent inserts may or may not be seen.
+pub struct Ones<'a> {
+    words: &'a [AtomicU64],
+    next_word: usize,
+    base: usize,
+    current: u64
+}
+
+impl<'a> Ones<'a> {
+    fn new(words: &'a [AtomicU64]) -> Self {
+        Ones { words, next_word: 0, base: 0, current: 0 }
+    }
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.current == 0 {
+            let word = self.words.get(self.next_word)?;
+
+            self.current = word.load(Ordering::Relaxed);
+            self.base = self.next_word * 64;
+            self.next_word += 1;
+        }
+
+        let bit = self.current.trailing_zeros() as usize;
+        // clear the lowest set bit
+        self.current &= self.current - 1;
+
+        Some(self.base + bit)
+    }
+}
+
+// Word-level helpers shared by both implementations
+
+/// hints the CPU to pull the cache line holding `word` in ahead of an upcoming access
+#[inline(always)]
+fn prefetch_word(word: &AtomicU64) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(word.as_ptr() as *const i8);
+    }
+
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        use std::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(word.as_ptr() as *const i8);
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let _ = word;
+}
+
+fn copy_words(dst: &[AtomicU64], src: &[u64]) {
+    assert_eq!(dst.len(), src.len());
+
+    for (d, s) in dst.iter().zip(src.iter()) {
+        d.store(*s, Ordering::Relaxed);
+    }
+}
+
+fn clear_words(words: &[AtomicU64]) {
+    for word in words.iter() {
+        word.store(0, Ordering::Relaxed);
+    }
+}
+
+fn count_words(words: &[AtomicU64]) -> usize {
+    words.iter().map(|word| word.load(Ordering::Relaxed).count_ones() as usize).sum()
+}
+
+fn union_words(dst: &[AtomicU64], src: &[AtomicU64]) {
+    assert_eq!(dst.len(), src.len());
+
+    for (d, s) in dst.iter().zip(src.iter()) {
+        let val = s.load(Ordering::Relaxed);
+        // skip the atomic read-modify-write when it can't change anything
+        if val != 0 {
+            d.fetch_or(val, Ordering::Relaxed);
+        }
+    }
+}
+
+fn intersect_words(dst: &[AtomicU64], src: &[AtomicU64]) {
+    assert_eq!(dst.len(), src.len());
+
+    for (d, s) in dst.iter().zip(src.iter()) {
+        let val = s.load(Ordering::Relaxed);
+        if val != !0 {
+            d.fetch_and(val, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    /// Checks that a BitSet implementation provides the common API with the common semantics
+    macro_rules! conformance {
+        ($name:ident, $bitset:path) => {
+            mod $name {
+                use $bitset as BitSet;
+
+                #[test]
+                pub fn test_capacity() {
+                    assert_eq!(BitSet::new(1).capacity(), 64);
+                    assert_eq!(BitSet::new(64).capacity(), 64);
+                    assert_eq!(BitSet::new(65).capacity(), 128);
+                    assert_eq!(BitSet::new(1000).as_words().len(), 16);
+                }
+
+                #[test]
+                pub fn test_insert_contains() {
+                    let bitset = BitSet::new(1000);
+                    assert!(bitset.is_empty());
+
+                    for i in (0..1000).step_by(7) {
+                        bitset.insert(i);
+                    }
+
+                    for i in 0..1000 {
+                        assert_eq!(bitset.contains(i), i % 7 == 0, "bit {}", i);
+                    }
+
+                    assert!(!bitset.is_empty());
+                    assert_eq!(bitset.count_ones(), (0..1000).step_by(7).count());
+                }
+
+                #[test]
+                pub fn test_insert_exclusive() {
+                    let bitset = BitSet::new(128);
+
+                    bitset.insert(1);
+                    bitset.insert_exclusive(2);
+                    bitset.insert_exclusive(100);
+
+                    assert_eq!(bitset.iter_ones().collect::<Vec<_>>(), vec![1, 2, 100]);
+                }
+
+                #[test]
+                pub fn test_iter_ones() {
+                    let bitset = BitSet::new(4096);
+                    let expected = vec![0, 1, 63, 64, 65, 127, 2000, 4095];
+
+                    for i in expected.iter().rev() {
+                        bitset.insert(*i);
+                    }
+
+                    assert_eq!(bitset.iter_ones().collect::<Vec<_>>(), expected);
+                    assert_eq!(BitSet::new(4096).iter_ones().next(), None);
+                }
+
+                #[test]
+                pub fn test_clear() {
+                    let bitset = BitSet::new(256);
+                    bitset.insert(3);
+                    bitset.insert(200);
+
+                    bitset.clear();
+
+                    assert!(bitset.is_empty());
+                    assert!(!bitset.contains(3));
+                }
+
+                #[test]
+                pub fn test_union_intersect() {
+                    let a = BitSet::new(256);
+                    let b = BitSet::new(256);
+
+                    for i in [1, 2, 100, 255].iter() {
+                        a.insert(*i);
+                    }
+                    for i in [2, 3, 100, 200].iter() {
+                        b.insert(*i);
+                    }
+
+                    let union = BitSet::from_words(&words(&a));
+                    union.union_with(&b);
+                    assert_eq!(union.iter_ones().collect::<Vec<_>>(), vec![1, 2, 3, 100, 200, 255]);
+
+                    let intersection = BitSet::from_words(&words(&a));
+                    intersection.intersect_with(&b);
+                    assert_eq!(intersection.iter_ones().collect::<Vec<_>>(), vec![2, 100]);
+                }
+
+                #[test]
+                pub fn test_words_round_trip() {
+                    let bitset = BitSet::new(512);
+                    for i in [5, 64, 300, 511].iter() {
+                        bitset.insert(*i);
+                    }
+
+                    let copy = BitSet::from_words(&words(&bitset));
+
+                    assert_eq!(copy.capacity(), bitset.capacity());
+                    assert_eq!(words(&copy), words(&bitset));
+                    assert_eq!(copy.as_words()[1].load(std::sync::atomic::Ordering::Relaxed), 1);
+                }
+
+                #[test]
+                pub fn test_cross_or() {
+                    let mut a = BitSet::new(1 << 16);
+                    let mut b = BitSet::new(1 << 16);
+
+                    a.insert(10);
+                    b.insert(60000);
+
+                    a.cross_or(&mut b, &crate::numa_threadpool::Workers::new());
+
+                    assert_eq!(words(&a), words(&b));
+                    assert_eq!(a.iter_ones().collect::<Vec<_>>(), vec![10, 60000]);
+                }
+
+                fn words(bitset: &BitSet) -> Vec<u64> {
+                    bitset.as_words().iter()
+                        .map(|word| word.load(std::sync::atomic::Ordering::Relaxed))
+                        .collect()
+                }
+            }
+        }
+    }
+
+    conformance!(stable, super::super::stable::BitSet);
+
+    #[cfg(target_os = "linux")]
+    conformance!(hugepage, super::super::hugepage::BitSet);
 }
@@ -1,56 +1,128 @@
 // stable.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-fn usize_bits() -> usize {
-    usize::max_value().count_ones() as usize
-}
+use super::{PageSize, Ones};
+use crate::numa_threadpool::Workers;
+
+const BITS: usize = 64;
 
 pub struct BitSet {
-    bits: Vec<AtomicUsize>
+    bits: Vec<AtomicU64>
 }
 
 impl BitSet {
     /// creates a new bitset with the specified size (in bits)
     pub fn new(capacity: usize) -> Self {
-        let capacity_blocks = (capacity + usize_bits() - 1) / usize_bits();
+        let capacity_blocks = capacity.div_ceil(BITS).max(1);
         let mut bits = Vec::with_capacity(capacity_blocks);
 
         // initially, the bitset is all 0s
         for _ in 0..capacity_blocks {
-            bits.push(AtomicUsize::new(0));
+            bits.push(AtomicU64::new(0));
         }
 
-        return BitSet { bits };
+        BitSet { bits }
+    }
+
+    /// creates a bitset holding a copy of `words`, as returned by `as_words`
+    pub fn from_words(words: &[u64]) -> Self {
+        let bitset = BitSet::new(words.len() * BITS);
+        super::copy_words(bitset.as_words(), words);
+
+        bitset
+    }
+
+    /// the kind of pages backing this bitset; the heap never gives us hugepages
+    pub fn page_size(&self) -> PageSize {
+        PageSize::Regular
+    }
+
+    /// the number of bits the set can hold (the requested capacity, rounded up to a whole word)
+    pub fn capacity(&self) -> usize {
+        self.bits.len() * BITS
+    }
+
+    /// true if no bits are set
+    pub fn is_empty(&self) -> bool {
+        self.count_ones() == 0
+    }
+
+    /// the underlying words; bit `i` is bit `i % 64` of word `i / 64`
+    pub fn as_words(&self) -> &[AtomicU64] {
+        &self.bits
+    }
+
+    /// unsets every bit
+    pub fn clear(&self) {
+        super::clear_words(self.as_words());
     }
 
     /// sets the bit at index `index`
     pub fn insert(&self, index: usize) {
-        let block = index / usize_bits();
-        let bit   = index % usize_bits();
+        let block = index / BITS;
+        let bit   = index % BITS;
 
-        self.bits[block].fetch_or(1usize << bit, Ordering::Relaxed);
+        self.bits[block].fetch_or(1u64 << bit, Ordering::Relaxed);
+    }
+
+    /// sets the bit at index `index` with a plain load and store instead of an atomic
+    /// read-modify-write. The caller must make sure nobody else is writing to the same
+    /// word at the same time, or their bits may be lost.
+    pub fn insert_exclusive(&self, index: usize) {
+        let block = index / BITS;
+        let bit   = index % BITS;
+
+        let word = &self.bits[block];
+        word.store(word.load(Ordering::Relaxed) | (1u64 << bit), Ordering::Relaxed);
     }
 
     /// checks if the bit at index `index` is set
     pub fn contains(&self, index: usize) -> bool {
-        let block = index / usize_bits();
-        let bit   = index % usize_bits();
+        let block = index / BITS;
+        let bit   = index % BITS;
+
+        self.bits[block].load(Ordering::Relaxed) & (1u64 << bit) != 0
+    }
+
+    /// starts pulling the bit at index `index` into cache, so that a later
+    /// `insert` or `contains` on it doesn't stall on memory
+    #[inline(always)]
+    pub fn prefetch(&self, index: usize) {
+        super::prefetch_word(&self.bits[index / BITS]);
+    }
 
-        return self.bits[block].load(Ordering::Relaxed) & (1usize << bit) != 0;
+    /// the number of set bits
+    pub fn count_ones(&self) -> usize {
+        super::count_words(self.as_words())
     }
 
-    /// given inputs a and b, results in a = a|b and b=a|b
-    pub fn cross_or(&mut self, other: &mut Self) {
-        for (a, b) in self.bits.iter().zip(other.bits.iter()) {
-            let val_a = a.load(Ordering::Relaxed);
-            let val_b = b.load(Ordering::Relaxed);
+    /// iterates over the indices of the set bits, in increasing order
+    pub fn iter_ones(&self) -> Ones<'_> {
+        Ones::new(self.as_words())
+    }
+
+    /// self = self | other
+    pub fn union_with(&self, other: &Self) {
+        super::union_words(self.as_words(), other.as_words());
+    }
 
-            let val = val_a | val_b;
+    /// self = self & other
+    pub fn intersect_with(&self, other: &Self) {
+        super::intersect_words(self.as_words(), other.as_words());
+    }
 
-            a.store(val, Ordering::Relaxed);
-            b.store(val, Ordering::Relaxed);
+    /// given inputs a and b, results in a = a|b and b=a|b.
+    /// This is a single pass on the calling thread; `_workers` is there to match the hugepage version.
+    pub fn cross_or(&mut self, other: &mut Self, _workers: &Workers) {
+        assert_eq!(self.bits.len(), other.bits.len());
+
+        for (a, b) in self.bits.iter_mut().zip(other.bits.iter_mut()) {
+            let val = *a.get_mut() | *b.get_mut();
+
+            *a.get_mut() = val;
+            *b.get_mut() = val;
         }
     }
-}
\ No newline at end of file
+}
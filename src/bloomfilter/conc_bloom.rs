@@ -2,33 +2,89 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::hash::{Hasher, Hash, BuildHasher};
-use std::collections::hash_map::RandomState;
+use std::collections::hash_map::DefaultHasher;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+
+use crate::bitset::{BitSet, PageSize, NodePlacement};
+use crate::numa_threadpool::Workers;
+
+/// How inserted values reach the filter's bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InsertMode {
+    /// each bit is set directly with an atomic fetch_or on the shared filter
+    Atomic,
+    /// The filter is split into `regions` equal regions, chosen by the high bits of the
+    /// bit index. Workers hash their values into private `RegionBuckets`, and each region
+    /// is then written by a single worker with plain stores (see `put_region`).
+    /// `bloom_t1` takes `regions: 0` to mean one region per worker.
+    Partitioned { regions: usize }
+}
+
+/// Where a filter's memory lives on a NUMA machine
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// one full copy of the filter per node, each written by that node's threads and
+    /// merged at the end, so that every lookup is node-local
+    Replicate,
+    /// a single copy with its pages interleaved across all nodes by the kernel
+    Interleave,
+    /// a single copy, entirely on the given node
+    Bind(u32)
+}
+
+/// One of a filter's hash functions. Unlike `RandomState`, its key can be written down, so
+/// a saved filter can be read back and queried in a later run (of the same build: std
+/// doesn't promise `DefaultHasher` stays the same between Rust releases).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct SeededState {
+    seed: u64
+}
 
-use crate::bitset::BitSet;
+impl BuildHasher for SeededState {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+
+/// the hash functions for a filter with the given seed
+fn hash_states(seed: u64, hashes: usize) -> Vec<SeededState> {
+    (0..hashes as u64)
+        .map(|i| SeededState { seed: seed.wrapping_add(i.wrapping_mul(0x9e37_79b9_7f4a_7c15)) })
+        .collect()
+}
 
 pub struct Builder<T: Hash> {
-    hash_states: Vec<RandomState>,
+    seed: u64,
+    hash_states: Vec<SeededState>,
     size: usize,
     mask: usize,
+    insert_mode: InsertMode,
+    memory_policy: MemoryPolicy,
     phantom: PhantomData<T>
 }
 
 pub struct BloomFilter<T: Hash> {
-    hash_states: Vec<RandomState>,
+    seed: u64,
+    hash_states: Vec<SeededState>,
     bits: BitSet,
     mask: usize,
+    insert_mode: InsertMode,
+    region_shift: u32,
     phantom: PhantomData<T>
 }
 
 impl<T: Hash> Builder<T> {
-    /// takes size (in bits) and number of hashes
+    /// takes size (in bits) and number of hashes. The hash functions are chosen at random;
+    /// use `seed` to pick them.
     pub fn new(size: usize, hashes: usize) -> Self {
-        let mut hash_states = Vec::with_capacity(hashes);
-
-        for _i in 0..hashes {
-            hash_states.push(RandomState::new());
-        }
+        let seed = rand::random();
+        let hash_states = hash_states(seed, hashes);
 
         // Round size up to the next power of two
         let size = size as u64;
@@ -41,30 +97,88 @@ impl<T: Hash> Builder<T> {
 
         let mask = size - 1;
 
-        Builder { hash_states, size, mask, phantom: PhantomData }
+        Builder {
+            seed, hash_states, size, mask,
+            insert_mode: InsertMode::Atomic,
+            memory_policy: MemoryPolicy::Replicate,
+            phantom: PhantomData
+        }
     }
 
-    pub fn build(&self) -> BloomFilter<T> {
-        BloomFilter {
-            hash_states: self.hash_states.clone(),
-            bits: BitSet::new(self.size),
-            mask: self.mask,
-            phantom: PhantomData
+    /// chooses the hash functions, so that filters from different builders (or runs) with
+    /// the same seed, size and number of hashes set the same bits
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.hash_states = hash_states(seed, self.hash_states.len());
+        self
+    }
+
+    /// sets where filters built by `on_node` and `build_shared` live; defaults to
+    /// `MemoryPolicy::Replicate`
+    pub fn memory_policy(mut self, memory_policy: MemoryPolicy) -> Self {
+        self.memory_policy = memory_policy;
+        self
+    }
+
+    /// true if each node should get its own copy of the filter from `on_node`,
+    /// rather than everyone sharing one from `build_shared`
+    pub fn replicated(&self) -> bool {
+        self.memory_policy == MemoryPolicy::Replicate
+    }
+
+    /// sets how the filters built by this builder will be written; defaults to `InsertMode::Atomic`
+    pub fn insert_mode(mut self, insert_mode: InsertMode) -> Self {
+        self.insert_mode = match insert_mode {
+            // regions must be a power of two, and no smaller than a local block
+            InsertMode::Partitioned { regions } => InsertMode::Partitioned {
+                regions: regions.next_power_of_two().min((self.size >> 8).max(1))
+            },
+            mode => mode
+        };
+
+        self
+    }
+
+    /// the number of regions the filters built will be split into; see `InsertMode::Partitioned`
+    pub fn regions(&self) -> usize {
+        match self.insert_mode {
+            InsertMode::Atomic => 1,
+            InsertMode::Partitioned { regions } => regions.max(1)
         }
     }
 
+    pub fn build(&self) -> BloomFilter<T> {
+        self.build_with(BitSet::new(self.size))
+    }
+
+    /// builds node `node_id`'s copy of a replicated filter
     pub fn on_node(&self, node_id: u32) -> BloomFilter<T> {
-        let filter = self.build();
+        self.build_with(BitSet::new_on_node(self.size, node_id))
+    }
+
+    /// builds the single copy of the filter for the `Interleave` and `Bind` policies
+    pub fn build_shared(&self) -> BloomFilter<T> {
+        match self.memory_policy {
+            MemoryPolicy::Replicate => self.build(),
+            MemoryPolicy::Interleave => self.build_with(BitSet::new_interleaved(self.size)),
+            MemoryPolicy::Bind(node_id) => self.on_node(node_id)
+        }
+    }
+
+    fn build_with(&self, bits: BitSet) -> BloomFilter<T> {
         BloomFilter {
-            hash_states: filter.hash_states,
-            bits: filter.bits.on_node(node_id),
-            mask: filter.mask,
-            phantom: filter.phantom
+            seed: self.seed,
+            hash_states: self.hash_states.clone(),
+            bits,
+            mask: self.mask,
+            insert_mode: self.insert_mode,
+            region_shift: (self.size / self.regions()).trailing_zeros(),
+            phantom: PhantomData
         }
     }
 }
 
-struct BitSelector<'a, T: Hash, I: Iterator<Item=&'a RandomState>> {
+struct BitSelector<'a, T: Hash, I: Iterator<Item=&'a SeededState>> {
     item: T,
     hash_iter: I,
     mask: usize,
@@ -72,7 +186,7 @@ struct BitSelector<'a, T: Hash, I: Iterator<Item=&'a RandomState>> {
     local_index: usize
 }
 
-impl<'a, T: Hash, I: Iterator<Item=&'a RandomState>> BitSelector<'a, T, I> {
+impl<'a, T: Hash, I: Iterator<Item=&'a SeededState>> BitSelector<'a, T, I> {
     fn new(item: T, mask: usize, iter: I) -> Self {
         BitSelector { item, mask, hash_iter: iter, locality: None, local_index: 0 }
     }
@@ -81,7 +195,7 @@ impl<'a, T: Hash, I: Iterator<Item=&'a RandomState>> BitSelector<'a, T, I> {
 const LOCAL_INDEXES: usize = 2;
 const LOCAL_MASK: usize = (1 << 8) - 1;
 
-impl<'a, T: Hash, I: Iterator<Item=&'a RandomState>> Iterator for BitSelector<'a, T, I> {
+impl<'a, T: Hash, I: Iterator<Item=&'a SeededState>> Iterator for BitSelector<'a, T, I> {
     type Item = usize;
 
     fn next(&mut self) -> Option<usize> {
@@ -91,8 +205,9 @@ impl<'a, T: Hash, I: Iterator<Item=&'a RandomState>> Iterator for BitSelector<'a
 
             let index = hasher.finish() as usize;
 
+            // a filter smaller than a local block has to keep local indexes inside the filter
             let (offset, mask) = match &self.locality {
-                Some(x) => (*x, LOCAL_MASK),
+                Some(x) => (*x, LOCAL_MASK & self.mask),
                 None => (0, self.mask)
             };
 
@@ -109,11 +224,41 @@ impl<'a, T: Hash, I: Iterator<Item=&'a RandomState>> Iterator for BitSelector<'a
     }
 }
 
+/// the number of bit indexes computed ahead of touching the filter in the batched operations,
+/// unless one value alone has more
+const BATCH_INDEXES: usize = 256;
+
 impl<T: Hash> BloomFilter<T> {
     pub fn new(size: usize, hashes: usize) -> Self {
         Builder::new(size, hashes).build()
     }
 
+    /// the number of values the batched operations hash before touching the filter
+    fn batch_len(&self) -> usize {
+        (BATCH_INDEXES / self.hash_states.len()).max(1)
+    }
+
+    /// room for the indexes of `batch_len` values
+    fn batch_indexes(&self) -> Vec<usize> {
+        vec![0; self.batch_len() * self.hash_states.len()]
+    }
+
+    /// fills `indexes` with the bit indexes for each value in `vals` (`hashes` per value),
+    /// prefetching each one as we go, and returns the number of indexes written
+    fn prefetch_batch(&self, vals: &[T], indexes: &mut [usize]) -> usize {
+        let mut n = 0;
+
+        for val in vals.iter() {
+            for i in BitSelector::new(val, self.mask, self.hash_states.iter()) {
+                self.bits.prefetch(i);
+                indexes[n] = i;
+                n += 1;
+            }
+        }
+
+        n
+    }
+
     pub fn maybe_present(&self, val: &T) -> bool {
         for i in BitSelector::new(val, self.mask, self.hash_states.iter()) {
             if !self.bits.contains(i) {
@@ -130,11 +275,186 @@ impl<T: Hash> BloomFilter<T> {
         }
     }
 
-    pub fn cross_or(&mut self, other: &mut Self) {
-        // TODO: check: assert_eq!(self.hash_states, other.hash_states);
+    /// Inserts every value in `vals`. All bit positions for a batch are computed and
+    /// prefetched before any of them are set, so the cache misses overlap instead of
+    /// being taken one at a time.
+    pub fn put_batch(&self, vals: &[T]) {
+        let mut indexes = self.batch_indexes();
+
+        for chunk in vals.chunks(self.batch_len()) {
+            let n = self.prefetch_batch(chunk, &mut indexes);
+
+            for i in indexes[..n].iter() {
+                self.bits.insert(*i);
+            }
+        }
+    }
+
+    /// Sets `present[i]` to `maybe_present(&vals[i])`, prefetching like `put_batch`.
+    pub fn maybe_present_batch(&self, vals: &[T], present: &mut [bool]) {
+        assert_eq!(vals.len(), present.len());
+
+        let hashes = self.hash_states.len();
+        let mut indexes = self.batch_indexes();
+
+        let batch_len = self.batch_len();
+        for (chunk, present) in vals.chunks(batch_len).zip(present.chunks_mut(batch_len)) {
+            let n = self.prefetch_batch(chunk, &mut indexes);
+
+            for (val_indexes, present) in indexes[..n].chunks(hashes).zip(present.iter_mut()) {
+                *present = val_indexes.iter().all(|i| self.bits.contains(*i));
+            }
+        }
+    }
+
+    /// how this filter expects to be written
+    pub fn insert_mode(&self) -> InsertMode {
+        self.insert_mode
+    }
+
+    /// the number of regions the filter is split into; see `InsertMode::Partitioned`
+    pub fn regions(&self) -> usize {
+        self.size() >> self.region_shift
+    }
+
+    /// empty buckets for `bucket_batch`, one per region
+    pub fn region_buckets(&self) -> RegionBuckets {
+        RegionBuckets { buckets: (0..self.regions()).map(|_| Vec::new()).collect() }
+    }
+
+    /// Adds the bit indexes of every value in `vals` to the bucket of the region each falls
+    /// in. Nothing in the filter is touched until they're written out by `put_region`.
+    pub fn bucket_batch(&self, vals: &[T], buckets: &mut RegionBuckets) {
+        assert_eq!(buckets.buckets.len(), self.regions());
+
+        for val in vals.iter() {
+            for i in BitSelector::new(val, self.mask, self.hash_states.iter()) {
+                buckets.buckets[i >> self.region_shift].push(i);
+            }
+        }
+    }
+
+    /// Sets the bits each of `buckets` holds for `region`, with plain stores rather than
+    /// atomic read-modify-writes. Nothing else may write to that region meanwhile, or
+    /// some of the bits may be lost.
+    pub fn put_region<'a>(&self, region: usize, buckets: impl IntoIterator<Item = &'a RegionBuckets>) {
+        for bucket in buckets.into_iter().map(|buckets| &buckets.buckets[region]) {
+            for chunk in bucket.chunks(BATCH_INDEXES) {
+                for i in chunk.iter() {
+                    self.bits.prefetch(*i);
+                }
+                for i in chunk.iter() {
+                    self.bits.insert_exclusive(*i);
+                }
+            }
+        }
+    }
+
+    /// the kind of pages backing the filter's bits
+    pub fn page_size(&self) -> PageSize {
+        self.bits.page_size()
+    }
+
+    /// reports whether the filter's bits really are on node `node_id`
+    pub fn check_node(&self, node_id: u32) -> NodePlacement {
+        self.bits.check_node(node_id)
+    }
+
+    /// the seed of the filter's hash functions; see `Builder::seed`
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// the number of hash functions
+    pub fn hashes(&self) -> usize {
+        self.hash_states.len()
+    }
+
+    /// size in bits
+    pub fn size(&self) -> usize {
+        self.mask + 1
+    }
+
+    pub fn cross_or(&mut self, other: &mut Self, workers: &Workers) {
+        assert_eq!(self.hash_states, other.hash_states);
         assert_eq!(self.mask, other.mask);
 
-        self.bits.cross_or(&mut other.bits);
+        self.bits.cross_or(&mut other.bits, workers);
+    }
+
+    /// Writes the filter's bits to `out` as little-endian words. Nothing else is written:
+    /// the seed, size and number of hashes have to be kept alongside.
+    pub fn save(&self, out: impl Write) -> io::Result<()> {
+        self.save_union(&[], out)
+    }
+
+    /// Like `save`, but writes the union of this filter's bits and those of `others`,
+    /// which have the same hashes and size. They can be written to meanwhile: a bit set
+    /// before the call is in what's written.
+    pub fn save_union(&self, others: &[&Self], mut out: impl Write) -> io::Result<()> {
+        for other in others.iter() {
+            assert_eq!(self.hash_states, other.hash_states);
+            assert_eq!(self.mask, other.mask);
+        }
+
+        let mut buf = Vec::with_capacity(IO_WORDS * 8);
+
+        for (n, chunk) in self.bits.as_words().chunks(IO_WORDS).enumerate() {
+            buf.clear();
+            for (i, word) in chunk.iter().enumerate() {
+                let word = others.iter().fold(word.load(Ordering::Relaxed), |word, other| {
+                    word | other.bits.as_words()[n * IO_WORDS + i].load(Ordering::Relaxed)
+                });
+                buf.extend_from_slice(&word.to_le_bytes());
+            }
+
+            out.write_all(&buf)?;
+        }
+
+        out.flush()
+    }
+
+    /// Replaces the filter's bits with those written by `save`, from a filter with the same
+    /// seed and size. Fails if `input` holds fewer words than the filter; any more are ignored.
+    pub fn load(&self, mut input: impl Read) -> io::Result<()> {
+        let mut buf = vec![0u8; IO_WORDS * 8];
+
+        for chunk in self.bits.as_words().chunks(IO_WORDS) {
+            let buf = &mut buf[..chunk.len() * 8];
+            input.read_exact(buf)?;
+
+            for (word, bytes) in chunk.iter().zip(buf.chunks_exact(8)) {
+                word.store(u64::from_le_bytes(bytes.try_into().unwrap()), Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// the number of words `save` and `load` copy at a time
+const IO_WORDS: usize = 1 << 16;
+
+/// A worker's hashed but not yet written bit indexes for a partitioned filter, by region
+pub struct RegionBuckets {
+    buckets: Vec<Vec<usize>>
+}
+
+impl RegionBuckets {
+    /// the number of bit indexes held, in all regions
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// empties every bucket, keeping the memory for the next batch
+    pub fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.clear();
+        }
     }
 }
 
@@ -154,6 +474,102 @@ mod test {
         }
     }
 
+    #[test]
+    pub fn test_batches_match_single() {
+        // more hashes than BATCH_INDEXES too, so a batch is a single value
+        for &hashes in [3, BATCH_INDEXES + 1].iter() {
+            let builder = Builder::new(1 << 16, hashes);
+            let single = builder.build();
+            let batched = builder.build();
+
+            let vals: Vec<u64> = (0..1000u64).map(|i| i * 7919).collect();
+            for val in vals.iter() {
+                single.put(val);
+            }
+            batched.put_batch(&vals);
+
+            for (a, b) in single.bits.as_words().iter().zip(batched.bits.as_words().iter()) {
+                assert_eq!(a.load(Ordering::Relaxed), b.load(Ordering::Relaxed));
+            }
+
+            let probes: Vec<u64> = (0..5000u64).collect();
+            let mut present = vec![false; probes.len()];
+            batched.maybe_present_batch(&probes, &mut present);
+
+            for (probe, present) in probes.iter().zip(present.iter()) {
+                assert_eq!(single.maybe_present(probe), *present);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_partitioned_matches_atomic() {
+        let builder = Builder::new(1 << 20, 2).insert_mode(InsertMode::Partitioned { regions: 60 });
+        let atomic = builder.build();
+        let partitioned = builder.build();
+
+        assert_eq!(partitioned.insert_mode(), InsertMode::Partitioned { regions: 64 });
+
+        let vals: Vec<u64> = (0..100000u64).map(|i| i * 104729).collect();
+        for val in vals.iter() {
+            atomic.put(val);
+        }
+
+        // several workers bucket a share of the values each...
+        let buckets: Vec<RegionBuckets> = vals.chunks(30000).map(|chunk| {
+            let mut buckets = partitioned.region_buckets();
+            partitioned.bucket_batch(chunk, &mut buckets);
+            buckets
+        }).collect();
+        assert_eq!(buckets.iter().map(|buckets| buckets.len()).sum::<usize>(), 2 * vals.len());
+
+        // ...then write a region each, all at once
+        std::thread::scope(|scope| {
+            for region in 0..partitioned.regions() {
+                let (partitioned, buckets) = (&partitioned, &buckets);
+                scope.spawn(move || partitioned.put_region(region, buckets));
+            }
+        });
+
+        for (a, b) in atomic.bits.as_words().iter().zip(partitioned.bits.as_words().iter()) {
+            assert_eq!(a.load(Ordering::Relaxed), b.load(Ordering::Relaxed));
+        }
+    }
+
+    #[test]
+    pub fn test_save_load() {
+        let filter = Builder::new(1 << 20, 2).seed(7).build();
+        for i in 0..1000u64 {
+            filter.put(&(i * 7919));
+        }
+
+        let mut saved = Vec::new();
+        filter.save(&mut saved).unwrap();
+        assert_eq!(saved.len(), (1 << 20) / 8);
+
+        // a new filter with the same seed answers the same way
+        let loaded = Builder::new(1 << 20, 2).seed(7).build();
+        loaded.load(&saved[..]).unwrap();
+
+        assert_eq!(loaded.seed(), 7);
+        for i in 0..10000u64 {
+            assert_eq!(loaded.maybe_present(&i), filter.maybe_present(&i));
+        }
+
+        let other = Builder::<u64>::new(1 << 20, 2).seed(8).build();
+        assert!(other.load(&saved[..saved.len() - 8]).is_err());
+
+        // the union of two halves is the whole
+        let halves = [Builder::new(1 << 20, 2).seed(7).build(), Builder::new(1 << 20, 2).seed(7).build()];
+        for i in 0..1000u64 {
+            halves[(i % 2) as usize].put(&(i * 7919));
+        }
+
+        let mut union = Vec::new();
+        halves[0].save_union(&[&halves[1]], &mut union).unwrap();
+        assert_eq!(union, saved);
+    }
+
     #[test]
     pub fn test_fp_rate() {
         let filter = BloomFilter::new(8192, 4);
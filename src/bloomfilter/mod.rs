@@ -3,125 +3,443 @@
 
 use crate::gray_prod_iter::*;
 use crate::progress;
-use crate::numa_threadpool::ThreadPool;
+use crate::cancel::{CancellationToken, Outcome, Tasks, skip_completed};
+use crate::checkpoint::FilterFile;
+use crate::numa_threadpool::{TaskPanic, ThreadPool, Workers, split_range};
+use crate::t2_map::{T2Index, T2Map};
 
-use std::sync::{Mutex, Arc, mpsc::channel};
-use std::sync::atomic::{Ordering, AtomicUsize};
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
-use std::time::Instant;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 mod conc_bloom;
-use crate::bloomfilter::conc_bloom::*;
+pub use crate::bloomfilter::conc_bloom::{BloomFilter, Builder, InsertMode, MemoryPolicy, RegionBuckets};
 
 use crate::magic_numbers::*;
 use crate::modulus::*;
 
 const FILTER_SIZE : usize = 1usize << 39;
 const FILTER_HASHES : usize = 2;
-const N_TASKS : u64 = 1u64 << 16;
+/// how many tasks each phase's work is divided into, by default
+pub const N_TASKS : u64 = 1u64 << 16;
+/// how many SSPs the kernels buffer before handing them to the filter at once
+const KERNEL_BATCH : usize = 64;
+/// how many bit indexes each worker buckets per round with `InsertMode::Partitioned`
+const ROUND_INDEXES : u64 = 1 << 18;
+
+/// Parameters for the phase 1 Bloom filter
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FilterConfig {
+    /// size in bits, rounded up to a power of two
+    pub size: usize,
+    pub hashes: usize,
+    pub insert_mode: InsertMode,
+    pub memory_policy: MemoryPolicy,
+    /// picks the hash functions; at random if None. See `Builder::seed`
+    pub seed: Option<u64>
+}
+
+/// What every phase needs besides its inputs: the modulus the subset products are taken
+/// mod, how many tasks to divide the work into, the workers to run them on, and where to
+/// hand its progress along the way
+#[derive(Clone)]
+pub struct PhaseContext<'a, M: Modulus> {
+    pub modulus: M,
+    pub n_tasks: u64,
+    pub workers: &'a Workers,
+    /// how often a running phase hands a `Checkpoint` to the function, so that being killed
+    /// doesn't lose the whole phase; never if None
+    pub checkpoint: Option<(Duration, &'a dyn Fn(Checkpoint))>
+}
+
+/// A running phase's progress so far. The completed ranges are those of tasks that have
+/// finished, which the value covers; it may hold more, from tasks still running.
+pub enum Checkpoint {
+    /// phase 1's filters; until they're merged, it's their union that covers the ranges
+    Filter(Outcome<HashMap<u32, Arc<BloomFilter<u64>>>>),
+    /// the phase 2 entries found since the last checkpoint of the phase
+    T2Entries(Outcome<Vec<(u64, u32)>>),
+    /// every pseudoprime phase 3 has found
+    Results(Outcome<Vec<Pseudoprime>>)
+}
+
+impl<'a> PhaseContext<'a, OptiM> {
+    /// the search from the paper: `MODULUS`, in `N_TASKS` tasks
+    pub fn new(workers: &'a Workers) -> Self {
+        PhaseContext { modulus: MODULUS, n_tasks: N_TASKS, workers, checkpoint: None }
+    }
+}
+
+impl<'a, M: Modulus> PhaseContext<'a, M> {
+    /// the task ranges covering `0..total_work` that are left to run, and those (as
+    /// finished tasks) already inside `completed`
+    fn split(&self, total_work: u64, completed: &[Range<u64>]) -> (Vec<Range<u64>>, Tasks) {
+        skip_completed(split_range(0..total_work, self.n_tasks), completed)
+    }
+
+    /// hands on `checkpoint()` if the checkpoint interval has passed since `last`, and if so
+    /// starts the next one
+    fn checkpoint(&self, last: &mut Instant, checkpoint: impl FnOnce() -> Checkpoint) {
+        if let Some((_, save)) = self.checkpoint.filter(|(interval, _)| last.elapsed() >= *interval) {
+            save(checkpoint());
+            *last = Instant::now();
+        }
+    }
+}
+
+/// the outcome so far of a phase that has run `tasks`, with `pending` still to finish
+fn so_far<T>(value: T, tasks: &[(Range<u64>, bool)], pending: &[Range<u64>]) -> Outcome<T> {
+    let tasks: Tasks = tasks.iter().cloned().chain(pending.iter().map(|range| (range.clone(), false))).collect();
+    Outcome::from_tasks(value, &tasks, &CancellationToken::new())
+}
+
+impl Default for FilterConfig {
+    /// fp p<=0.001, 64GiB, k=2
+    fn default() -> Self {
+        FilterConfig {
+            size: FILTER_SIZE,
+            hashes: FILTER_HASHES,
+            insert_mode: InsertMode::Atomic,
+            memory_policy: MemoryPolicy::Replicate,
+            seed: None
+        }
+    }
+}
 
 /// computes gray code SSPs from the start'th gray code word to the end'th gray code word (not included),
-/// inserting the SSP values into the Bloom filter
+/// inserting the SSP values into the Bloom filter, or only into `buckets` if given.
+/// Returns false if `progress` was cancelled before the whole range was inserted.
 pub fn bloom_t1_kernel<M: Modulus>(
     product_set: &ProductSet<M>, 
     start: u64, 
     end: u64, 
     filter: &BloomFilter<u64>, 
+    mut buckets: Option<&mut RegionBuckets>,
     progress: &progress::ProgressReporter
-) {
+) -> bool {
     let mut handle = progress.handle();
 
     let filter = &filter as &BloomFilter<u64>;
 
+    let mut put_batch = |batch: &[u64]| match buckets.as_mut() {
+        Some(buckets) => filter.bucket_batch(batch, buckets),
+        None => filter.put_batch(batch)
+    };
+
+    let mut batch = Vec::with_capacity(KERNEL_BATCH);
     for (_k, v) in ProductIter::new(&product_set, start, end) {
-        filter.put(&v);
-        handle.report(1);
+        batch.push(v);
+
+        if batch.len() == KERNEL_BATCH {
+            if handle.is_cancelled() {
+                return false;
+            }
+
+            put_batch(&batch);
+            handle.report(batch.len());
+            batch.clear();
+        }
     }
+
+    put_batch(&batch);
+    handle.report(batch.len());
+    true
+}
+
+/// Inserts the subset products of each of `ranges` into a partitioned filter, in rounds.
+/// In each round, every worker first hashes a piece of the ranges into its own
+/// `RegionBuckets`; then each of the `regions` is written, from all of those buckets, by a
+/// single worker. So every bit is set with a plain store, and no lock is taken on the way.
+/// With `MemoryPolicy::Replicate` a region goes into the filter of the node writing it,
+/// which is fine as the replicas are merged afterwards.
+/// After each round, `on_round` is given how many pieces of each range are still to go.
+/// Returns which of `ranges` were inserted in full before `progress` was cancelled.
+pub fn bloom_t1_partitioned<M: Modulus + Send + Sync>(
+    pool: &ThreadPool<Arc<BloomFilter<u64>>>,
+    product_set: &ProductSet<M>,
+    ranges: &[Range<u64>],
+    regions: usize,
+    hashes: usize,
+    progress: &progress::ProgressReporter,
+    mut on_round: impl FnMut(&[usize])
+) -> Result<Vec<bool>, TaskPanic> {
+    let threads = pool.threads();
+    let piece_len = (ROUND_INDEXES / hashes.max(1) as u64).max(1);
+
+    // each range in pieces of about `piece_len` SSPs, along with the range it belongs to
+    let pieces: Vec<(usize, Range<u64>)> = ranges.iter().enumerate().flat_map(|(task, range)| {
+        let n = (range.end - range.start).div_ceil(piece_len).max(1);
+        split_range(range.clone(), n).into_iter().map(move |piece| (task, piece))
+    }).collect();
+
+    let mut missing = vec![0usize; ranges.len()];
+    for (task, _) in pieces.iter() {
+        missing[*task] += 1;
+    }
+
+    // each worker's buckets, kept from one round to the next
+    let slots: Vec<RwLock<Option<RegionBuckets>>> = (0..threads).map(|_| RwLock::new(None)).collect();
+
+    for round in pieces.chunks(threads.max(1)) {
+        let slots = &slots[..round.len()];
+
+        let done = pool.scope(|scope| {
+            scope.map_ranges((0..round.len() as u64).map(|i| i..i + 1), |filter, i| {
+                let (_, piece) = &round[i.start as usize];
+                let mut slot = slots[i.start as usize].write().unwrap();
+                let buckets = slot.get_or_insert_with(|| filter.region_buckets());
+
+                buckets.clear();
+                bloom_t1_kernel(product_set, piece.start, piece.end, filter, Some(buckets), progress)
+            })
+        })?;
+
+        pool.scope(|scope| {
+            scope.map_ranges((0..regions as u64).map(|region| region..region + 1), |filter, region| {
+                let slots: Vec<_> = slots.iter().map(|slot| slot.read().unwrap()).collect();
+                filter.put_region(region.start as usize, slots.iter().filter_map(|slot| slot.as_ref()));
+            })
+        })?;
+
+        for ((task, _), done) in round.iter().zip(done.iter()) {
+            if *done {
+                missing[*task] -= 1;
+            }
+        }
+        on_round(&missing);
+
+        // what was bucketed before the cancel is in, but the rest of its range isn't
+        if done.contains(&false) {
+            break;
+        }
+    }
+
+    Ok(missing.into_iter().map(|missing| missing == 0).collect())
 }
 
 /// For all subsets of the input array t1 (which main.rs passes T1_INVERSE),
 /// computes the corresponding subset product
 /// and inserts the product into a Bloom filter.
-/// Details: we create a bloom filter for each NUMA node,
-/// then divides the work up into lots of chunks. 
-/// The subset products for each chunk are inserted into one of the two bloom filters,
-/// and at the end of the comptutation, we "cross_or" the two filters together so that
+/// Details: with `MemoryPolicy::Replicate` we create a bloom filter for each NUMA node,
+/// then divides the work up into lots of chunks.
+/// The subset products for each chunk are inserted into the filter of the node running it,
+/// and at the end of the comptutation, we "cross_or" the filters together so that
 /// each bloom filter contains *all* of the subset products.
+/// With the other policies there is a single filter, shared by every node, and nothing to merge.
 /// We output a map from NUMA node ID to a bloom filter,
 /// where each bloom filter contains all subset products in t1.
-pub fn bloom_t1(t1: &[u64]) -> HashMap<u32, Arc<BloomFilter<u64>>> {
+/// The filter is written according to `config.insert_mode`, and the work runs on `context.workers`;
+/// `InsertMode::Partitioned` goes through `bloom_t1_partitioned`.
+/// If `cancel` fires, the filters only hold the subset products of the completed ranges.
+/// Given a saved filter to `resume` from, we start from its bits (and seed) and skip the
+/// ranges it already covers.
+pub fn bloom_t1<M: Modulus + Send + Sync + 'static>(
+    t1: &[u64],
+    config: &FilterConfig,
+    context: &PhaseContext<M>,
+    cancel: &CancellationToken,
+    resume: Option<Outcome<FilterFile>>
+) -> Outcome<HashMap<u32, Arc<BloomFilter<u64>>>> {
+    let workers = context.workers;
     // we will work on 2^t1.len() subsets; divide this into N tasks
     let total_work = 1u64 << t1.len();
 
-    let progress = Arc::new(progress::ProgressReporter::new("bloom_t1", total_work as usize));
+    // a partitioned filter gets one region per worker, unless told otherwise
+    let insert_mode = match config.insert_mode {
+        InsertMode::Partitioned { regions: 0 } => InsertMode::Partitioned { regions: workers.threads() },
+        mode => mode
+    };
+
+    let progress = progress::ProgressReporter::with_cancel("bloom_t1", total_work as usize, cancel);
     // create an empty bloom filter
-    let builder = conc_bloom::Builder::new(FILTER_SIZE, FILTER_HASHES);
+    let mut builder = conc_bloom::Builder::new(config.size, config.hashes)
+        .insert_mode(insert_mode)
+        .memory_policy(config.memory_policy);
 
-    let product_set = Arc::new(ProductSet::new(t1, MODULUS));
+    println!("[bloom_t1] inserting with {:?} ({} regions), memory policy {:?}",
+        insert_mode, builder.regions(), config.memory_policy);
 
-    let pool = ThreadPool::new(|node_id| builder.on_node(node_id));
-    
-    let per_task = total_work / N_TASKS;
+    if let Some(seed) = resume.as_ref().map(|saved| saved.value.seed).or(config.seed) {
+        builder = builder.seed(seed);
+    }
 
-    // evaluate the kernel for each task
-    for i in 0..N_TASKS {
-        let start_idx = per_task * i;
-        let end_idx = if i == N_TASKS - 1 { total_work } else { start_idx + per_task };
+    // ...or one holding what an earlier run got through
+    let (ranges, mut tasks) = match &resume {
+        Some(saved) => {
+            println!("[bloom_t1] resuming from {}, {}", saved.value.path.display(), saved);
+            context.split(total_work, &saved.completed)
+        },
+        None => context.split(total_work, &[])
+    };
+    let start = |filter: BloomFilter<u64>| {
+        if let Some(saved) = &resume {
+            saved.value.load_into(&filter).unwrap_or_else(|e| {
+                panic!("[bloom_t1] couldn't load {}: {}", saved.value.path.display(), e)
+            });
+        }
+        Arc::new(filter)
+    };
 
-        let product_set = product_set.clone();
-        let progress = progress.clone();
+    let product_set = ProductSet::new(t1, context.modulus);
 
-        pool.execute(move|filter| {
-            bloom_t1_kernel(&product_set, start_idx, end_idx, &filter, &progress);
-        });
+    let filters: HashMap<u32, Arc<BloomFilter<u64>>> = if builder.replicated() {
+        workers.node_ids().into_iter().map(|node_id| (node_id, start(builder.on_node(node_id)))).collect()
+    } else {
+        let shared = start(builder.build_shared());
+        workers.node_ids().into_iter().map(|node_id| (node_id, shared.clone())).collect()
+    };
+    let pool = workers.install(|node_id| filters[&node_id].clone());
+    let mut last_checkpoint = Instant::now();
+
+    // evaluate the kernel for each task
+    match insert_mode {
+        InsertMode::Atomic => pool.scope(|scope| {
+            let handles: Vec<_> = ranges.iter().cloned().map(|range| {
+                let (product_set, progress) = (&product_set, &progress);
+                scope.submit(move |filter| bloom_t1_kernel(product_set, range.start, range.end, filter, None, progress))
+            }).collect();
+
+            for (i, handle) in handles.into_iter().enumerate() {
+                let completed = handle.join().unwrap_or_else(|panic| panic!("[bloom_t1] {}", panic));
+                tasks.push((ranges[i].clone(), completed));
+                context.checkpoint(&mut last_checkpoint, || Checkpoint::Filter(so_far(filters.clone(), &tasks, &ranges[i + 1..])));
+            }
+        }),
+        InsertMode::Partitioned { .. } => {
+            let completed = bloom_t1_partitioned(&pool, &product_set, &ranges, builder.regions(), config.hashes, &progress, |missing| {
+                context.checkpoint(&mut last_checkpoint, || {
+                    let done = ranges.iter().cloned().zip(missing.iter().map(|missing| *missing == 0));
+                    Checkpoint::Filter(so_far(filters.clone(), &tasks.iter().cloned().chain(done).collect::<Tasks>(), &[]))
+                });
+            }).unwrap_or_else(|panic| panic!("[bloom_t1] {}", panic));
+            tasks.extend(ranges.iter().cloned().zip(completed));
+        }
     }
 
     // wait for all tasks to complete
-    let mut filters = pool.join();
-
-    // this code ONLY works for at most two NUMA nodes; it would have to be generalized
-    // to work for more.
-    assert!(filters.len() <= 2);
-
-    // if there are exactly two NUMA nodes, there are two bloom filters: each containing
-    // half of the subset products. We need *both* filters to contain *all* subset products
-    // so we just OR the two bitsets together.
-    if filters.len() == 2 {
-        let (node_id, mut f2) = filters.pop().unwrap();
-        filters[0].1.cross_or(&mut f2);
-        filters.push((node_id, f2));
+    pool.join().unwrap_or_else(|panic| panic!("[bloom_t1] {}", panic));
+    let inserted = !ranges.is_empty();
+    let mut filters: Vec<(u32, Arc<BloomFilter<u64>>)> = filters.into_iter().collect();
+    filters.sort_by_key(|(node, _)| *node);
+
+    report_placement(&filters, config.memory_policy);
+
+    // replicas that were only loaded from the same file already agree
+    if !builder.replicated() || !inserted {
+        return Outcome::from_tasks(filters.into_iter().collect(), &tasks, cancel);
     }
 
+    // each replica only holds the subset products computed on its own node
+    let mut replicas: Vec<(u32, BloomFilter<u64>)> = filters.into_iter().map(|(node, filter)| {
+        (node, Arc::try_unwrap(filter).unwrap_or_else(|_| panic!("Filter for node {} still in use", node)))
+    }).collect();
+
+    merge_replicas(&mut replicas, workers);
+
     // create a map from NUMA node to corresponding bloom filter
     let mut filtermap = HashMap::new();
-    for (node, filter) in filters.into_iter() {
+    for (node, filter) in replicas.into_iter() {
         filtermap.insert(node, Arc::new(filter));
     }
 
-    return filtermap;
+    return Outcome::from_tasks(filtermap, &tasks, cancel);
+}
+
+/// prints what memory each node's filter ended up in, and warns about any that isn't where
+/// `memory_policy` wanted it
+fn report_placement(filters: &[(u32, Arc<BloomFilter<u64>>)], memory_policy: MemoryPolicy) {
+    let filters: Vec<_> = match memory_policy {
+        MemoryPolicy::Replicate => filters.iter().map(|(node, filter)| (Some(*node), filter)).collect(),
+        // only one filter, shared by every node
+        MemoryPolicy::Bind(node) => vec![(Some(node), &filters[0].1)],
+        MemoryPolicy::Interleave => vec![(None, &filters[0].1)]
+    };
+
+    for (node, filter) in filters.into_iter() {
+        let node = match node {
+            Some(node) => node,
+            None => {
+                println!("[bloom_t1] interleaved filter is backed by {}", filter.page_size());
+                continue;
+            }
+        };
+
+        let placement = filter.check_node(node);
+        println!("[bloom_t1] filter for node {} is backed by {}, {}",
+            node, filter.page_size(), placement);
+
+        if !placement.is_local() {
+            println!("Warning: {} pages of the filter for node {} are elsewhere, first few: {:?}",
+                placement.misplaced.len(), node, &placement.misplaced[..placement.misplaced.len().min(8)]);
+        }
+    }
+}
+
+/// Each replica starts out holding only the subset products inserted on its own node.
+/// We need *every* replica to contain *all* subset products, so we OR them all into the
+/// first one and then OR that back into the rest.
+fn merge_replicas(replicas: &mut [(u32, BloomFilter<u64>)], workers: &Workers) {
+    if let Some(((_, first), rest)) = replicas.split_first_mut() {
+        for (_, replica) in rest.iter_mut() {
+            first.cross_or(replica, workers);
+        }
+
+        // the last replica was updated by the final cross_or above
+        let n = rest.len();
+        for (_, replica) in rest.iter_mut().take(n.saturating_sub(1)) {
+            first.cross_or(replica, workers);
+        }
+    }
 }
 
 /// outputs a vector of (t2-idx,SSP) pairs for SSPs found in the bloom filter
-/// (using the bloom filter closest to the NUMA node running the kernel)
+/// (using the bloom filter closest to the NUMA node running the kernel),
+/// and whether the whole range was covered before `progress` was cancelled
 fn build_t2_kernel<M: Modulus>(
     filter: &BloomFilter<u64>,
     progress: &progress::ProgressReporter,
     product_set: &ProductSet<M>,
     start: u64,
     end: u64
-) -> Vec<(u32, u64)> {
+) -> (Vec<(u32, u64)>, bool) {
     let mut results = Vec::new();
     let mut handle = progress.handle();
 
-    for (mask, ssp) in ProductIter::new(&product_set, start, end) {
-        if filter.maybe_present(&ssp) {
-            results.push((mask as u32, ssp));
+    let mut masks = Vec::with_capacity(KERNEL_BATCH);
+    let mut ssps = Vec::with_capacity(KERNEL_BATCH);
+    let mut present = [false; KERNEL_BATCH];
+
+    let mut check_batch = |masks: &mut Vec<u64>, ssps: &mut Vec<u64>| {
+        let present = &mut present[..ssps.len()];
+        filter.maybe_present_batch(ssps, present);
+
+        for ((mask, ssp), present) in masks.iter().zip(ssps.iter()).zip(present.iter()) {
+            if *present {
+                results.push((*mask as u32, *ssp));
+            }
         }
 
-        handle.report(1);
+        handle.report(ssps.len());
+        masks.clear();
+        ssps.clear();
+
+        // whether to stop here
+        handle.is_cancelled()
+    };
+
+    for (mask, ssp) in ProductIter::new(&product_set, start, end) {
+        masks.push(mask);
+        ssps.push(ssp);
+
+        if ssps.len() == KERNEL_BATCH && check_batch(&mut masks, &mut ssps) {
+            return (results, false);
+        }
     }
 
-    return results;
+    check_batch(&mut masks, &mut ssps);
+
+    return (results, true);
 }
 
 /// The next step is to compute all subset products for the array t2, and record those
@@ -130,142 +448,378 @@ fn build_t2_kernel<M: Modulus>(
 /// compute resources. For each subset proudct, we check the (closest copy of the) bloom filter.
 /// If the product is in the bloom filter, we add the (product, SSP mask) to the map,
 /// otherwise we discard it.
-/// Outputs a hashmap from SSPs to t2-masks which create them for SSPs found in the bloom filter
-pub fn build_t2(
+/// Outputs a map from SSPs found in the bloom filter to all the t2-masks which create them.
+/// If `cancel` fires, the map also holds what the unfinished ranges found before stopping.
+/// Given an earlier map to `resume` from, we add to it, skipping the ranges it already covers.
+pub fn build_t2<M: Modulus + Send + Sync + 'static>(
     filters: HashMap<u32, Arc<BloomFilter<u64>>>, 
-    t2: &[u64]
-) -> HashMap<u64, u32> {
+    t2: &[u64],
+    context: &PhaseContext<M>,
+    cancel: &CancellationToken,
+    resume: Option<Outcome<T2Map>>
+) -> Outcome<T2Map> {
     // we will work on 2^t2.len() subsets; divide this into N tasks
     let total_work = 1u64 << t2.len();
-    let progress = Arc::new(progress::ProgressReporter::new("t2_map", total_work as usize));
-    let product_set = Arc::new(ProductSet::new(t2, MODULUS));  
+    let progress = progress::ProgressReporter::with_cancel("t2_map", total_work as usize, cancel);
+    let product_set = ProductSet::new(t2, context.modulus);
 
-    let per_task = total_work / N_TASKS;
-
-    let pool : ThreadPool<Arc<BloomFilter<u64>>> = ThreadPool::new(|node_id| 
+    let pool : ThreadPool<Arc<BloomFilter<u64>>> = context.workers.install(|node_id| 
         filters.get(&node_id).unwrap_or_else(|| {
             println!("Warning: Couldn't find a T1 for node {}, falling back to arbitrary node", node_id);
             filters.iter().next().unwrap().1
         }).clone()
     );
 
-    let (tx, rx) = channel();
-    let parallel_end = Arc::new(Mutex::new(Instant::now()));
-
-    // evaluate the kernel for each task
-    for task_idx in 0..N_TASKS {
-        let start_idx = task_idx * per_task;
-        let end_idx   = if task_idx == N_TASKS - 1 { total_work } else { start_idx + per_task };
-
-        let progress = progress.clone();
-        let product_set = product_set.clone();
-        let parallel_end = parallel_end.clone();
-        let tx = tx.clone();
-
-        pool.execute(move |filter| {
-            let result = build_t2_kernel(&filter, &progress, &product_set, start_idx, end_idx);
-            let mut guard = parallel_end.lock().unwrap();
-            *guard = Instant::now();
-
-            tx.send(result).unwrap();
-        });
-    }
-
-    // each kernel returns a vector of subset indicators and subset products, where
-    // the subset product is in 
-    let mut hashmap = HashMap::new();
-    for _task_idx in 0..N_TASKS {
-        let vals = rx.recv().unwrap();
-
-        for (v, k) in vals {
-            hashmap.insert(k, v);
+    let (ranges, mut tasks, mut t2map) = match resume {
+        Some(saved) => {
+            println!("[t2_map] resuming with {} entries, {}", saved.value.len(), saved);
+            let (ranges, tasks) = context.split(total_work, &saved.completed);
+            (ranges, tasks, saved.value)
+        },
+        None => {
+            let (ranges, tasks) = context.split(total_work, &[]);
+            (ranges, tasks, T2Map::new())
         }
-    }
+    };
+    let mut entries = Vec::new();
+    let mut parallel_end = None;
+    // the entries before this one have been handed to a checkpoint
+    let mut checkpointed = 0;
+    let mut last_checkpoint = Instant::now();
+
+    pool.scope(|scope| {
+        // evaluate the kernel for each task, noting when it finished
+        let handles: Vec<_> = ranges.iter().cloned().map(|range| {
+            let progress = &progress;
+            let product_set = &product_set;
+
+            scope.submit(move |filter| {
+                let result = build_t2_kernel(filter, progress, product_set, range.start, range.end);
+                (result, Instant::now())
+            })
+        }).collect();
+
+        // each kernel returns a vector of subset indicators and subset products, where
+        // the subset product is in 
+        for (i, handle) in handles.into_iter().enumerate() {
+            let ((vals, completed), finished) = handle.join().unwrap_or_else(|panic| panic!("[t2_map] {}", panic));
+            parallel_end = parallel_end.max(Some(finished));
+            tasks.push((ranges[i].clone(), completed));
+
+            entries.extend(vals.into_iter().map(|(v, k)| (k, v)));
+            context.checkpoint(&mut last_checkpoint, || {
+                let new_entries = entries[checkpointed..].to_vec();
+                checkpointed = entries.len();
+                Checkpoint::T2Entries(so_far(new_entries, &tasks, &ranges[i + 1..]))
+            });
+        }
+    });
 
-    println!("[t2 serial] {} entries, {} seconds single-thread",
-        hashmap.len(), parallel_end.lock().unwrap().elapsed().as_secs()
+    pool.join().unwrap_or_else(|panic| panic!("[t2_map] {}", panic));
+    t2map.extend(entries);
+
+    println!("[t2 serial] {} entries ({} sharing an SSP with another), {} seconds single-thread",
+        t2map.len(), t2map.duplicates(), parallel_end.map_or(0, |end: Instant| end.elapsed().as_secs())
     );
 
-    hashmap
+    Outcome::from_tasks(t2map, &tasks, cancel)
 }
 
-/// Compute subset products for some range in t1_product_set.
-/// If the SSP is in t2map, we have found a match! Check the candidate
-/// for the remaining conditions, and save it if they are met (otherwise it is a `t3_miss`)
+/// Checks a match between a T1 mask and a T2 mask for the remaining conditions, returning
+/// the pseudoprime if they are met. `check_prime` is the one from the paper.
+pub type Check<'a> = dyn Fn(u32, u32) -> Option<Pseudoprime> + Sync + 'a;
+
+/// Compute subset products for some range in t1_product_set, and look them up in the T2 map
+/// `KERNEL_BATCH` at a time. If the SSP is in t2map, we have found a match for each of its T2 masks! Check each
+/// candidate for the remaining conditions, and save it if they are met (otherwise it is
+/// a `t3_miss`). Matches `size` rules out are t3 misses too, but never get as far as a big
+/// integer.
 fn final_sieve_kernel<M:Modulus>(
     t1_product_set: &ProductSet<M>,
-    t2map: &HashMap<u64, u32>,
+    t2map: &T2Index,
     start_idx: u64,
     end_idx: u64,
-    t1: &[u64],
-    t2: &[u64],
-    t3_misses: &AtomicUsize,
-    results: &Mutex<Vec<Pseudoprime>>
-) {
-    for (t1_mask, v) in ProductIter::new(t1_product_set, start_idx, end_idx) {
-        match t2map.get(&v) {
-            Some(t2_mask) => {
-                match check_prime(&MIN_N, t1, t2, t1_mask as u32, *t2_mask) {
+    size: Option<&SizeFilter>,
+    check: &Check,
+    progress: &progress::ProgressReporter
+) -> (Vec<Pseudoprime>, SieveStats, bool) {
+    let mut results = Vec::new();
+    let mut stats = SieveStats::default();
+    let mut handle = progress.handle();
+
+    let mut masks = Vec::with_capacity(KERNEL_BATCH);
+    let mut ssps = Vec::with_capacity(KERNEL_BATCH);
+    let mut found = Vec::with_capacity(KERNEL_BATCH);
+
+    let mut check_batch = |masks: &mut Vec<u64>, ssps: &mut Vec<u64>| {
+        t2map.get_batch(ssps, &mut found);
+
+        for ((t1_mask, v), matches) in masks.iter().zip(ssps.iter()).zip(found.iter()) {
+            if matches.is_empty() {
+                continue;
+            }
+            stats.ssps.push(*v);
+            stats.matches += matches.len();
+            stats.duplicates += matches.len() - 1;
+
+            for (_, t2_mask) in matches.iter() {
+                if size.is_some_and(|size| !size.might_fit(*t1_mask as u32, *t2_mask)) {
+                    stats.t3_misses += 1;
+                    stats.size_misses += 1;
+                    continue;
+                }
+                match check(*t1_mask as u32, *t2_mask) {
                     Some(result) => {
-                        let mut guard = results.lock().unwrap();
-                        guard.push(result);
+                        results.push(result);
                     }
                     None => {
-                        t3_misses.fetch_add(1, Ordering::Relaxed);
+                        stats.t3_misses += 1;
                     }
                 }
             }
-            None => {}
+        }
+
+        handle.report(ssps.len());
+        masks.clear();
+        ssps.clear();
+
+        // whether to stop here
+        handle.is_cancelled()
+    };
+
+    for (t1_mask, v) in ProductIter::new(t1_product_set, start_idx, end_idx) {
+        masks.push(t1_mask);
+        ssps.push(v);
+
+        if ssps.len() == KERNEL_BATCH && check_batch(&mut masks, &mut ssps) {
+            return (results, stats, false);
         }
     }
+
+    check_batch(&mut masks, &mut ssps);
+
+    (results, stats, true)
 }
 
+/// What the final sieve made of the T2 map: the (T1 mask, T2 mask) matches, those past the
+/// first for an SSP with more than one T2 mask, the matches turned down (and of those, the
+/// ones `size` did without a check), and the SSPs that matched at all.
+#[derive(Clone, Debug, Default)]
+struct SieveStats {
+    matches: usize,
+    duplicates: usize,
+    t3_misses: usize,
+    size_misses: usize,
+    ssps: Vec<u64>
+}
 
 /// The final step is to *recompute* the SSPs for T1_INVERSE (this is a memory-bound computation).
-/// If the SSP is a key in the map from the previous step, we have found a candidate pseudoprime.
-/// We check the remaining conditions, and if the candidate is satisfactory, add it to the output vector. 
-pub fn final_sieve(
+/// If the SSP is a key in the map from the previous step (looked up with `t2map`, an index on
+/// it), we have found a candidate pseudoprime.
+/// We `check` the remaining conditions, and if the candidate is satisfactory, add it to the output vector. 
+/// `size`, if given, turns down matches of the wrong size first, from the log2 of their factors.
+/// If `cancel` fires, the output holds the pseudoprimes found so far.
+/// Given earlier results to `resume` from, we add to them, skipping the ranges they already cover.
+pub fn final_sieve<M: Modulus + Send + Sync + 'static>(
     t1_forward: &[u64],
-    t2map: HashMap<u64, u32>,
-    t1: &[u64],
-    t2: &[u64]
-) -> Vec<Pseudoprime> {
-    let t2map = Arc::new(t2map);
-    let pool = ThreadPool::new(|_| ());
-    let t1_product_set = Arc::new(ProductSet::new(t1_forward, MODULUS));
-    // a counter for candidates which have a matching subset in T2 and T1_INVERSE,
-    // but which do not satisfy the remaining conditions imposed by Bleichenbacher.
-    let t3_misses = Arc::new(AtomicUsize::new(0));
-    let results = Arc::new(Mutex::new(Vec::new()));
-
-    for task in 0..N_TASKS {
-        let t2map = t2map.clone();
-        let t1_product_set = t1_product_set.clone();
-
-        let start_idx = task * N_TASKS;
-        let end_idx = start_idx + N_TASKS;
-
-        let t1 = Vec::from(t1);
-        let t2 = Vec::from(t2);
-        let t3_misses = t3_misses.clone();
-        let results = results.clone();
-
-        pool.execute(move |_| {
-            final_sieve_kernel(&t1_product_set, &t2map, start_idx, end_idx, &t1, &t2,
-                &t3_misses, &results);
-        })
+    t2map: &T2Index,
+    size: Option<&SizeFilter>,
+    check: &Check,
+    context: &PhaseContext<M>,
+    cancel: &CancellationToken,
+    resume: Option<Outcome<Vec<Pseudoprime>>>
+) -> Outcome<Vec<Pseudoprime>> {
+    let pool = context.workers.install(|_| ());
+    let t1_product_set = ProductSet::new(t1_forward, context.modulus);
+    let total_work = 1u64 << t1_forward.len();
+    let progress = progress::ProgressReporter::with_cancel("final_sieve", total_work as usize, cancel);
+
+    let resumed = resume.is_some();
+    let (ranges, mut tasks, mut results) = match resume {
+        Some(saved) => {
+            println!("[final_sieve] resuming with {} pseudoprimes, {}", saved.value.len(), saved);
+            let (ranges, tasks) = context.split(total_work, &saved.completed);
+            (ranges, tasks, saved.value)
+        },
+        None => {
+            let (ranges, tasks) = context.split(total_work, &[]);
+            (ranges, tasks, Vec::new())
+        }
+    };
+
+    // accumulate results. t3 misses are candidates which have a matching subset in T2 and
+    // T1_INVERSE, but which do not satisfy the remaining conditions imposed by Bleichenbacher.
+    let mut stats = SieveStats::default();
+    let mut last_checkpoint = Instant::now();
+
+    // the kernels borrow everything for the duration of the scope
+    pool.scope(|scope| {
+        let handles: Vec<_> = ranges.iter().cloned().map(|range| {
+            let (t1_product_set, progress) = (&t1_product_set, &progress);
+            scope.submit(move |_| final_sieve_kernel(t1_product_set, t2map, range.start, range.end, size, check, progress))
+        }).collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let (found, task_stats, completed) = handle.join().unwrap_or_else(|panic| panic!("[final_sieve] {}", panic));
+            results.extend(found);
+            stats.matches += task_stats.matches;
+            stats.duplicates += task_stats.duplicates;
+            stats.t3_misses += task_stats.t3_misses;
+            stats.size_misses += task_stats.size_misses;
+            stats.ssps.extend(task_stats.ssps);
+            tasks.push((ranges[i].clone(), completed));
+
+            context.checkpoint(&mut last_checkpoint, || Checkpoint::Results(so_far(results.clone(), &tasks, &ranges[i + 1..])));
+        }
+    });
+
+    pool.join().unwrap_or_else(|panic| panic!("[final_sieve] {}", panic));
+    stats.ssps.sort_unstable();
+    stats.ssps.dedup();
+
+    // a range that was cut short last time gets searched again from the start
+    results.sort_by(|a, b| a.pseudoprime.cmp(&b.pseudoprime));
+    results.dedup_by(|a, b| a.pseudoprime == b.pseudoprime);
+
+    println!("Found {} pseudoprimes in {} matches ({} more from SSPs with several T2 masks), with {} T3 misses ({} ruled out by size)",
+        results.len(), stats.matches, stats.duplicates, stats.t3_misses, stats.size_misses);
+    // the SSPs matched before resuming, or in the ranges not searched, aren't known, so they
+    // would count as false positives
+    let outcome = Outcome::from_tasks(results, &tasks, cancel);
+    if outcome.is_complete() && !resumed {
+        println!("{} T2 false positives", t2map.map().ssps() - stats.ssps.len());
     }
-    // wait for all tasks to complete
-    pool.join();
 
-    // accumulate results
-    let results = Arc::try_unwrap(results).unwrap();
-    let results = results.into_inner().unwrap();
+    outcome
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_merge_replicas() {
+        let builder = conc_bloom::Builder::new(1 << 16, 2);
+        let mut replicas: Vec<(u32, BloomFilter<u64>)> = (0..4).map(|node| (node, builder.build())).collect();
 
-    let t3_misses = t3_misses.load(Ordering::SeqCst);
+        for (node, replica) in replicas.iter() {
+            for i in 0..100u64 {
+                replica.put(&(i * 4 + *node as u64));
+            }
+        }
 
-    println!("Found {} pseudoprimes, with {} T3 misses, {} T2 false positives",
-        results.len(), t3_misses, t2map.len() - t3_misses - results.len());
+        merge_replicas(&mut replicas, &Workers::new());
 
-    return results;
+        for (_, replica) in replicas.iter() {
+            for i in 0..400u64 {
+                assert!(replica.maybe_present(&i));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_cancelled_kernels() {
+        let product_set = ProductSet::new(&T1_INVERSE[..12], MODULUS);
+        let filter = conc_bloom::Builder::new(1 << 16, 2).build();
+
+        let cancel = CancellationToken::new();
+        let progress = progress::ProgressReporter::with_cancel("test", 1 << 12, &cancel);
+        assert!(bloom_t1_kernel(&product_set, 0, 1 << 11, &filter, None, &progress));
+
+        cancel.cancel();
+        assert!(!bloom_t1_kernel(&product_set, 1 << 11, 1 << 12, &filter, None, &progress));
+
+        let (found, completed) = build_t2_kernel(&filter, &progress, &product_set, 0, 1 << 12);
+        assert!(!completed);
+        assert!(found.len() <= KERNEL_BATCH);
+    }
+
+    #[test]
+    pub fn test_partitioned_bloom_t1() {
+        let t1 = &T1_INVERSE[..14];
+        let workers = Workers::new();
+        let context = PhaseContext { n_tasks: 64, ..PhaseContext::new(&workers) };
+        let atomic = FilterConfig { size: 1 << 16, seed: Some(5), ..FilterConfig::default() };
+        let partitioned = FilterConfig { insert_mode: InsertMode::Partitioned { regions: 0 }, ..atomic };
+
+        let expected = bloom_t1(t1, &atomic, &context, &CancellationToken::new(), None);
+        let found = bloom_t1(t1, &partitioned, &context, &CancellationToken::new(), None);
+        assert!(found.is_complete());
+
+        for (node, filter) in found.value.iter() {
+            assert!(matches!(filter.insert_mode(), InsertMode::Partitioned { regions } if regions > 0));
+            for i in 0..1u64 << 16 {
+                assert_eq!(filter.maybe_present(&i), expected.value[node].maybe_present(&i));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_checkpoints() {
+        let (t1, t2) = (&T1_INVERSE[..12], &T2[..12]);
+        let config = FilterConfig { size: 1 << 16, seed: Some(3), ..FilterConfig::default() };
+        let workers = Workers::new();
+
+        let path = std::env::temp_dir().join(format!("pseudoprimes-checkpoints-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let run = std::cell::RefCell::new(crate::checkpoint::RunDir::open(&path, t1, t2, &config).unwrap());
+
+        // only the checkpoints are saved, as if the phases were killed as they ended
+        let save = |progress: Checkpoint| match progress {
+            Checkpoint::Filter(filter) => run.borrow_mut().save_filter(&filter).unwrap(),
+            Checkpoint::T2Entries(entries) => run.borrow_mut().append_t2_map(&entries).unwrap(),
+            Checkpoint::Results(results) => run.borrow_mut().save_results(&results).unwrap()
+        };
+        let context = PhaseContext { n_tasks: 16, checkpoint: Some((Duration::ZERO, &save)), ..PhaseContext::new(&workers) };
+
+        let filters = bloom_t1(t1, &config, &context, &CancellationToken::new(), None);
+        let t2_map = build_t2(filters.value.clone(), t2, &context, &CancellationToken::new(), None);
+        assert!(!t2_map.value.is_empty());
+
+        let saved = run.borrow().filter().unwrap().unwrap();
+        assert!(saved.is_complete());
+        let loaded = bloom_t1(t1, &config, &PhaseContext::new(&workers), &CancellationToken::new(), Some(saved));
+        assert_eq!(build_t2(loaded.value, t2, &PhaseContext::new(&workers), &CancellationToken::new(), None).value, t2_map.value);
+
+        let saved = run.borrow().t2_map().unwrap().unwrap();
+        assert!(saved.is_complete());
+        assert_eq!(saved.value, t2_map.value);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_resume() {
+        let (t1, t2) = (&T1_INVERSE[..12], &T2[..12]);
+        let config = FilterConfig { size: 1 << 16, seed: Some(3), ..FilterConfig::default() };
+        let workers = Workers::new();
+        let context = PhaseContext::new(&workers);
+        let stopped = CancellationToken::new();
+        stopped.cancel();
+
+        let path = std::env::temp_dir().join(format!("pseudoprimes-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut run = crate::checkpoint::RunDir::open(&path, t1, t2, &config).unwrap();
+
+        let partial = bloom_t1(t1, &config, &context, &stopped, None);
+        assert!(!partial.is_complete());
+        run.save_filter(&partial).unwrap();
+
+        let resumed = bloom_t1(t1, &config, &context, &CancellationToken::new(), run.filter().unwrap());
+        let full = bloom_t1(t1, &config, &context, &CancellationToken::new(), None);
+        assert!(resumed.is_complete());
+        assert_eq!(resumed.completed, vec![0..1 << 12]);
+
+        let expected = build_t2(full.value.clone(), t2, &context, &CancellationToken::new(), None);
+        assert!(!expected.value.is_empty());
+        assert_eq!(build_t2(resumed.value, t2, &context, &CancellationToken::new(), None).value, expected.value);
+
+        let partial = build_t2(full.value.clone(), t2, &context, &stopped, None);
+        assert!(!partial.is_complete());
+        let resumed = build_t2(full.value, t2, &context, &CancellationToken::new(), Some(partial));
+        assert!(resumed.is_complete());
+        assert_eq!(resumed.value, expected.value);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
@@ -0,0 +1,401 @@
+// cancel.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Stopping a search part way through. Kernels see cancellation through their
+// `ProgressHandle`, finish the batch they're on and return; each phase then reports
+// which of its task ranges ran to completion alongside whatever it found so far.
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// the last SIGINT/SIGTERM received, or 0
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// how many SIGINTs/SIGTERMs have been received; a token only counts those after it was made
+static SIGNALS_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+/// set by a signal, and cleared when a token starts watching; a second signal while it's
+/// set kills the process as usual
+static SIGNAL_PENDING: AtomicBool = AtomicBool::new(false);
+/// how many tokens are watching for signals; the handlers are installed while there are any
+static WATCHERS: Mutex<usize> = Mutex::new(0);
+#[cfg(unix)]
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Why a token was cancelled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    /// `cancel` was called
+    Requested,
+    /// the token's time limit ran out
+    TimeLimit,
+    /// the process received this signal
+    Signal(i32)
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CancelReason::Requested => write!(f, "cancelled"),
+            CancelReason::TimeLimit => write!(f, "time limit reached"),
+            CancelReason::Signal(signal) => write!(f, "received signal {}", signal)
+        }
+    }
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    reason: Mutex<Option<CancelReason>>,
+    deadline: Option<Instant>,
+    /// the count of signals received when the token was made, if it watches for them
+    signals_before: Option<usize>,
+    parent: Option<CancellationToken>
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.signals_before.is_none() {
+            return;
+        }
+
+        let mut watchers = WATCHERS.lock().unwrap();
+        *watchers -= 1;
+        if *watchers == 0 {
+            restore_handlers();
+        }
+    }
+}
+
+/// A flag that tells running kernels to stop. Clones share the flag; a child (with its own
+/// time limit) is cancelled along with its parent, but not the other way round.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl CancellationToken {
+    /// a token that is only cancelled by `cancel`
+    pub fn new() -> Self {
+        CancellationToken::build(None, None, None)
+    }
+
+    /// A token that is also cancelled by SIGINT or SIGTERM received from now on. The first
+    /// such signal cancels every token watching for it; a second one, before another token
+    /// starts watching, kills the process as usual. The handlers stay installed for as long
+    /// as a watching token is alive, and the default ones are put back after.
+    pub fn on_signals() -> Self {
+        let mut watchers = WATCHERS.lock().unwrap();
+        if *watchers == 0 {
+            install_handlers();
+        }
+        *watchers += 1;
+        SIGNAL_PENDING.store(false, Ordering::SeqCst);
+
+        CancellationToken::build(None, Some(SIGNALS_RECEIVED.load(Ordering::SeqCst)), None)
+    }
+
+    /// a child token that is cancelled with this one, or when `limit` has passed
+    pub fn with_time_limit(&self, limit: Duration) -> Self {
+        CancellationToken::build(Some(Instant::now() + limit), None, Some(self.clone()))
+    }
+
+    /// a child token that is cancelled with this one, or when `limit` (if any) has passed
+    pub fn child(&self, limit: Option<Duration>) -> Self {
+        match limit {
+            Some(limit) => self.with_time_limit(limit),
+            None => CancellationToken::build(None, None, Some(self.clone()))
+        }
+    }
+
+    fn build(deadline: Option<Instant>, signals_before: Option<usize>, parent: Option<CancellationToken>) -> Self {
+        CancellationToken { inner: Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            deadline,
+            signals_before,
+            parent
+        })}
+    }
+
+    pub fn cancel(&self) {
+        self.cancel_with(CancelReason::Requested);
+    }
+
+    fn cancel_with(&self, reason: CancelReason) {
+        let mut current = self.inner.reason.lock().unwrap();
+        if current.is_none() {
+            *current = Some(reason);
+        }
+
+        self.inner.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Checks the flag, the time limit, signals and the parent. Cheap, but not free;
+    /// kernels get it through `ProgressHandle::is_cancelled`, which checks now and then.
+    pub fn is_cancelled(&self) -> bool {
+        if self.inner.cancelled.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        if let Some(reason) = self.check() {
+            self.cancel_with(reason);
+            return true;
+        }
+
+        false
+    }
+
+    fn check(&self) -> Option<CancelReason> {
+        if let Some(before) = self.inner.signals_before {
+            if SIGNALS_RECEIVED.load(Ordering::SeqCst) != before {
+                return Some(CancelReason::Signal(SIGNAL.load(Ordering::SeqCst)));
+            }
+        }
+
+        if let Some(deadline) = self.inner.deadline {
+            if Instant::now() >= deadline {
+                return Some(CancelReason::TimeLimit);
+            }
+        }
+
+        let parent = self.inner.parent.as_ref()?;
+        if parent.is_cancelled() {
+            return parent.reason();
+        }
+
+        None
+    }
+
+    /// why the token was cancelled, or None if it hasn't been (as of the last check)
+    pub fn reason(&self) -> Option<CancelReason> {
+        *self.inner.reason.lock().unwrap()
+    }
+}
+
+#[cfg(unix)]
+fn install_handlers() {
+    for &signal in SIGNALS.iter() {
+        unsafe { libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+    }
+}
+
+#[cfg(unix)]
+fn restore_handlers() {
+    for &signal in SIGNALS.iter() {
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+}
+
+/// elsewhere there are no signals to watch for, so only `cancel` and time limits stop a token
+#[cfg(not(unix))]
+fn install_handlers() {}
+
+#[cfg(not(unix))]
+fn restore_handlers() {}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    // only async-signal-safe calls in here
+    if SIGNAL_PENDING.swap(true, Ordering::SeqCst) {
+        // the signal is blocked while we handle it, so this takes effect when we return
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        return;
+    }
+
+    SIGNAL.store(signal, Ordering::SeqCst);
+    SIGNALS_RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+/// What a phase produced, and how much of its work that covers
+pub struct Outcome<T> {
+    pub value: T,
+    /// the task ranges that ran to completion, merged where they touch, in increasing order
+    pub completed: Vec<Range<u64>>,
+    /// the whole range the phase was to cover
+    pub total: Range<u64>,
+    /// why the phase stopped before covering `total`, if it did
+    pub cancelled: Option<CancelReason>
+}
+
+impl<T> Outcome<T> {
+    /// Builds the outcome of running `tasks`, given whether each one finished. The phase
+    /// only counts as cancelled if some task didn't finish.
+    pub fn from_tasks(value: T, tasks: &[(Range<u64>, bool)], cancel: &CancellationToken) -> Self {
+        let total = match (tasks.iter().map(|(range, _)| range.start).min(), tasks.iter().map(|(range, _)| range.end).max()) {
+            (Some(start), Some(end)) => start..end,
+            _ => 0..0
+        };
+
+        let mut finished: Vec<Range<u64>> = tasks.iter()
+            .filter(|(_, done)| *done)
+            .map(|(range, _)| range.clone())
+            .collect();
+        finished.sort_by_key(|range| range.start);
+
+        let completed = merge_ranges(finished);
+        let cancelled = if tasks.iter().all(|(_, done)| *done) {
+            None
+        } else {
+            Some(cancel.reason().unwrap_or(CancelReason::Requested))
+        };
+
+        Outcome { value, completed, total, cancelled }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.cancelled.is_none()
+    }
+
+    /// how much of `total` the completed ranges cover
+    pub fn completed_len(&self) -> u64 {
+        self.completed.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// the same outcome, with a different value
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Outcome<U> {
+        Outcome { value: f(self.value), completed: self.completed, total: self.total, cancelled: self.cancelled }
+    }
+}
+
+impl<T> fmt::Display for Outcome<T> {
+    /// e.g. "complete", or "time limit reached after 1024/4096"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cancelled {
+            None => write!(f, "complete"),
+            Some(reason) => write!(f, "{} after {}/{}", reason, self.completed_len(), self.total.end - self.total.start)
+        }
+    }
+}
+
+/// task ranges, each with whether it ran to completion
+pub type Tasks = Vec<(Range<u64>, bool)>;
+
+/// Splits `tasks` into those still to run and those already inside `completed` (e.g. from
+/// an earlier, cancelled run). The latter come back as finished tasks, ready to be passed
+/// to `Outcome::from_tasks` along with the results of the rest.
+pub fn skip_completed(tasks: Vec<Range<u64>>, completed: &[Range<u64>]) -> (Vec<Range<u64>>, Tasks) {
+    let (done, pending): (Vec<_>, Vec<_>) = tasks.into_iter().partition(|task| {
+        completed.iter().any(|range| range.start <= task.start && task.end <= range.end)
+    });
+
+    (pending, done.into_iter().map(|task| (task, true)).collect())
+}
+
+/// merges sorted ranges that touch or overlap
+pub fn merge_ranges(ranges: impl IntoIterator<Item = Range<u64>>) -> Vec<Range<u64>> {
+    let mut merged: Vec<Range<u64>> = Vec::new();
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range)
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        let child = token.child(None);
+
+        assert!(!token.is_cancelled());
+        assert_eq!(token.reason(), None);
+
+        clone.cancel();
+
+        assert!(token.is_cancelled());
+        assert!(child.is_cancelled());
+        assert_eq!(child.reason(), Some(CancelReason::Requested));
+    }
+
+    #[test]
+    pub fn test_child_does_not_cancel_parent() {
+        let token = CancellationToken::new();
+        let child = token.child(None);
+
+        child.cancel();
+
+        assert!(child.is_cancelled());
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    pub fn test_time_limit() {
+        let token = CancellationToken::new();
+        let limited = token.with_time_limit(Duration::from_millis(20));
+        let unlimited = token.child(Some(Duration::from_secs(3600)));
+
+        assert!(!limited.is_cancelled());
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(limited.is_cancelled());
+        assert_eq!(limited.reason(), Some(CancelReason::TimeLimit));
+        assert!(!unlimited.is_cancelled());
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    pub fn test_outcome() {
+        let token = CancellationToken::new();
+        let tasks = vec![(0..10, true), (10..20, true), (20..30, false), (30..40, true)];
+
+        // nothing was cancelled yet, but a task didn't finish
+        let outcome = Outcome::from_tasks((), &tasks, &token);
+        assert_eq!(outcome.completed, vec![0..20, 30..40]);
+        assert_eq!(outcome.total, 0..40);
+        assert_eq!(outcome.completed_len(), 30);
+        assert_eq!(outcome.cancelled, Some(CancelReason::Requested));
+        assert_eq!(outcome.to_string(), "cancelled after 30/40");
+
+        token.with_time_limit(Duration::from_secs(0)).is_cancelled();
+        let done: Vec<_> = tasks.iter().map(|(range, _)| (range.clone(), true)).collect();
+        let outcome = Outcome::from_tasks(5, &done, &token).map(|n| n * 2);
+
+        assert!(outcome.is_complete());
+        assert_eq!(outcome.completed, vec![0..40]);
+        assert_eq!(outcome.value, 10);
+        assert_eq!(outcome.to_string(), "complete");
+    }
+
+    #[test]
+    pub fn test_skip_completed() {
+        let tasks = vec![0..10, 10..20, 20..30, 30..40];
+        let (pending, done) = skip_completed(tasks.clone(), &[0..15, 20..40]);
+
+        assert_eq!(pending, vec![10..20]);
+        assert_eq!(done, vec![(0..10, true), (20..30, true), (30..40, true)]);
+
+        // resuming: the earlier tasks come first in `done`, the ones run now go after them
+        let mut tasks = done;
+        tasks.push((10..20, true));
+        let outcome = Outcome::from_tasks((), &tasks, &CancellationToken::new());
+        assert_eq!(outcome.total, 0..40);
+        assert_eq!(outcome.completed, vec![0..40]);
+
+        let (pending, done) = skip_completed(tasks.iter().map(|(range, _)| range.clone()).collect(), &[]);
+        assert_eq!((pending.len(), done.len()), (4, 0));
+    }
+
+    #[test]
+    pub fn test_merge_ranges() {
+        assert_eq!(merge_ranges(vec![0..5, 5..7, 9..10, 9..12, 20..21]), vec![0..7, 9..12, 20..21]);
+        assert_eq!(merge_ranges(Vec::new()), Vec::<Range<u64>>::new());
+    }
+}
@@ -0,0 +1,144 @@
+// candidates.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// The candidate set R for any M: the numbers r = d + 1, for the divisors d of M, that pass
+// a predicate (for a problem instance, that r is a prime within the bounds with the right
+// symbols). The divisors are walked in a reflected mixed-radix Gray code on their exponent
+// vectors, so each one is the one before times or divided by a single prime, rather than
+// multiplied out from scratch; the predicate, which is where the time goes, runs on every
+// CPU.
+
+use std::thread;
+
+/// R, in increasing order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CandidateSet {
+    candidates: Vec<u64>
+}
+
+impl CandidateSet {
+    /// Every r = d + 1 for a divisor d of the M with prime factorization `factors` (as
+    /// (prime, exponent)) that `predicate` accepts. The order is the same whatever the
+    /// threads, since it's sorted.
+    pub fn generate(factors: &[(u64, u32)], predicate: impl Fn(u64) -> bool + Sync) -> Self {
+        let divisors: Vec<u64> = Divisors::new(factors).collect();
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let per_thread = divisors.len().div_ceil(threads).max(1);
+        let predicate = &predicate;
+
+        let mut candidates: Vec<u64> = thread::scope(|scope| {
+            let handles: Vec<_> = divisors.chunks(per_thread).map(|chunk| scope.spawn(move || {
+                chunk.iter().filter_map(|d| d.checked_add(1)).filter(|r| predicate(*r)).collect::<Vec<u64>>()
+            })).collect();
+
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        candidates.sort_unstable();
+
+        CandidateSet { candidates }
+    }
+
+    pub fn as_slice(&self) -> &[u64] {
+        &self.candidates
+    }
+
+    pub fn into_vec(self) -> Vec<u64> {
+        self.candidates
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn contains(&self, r: u64) -> bool {
+        self.candidates.binary_search(&r).is_ok()
+    }
+}
+
+/// The divisors of M in Gray code order: each step moves one prime's exponent up or down
+/// by one, the first prime fastest. The exponents below the one that moves turn around
+/// each time they reach 0 or their exponent in M.
+pub struct Divisors<'a> {
+    factors: &'a [(u64, u32)],
+    exponents: Vec<u32>,
+    rising: Vec<bool>,
+    /// the next divisor, or None once they've all been
+    divisor: Option<u64>
+}
+
+impl<'a> Divisors<'a> {
+    /// `factors` has to multiply to no more than 64 bits
+    pub fn new(factors: &'a [(u64, u32)]) -> Self {
+        Divisors { factors, exponents: vec![0; factors.len()], rising: vec![true; factors.len()], divisor: Some(1) }
+    }
+}
+
+impl<'a> Iterator for Divisors<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let divisor = self.divisor?;
+
+        self.divisor = None;
+        for (i, (prime, exponent)) in self.factors.iter().enumerate() {
+            if self.rising[i] && self.exponents[i] < *exponent {
+                self.exponents[i] += 1;
+                self.divisor = Some(divisor * prime);
+                break;
+            }
+            if !self.rising[i] && self.exponents[i] > 0 {
+                self.exponents[i] -= 1;
+                self.divisor = Some(divisor / prime);
+                break;
+            }
+            self.rising[i] = !self.rising[i];
+        }
+
+        Some(divisor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_divisors() {
+        let factors = [(2, 2), (3, 1), (5, 3), (7, 2)];
+        let m: u64 = 4 * 3 * 125 * 49;
+
+        let walked: Vec<u64> = Divisors::new(&factors).collect();
+        assert_eq!(walked.len(), 3 * 2 * 4 * 3);
+        for pair in walked.windows(2) {
+            let (small, big) = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+            assert!(factors.iter().any(|(prime, _)| small * prime == big), "{:?}", pair);
+        }
+
+        let mut sorted = walked.clone();
+        sorted.sort();
+        assert_eq!(sorted, (1..=m).filter(|d| m.checked_rem(*d) == Some(0)).collect::<Vec<u64>>());
+
+        assert_eq!(Divisors::new(&[]).collect::<Vec<u64>>(), [1]);
+    }
+
+    #[test]
+    pub fn test_generate() {
+        let factors = [(2, 3), (3, 2), (5, 1), (7, 1), (11, 1), (13, 1)];
+        let m: u64 = 8 * 9 * 5 * 7 * 11 * 13;
+        let is_odd_square = |r: u64| r & 1 == 1 && (1..=r).take_while(|s| s * s <= r).any(|s| s * s == r);
+
+        let set = CandidateSet::generate(&factors, is_odd_square);
+        let expected: Vec<u64> = (2..=m + 1).filter(|r| m.checked_rem(r - 1) == Some(0) && is_odd_square(*r)).collect();
+        assert!(!set.is_empty());
+        assert_eq!(set.as_slice(), &expected[..]);
+        assert_eq!(set.len(), expected.len());
+        assert!(set.contains(expected[0]) && !set.contains(expected[0] + 1));
+
+        assert!(CandidateSet::generate(&factors, |_| false).is_empty());
+        assert_eq!(CandidateSet::generate(&factors, |_| true).len(), 4 * 3 * 2 * 2 * 2 * 2);
+    }
+}
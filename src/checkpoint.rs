@@ -0,0 +1,651 @@
+// checkpoint.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Saving a search's progress to a run directory, so that an interrupted run can pick up
+// where it left off. Each phase's output goes to its own file, and a text manifest
+// records which task ranges those files cover. Files are written to a temporary name and
+// renamed into place, and the manifest is only updated after the file it describes, so
+// the manifest never claims more than is on disk. (It can claim less, if we're stopped
+// in between; that only means some work is redone.)
+//
+// A run directory holds:
+//   manifest          key=value lines: the parameters of the run, then the completed
+//                     and total ranges of each phase that has saved anything. The
+//                     final_sieve.check, .bits and .factors keys say what phase 3 was
+//                     looking for: its saved results are dropped if they change, but
+//                     the filter and T2 map don't depend on them
+//   bloom_t1.bits     the phase 1 filter, as saved by `BloomFilter::save`
+//   t2_map.bin        the phase 2 map, as little-endian (u64 SSP, u32 mask) pairs, an SSP
+//                     once for each of its masks. Checkpoints add to the end of it, so
+//                     t2_map.entries in the manifest says how many of the pairs count
+//   final_sieve.txt   the pseudoprimes found so far, one per line with their factors
+//
+// Besides at the end of a phase, progress is saved every so often while it runs (see
+// `PhaseContext::checkpoint`), so that a run that is killed outright only loses the work
+// since then.
+//
+// The same formats are used for the files the phases read and write when run one at a
+// time from the command line, except that a standalone filter file starts with a header
+// (see `write_filter_file`) so that it can be loaded without a manifest.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rug::Integer;
+
+use crate::bloomfilter::{BloomFilter, FilterConfig};
+use crate::cancel::{CancelReason, Outcome};
+use crate::magic_numbers::{Pseudoprime, PseudoprimeBounds};
+use crate::t2_map::T2Map;
+
+const MANIFEST: &str = "manifest";
+const FILTER_FILE: &str = "bloom_t1.bits";
+const T2_MAP_FILE: &str = "t2_map.bin";
+const RESULTS_FILE: &str = "final_sieve.txt";
+const VERSION: &str = "1";
+
+/// the start of a standalone filter file, followed by its seed, size and hash count
+const FILTER_MAGIC: &[u8; 8] = b"PSPBLOOM";
+const FILTER_HEADER_LEN: u64 = 32;
+/// the bytes of a (u64 SSP, u32 mask) pair in a T2 map file
+const T2_ENTRY_LEN: u64 = 12;
+
+/// the manifest keys that must match for a run to be resumed
+const PARAMS: [&str; 5] = ["version", "t1", "t2", "filter.size", "filter.hashes"];
+
+/// the manifest keys that must match for phase 3's saved results to be kept
+const SIEVE_PARAMS: [&str; 3] = ["final_sieve.check", "final_sieve.bits", "final_sieve.factors"];
+
+/// The saved phase 1 filter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterFile {
+    pub path: PathBuf,
+    /// the seed of the filter's hash functions; see `conc_bloom::Builder::seed`
+    pub seed: u64,
+    /// where the filter's bits start in the file
+    pub offset: u64
+}
+
+impl FilterFile {
+    /// replaces `filter`'s bits with the saved ones
+    pub fn load_into(&self, filter: &BloomFilter<u64>) -> io::Result<()> {
+        assert_eq!(filter.seed(), self.seed, "Loading {} into a filter with different hashes", self.path.display());
+
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        if len != self.offset + filter.size() as u64 / 8 {
+            return Err(invalid(format!("{} holds {} bytes of filter, expected {}", self.path.display(),
+                len.saturating_sub(self.offset), filter.size() / 8)));
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        filter.load(BufReader::new(file))
+    }
+}
+
+/// A directory holding the saved progress of one search
+pub struct RunDir {
+    path: PathBuf,
+    manifest: BTreeMap<String, String>
+}
+
+impl RunDir {
+    /// Opens the run directory at `path`, creating it if need be. If it already holds a
+    /// run, that run must have been searching `t1` and `t2` with a filter of the same size.
+    /// A new run takes its filter seed from `config`, or picks one at random.
+    pub fn open(path: &Path, t1: &[u64], t2: &[u64], config: &FilterConfig) -> io::Result<RunDir> {
+        fs::create_dir_all(path)?;
+
+        let mut params = BTreeMap::new();
+        params.insert("version".to_string(), VERSION.to_string());
+        params.insert("t1".to_string(), fingerprint(t1));
+        params.insert("t2".to_string(), fingerprint(t2));
+        params.insert("filter.size".to_string(), config.size.next_power_of_two().to_string());
+        params.insert("filter.hashes".to_string(), config.hashes.to_string());
+
+        let manifest_path = path.join(MANIFEST);
+        if !manifest_path.exists() {
+            let seed = config.seed.unwrap_or_else(rand::random);
+            params.insert("filter.seed".to_string(), seed.to_string());
+
+            let run = RunDir { path: path.to_path_buf(), manifest: params };
+            run.write_manifest()?;
+            return Ok(run);
+        }
+
+        let manifest = read_manifest(&manifest_path)?;
+        for key in PARAMS.iter() {
+            if manifest.get(*key) != params.get(*key) {
+                return Err(invalid(format!("{} is a run with {} = {}, not {}", path.display(), key,
+                    manifest.get(*key).map_or("(missing)", |value| value.as_str()), params[*key])));
+            }
+        }
+
+        let run = RunDir { path: path.to_path_buf(), manifest };
+        run.filter_seed()?;
+        Ok(run)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the seed phase 1 has to use for its filter to be saved and resumed
+    pub fn filter_seed(&self) -> io::Result<u64> {
+        let seed = self.manifest.get("filter.seed").ok_or_else(|| invalid("no filter.seed in the manifest".to_string()))?;
+        seed.parse().map_err(|_| invalid(format!("bad filter.seed {:?}", seed)))
+    }
+
+    /// the saved phase 1 filter, and the ranges of T1 it covers
+    pub fn filter(&self) -> io::Result<Option<Outcome<FilterFile>>> {
+        let file = FilterFile { path: self.path.join(FILTER_FILE), seed: self.filter_seed()?, offset: 0 };
+        self.saved("bloom_t1", |_| Ok(file))
+    }
+
+    /// Saves the union of phase 1's filters. Once merged they all hold the same bits, but
+    /// partway through the phase each node's replica only has what its own node inserted.
+    pub fn save_filter(&mut self, outcome: &Outcome<HashMap<u32, Arc<BloomFilter<u64>>>>) -> io::Result<()> {
+        let mut filters: Vec<&Arc<BloomFilter<u64>>> = outcome.value.values().collect();
+        // with a single shared filter, every node has the same one
+        filters.sort_by_key(|filter| Arc::as_ptr(filter));
+        filters.dedup_by(|a, b| Arc::ptr_eq(a, b));
+
+        let (filter, others) = filters.split_first().ok_or_else(|| invalid("no filter to save".to_string()))?;
+        assert_eq!(filter.seed(), self.filter_seed()?, "Saving a filter built with a different seed");
+        let others: Vec<&BloomFilter<u64>> = others.iter().map(|other| other.as_ref()).collect();
+
+        self.save("bloom_t1", FILTER_FILE, outcome, |out| filter.save_union(&others, out))
+    }
+
+    /// the saved phase 2 map, and the ranges of T2 it covers
+    pub fn t2_map(&self) -> io::Result<Option<Outcome<T2Map>>> {
+        self.saved("t2_map", |run| {
+            let entries = run.t2_map_entries()?;
+            read_t2_map(File::open(run.path.join(T2_MAP_FILE))?.take(entries * T2_ENTRY_LEN))
+        })
+    }
+
+    pub fn save_t2_map(&mut self, outcome: &Outcome<T2Map>) -> io::Result<()> {
+        write_atomically(&self.path.join(T2_MAP_FILE), |out| write_t2_map(out, &outcome.value))?;
+
+        self.manifest.insert("t2_map.entries".to_string(), outcome.value.len().to_string());
+        self.record("t2_map", outcome)
+    }
+
+    /// Adds the T2 map entries found since the map was last saved to the end of the saved
+    /// one, and records the ranges it now covers. The entries may repeat ones already there.
+    pub fn append_t2_map(&mut self, outcome: &Outcome<Vec<(u64, u32)>>) -> io::Result<()> {
+        // anything past what the manifest counts is from an append that didn't finish
+        let saved = match self.manifest.get("t2_map.completed") {
+            Some(_) => self.t2_map_entries()?,
+            None => 0
+        };
+
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(self.path.join(T2_MAP_FILE))?;
+        file.set_len(saved * T2_ENTRY_LEN)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let mut out = BufWriter::new(file);
+        write_t2_entries(&mut out, outcome.value.iter())?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        self.manifest.insert("t2_map.entries".to_string(), (saved + outcome.value.len() as u64).to_string());
+        self.record("t2_map", outcome)
+    }
+
+    /// how many entries of the saved T2 map count; all of them, for a run from before
+    /// checkpoints added to the file
+    fn t2_map_entries(&self) -> io::Result<u64> {
+        match self.manifest.get("t2_map.entries") {
+            Some(entries) => entries.parse().map_err(|_| invalid(format!("bad t2_map.entries {:?}", entries))),
+            None => Ok(fs::metadata(self.path.join(T2_MAP_FILE))?.len() / T2_ENTRY_LEN)
+        }
+    }
+
+    /// Records what phase 3 looks for: the name of its check, and the bounds (if any) it
+    /// puts on the pseudoprimes. If saved results were found with anything different, the
+    /// manifest stops claiming them, so phase 3 starts over; returns true if it did.
+    pub fn sieve_params(&mut self, check: &str, bounds: Option<&PseudoprimeBounds>) -> io::Result<bool> {
+        let range = |range: Option<&std::ops::RangeInclusive<u32>>| match range {
+            Some(range) => format!("{}..={}", range.start(), range.end()),
+            None => "any".to_string()
+        };
+        let values = [check.to_string(), range(bounds.map(|bounds| &bounds.bits)), range(bounds.map(|bounds| &bounds.factors))];
+
+        if SIEVE_PARAMS.iter().zip(values.iter()).all(|(key, value)| self.manifest.get(*key) == Some(value)) {
+            return Ok(false);
+        }
+
+        let dropped = self.manifest.remove("final_sieve.total").is_some() | self.manifest.remove("final_sieve.completed").is_some();
+        for (key, value) in SIEVE_PARAMS.iter().zip(values) {
+            self.manifest.insert(key.to_string(), value);
+        }
+        self.write_manifest()?;
+
+        Ok(dropped)
+    }
+
+    /// the pseudoprimes phase 3 has found, and the ranges of T1 it has covered
+    pub fn results(&self) -> io::Result<Option<Outcome<Vec<Pseudoprime>>>> {
+        self.saved("final_sieve", |run| read_results(File::open(run.path.join(RESULTS_FILE))?))
+    }
+
+    pub fn save_results(&mut self, outcome: &Outcome<Vec<Pseudoprime>>) -> io::Result<()> {
+        self.save("final_sieve", RESULTS_FILE, outcome, |out| write_results(out, &outcome.value))
+    }
+
+    /// writes `phase`'s output to `file`, then records the ranges it covers in the manifest
+    fn save<T>(
+        &mut self,
+        phase: &str,
+        file: &str,
+        outcome: &Outcome<T>,
+        write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>
+    ) -> io::Result<()> {
+        write_atomically(&self.path.join(file), write)?;
+        self.record(phase, outcome)
+    }
+
+    /// records the ranges `phase`'s saved output covers, once it's on disk
+    fn record<T>(&mut self, phase: &str, outcome: &Outcome<T>) -> io::Result<()> {
+        self.manifest.insert(format!("{}.total", phase), format_ranges(std::slice::from_ref(&outcome.total)));
+        self.manifest.insert(format!("{}.completed", phase), format_ranges(&outcome.completed));
+        self.write_manifest()
+    }
+
+    /// reads `phase`'s output with `read`, if the manifest says it saved any
+    fn saved<T>(&self, phase: &str, read: impl FnOnce(&RunDir) -> io::Result<T>) -> io::Result<Option<Outcome<T>>> {
+        let (total, completed) = match (self.manifest.get(&format!("{}.total", phase)), self.manifest.get(&format!("{}.completed", phase))) {
+            (Some(total), Some(completed)) => (total, completed),
+            _ => return Ok(None)
+        };
+
+        let total = match parse_ranges(total).as_deref() {
+            Some([total]) => total.clone(),
+            _ => return Err(invalid(format!("bad {}.total {:?}", phase, total)))
+        };
+        let completed = parse_ranges(completed).ok_or_else(|| invalid(format!("bad {}.completed {:?}", phase, completed)))?;
+
+        // why it stopped isn't kept, only that it did
+        let cancelled = if completed == [total.clone()] { None } else { Some(CancelReason::Requested) };
+
+        Ok(Some(Outcome { value: read(self)?, completed, total, cancelled }))
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        write_atomically(&self.path.join(MANIFEST), |out| {
+            writeln!(out, "# pseudoprime search checkpoint")?;
+            for (key, value) in self.manifest.iter() {
+                writeln!(out, "{}={}", key, value)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Writes `filter` to `path` on its own: a header of the magic bytes and the filter's
+/// seed, size and hash count (as little-endian u64s), then its bits.
+pub fn write_filter_file(path: &Path, filter: &BloomFilter<u64>) -> io::Result<()> {
+    write_atomically(path, |out| {
+        out.write_all(FILTER_MAGIC)?;
+        for word in [filter.seed(), filter.size() as u64, filter.hashes() as u64] {
+            out.write_all(&word.to_le_bytes())?;
+        }
+
+        filter.save(out)
+    })
+}
+
+/// Reads the header of a file written by `write_filter_file`: the file to load the bits
+/// from, and the size, hash count and seed of the filter to load them into.
+pub fn read_filter_file(path: &Path) -> io::Result<(FilterFile, FilterConfig)> {
+    let mut header = [0u8; FILTER_HEADER_LEN as usize];
+    File::open(path)?.read_exact(&mut header)?;
+    if &header[..8] != FILTER_MAGIC {
+        return Err(invalid(format!("{} is not a filter file", path.display())));
+    }
+
+    let word = |i: usize| u64::from_le_bytes(header[8 * i..8 * (i + 1)].try_into().unwrap());
+    let (seed, size, hashes) = (word(1), word(2) as usize, word(3) as usize);
+    if !size.is_power_of_two() || size < 64 || hashes == 0 {
+        return Err(invalid(format!("{} has a bad header: size {}, {} hashes", path.display(), size, hashes)));
+    }
+
+    let len = fs::metadata(path)?.len();
+    if len != FILTER_HEADER_LEN + size as u64 / 8 {
+        return Err(invalid(format!("{} holds {} bytes, expected {} for a filter of {} bits", path.display(),
+            len, FILTER_HEADER_LEN + size as u64 / 8, size)));
+    }
+
+    let file = FilterFile { path: path.to_path_buf(), seed, offset: FILTER_HEADER_LEN };
+    Ok((file, FilterConfig { size, hashes, seed: Some(seed), ..FilterConfig::default() }))
+}
+
+/// writes a phase 2 map as little-endian (u64 SSP, u32 mask) pairs
+pub fn write_t2_map(out: &mut impl Write, map: &T2Map) -> io::Result<()> {
+    write_t2_entries(out, map.iter())
+}
+
+fn write_t2_entries<'a>(out: &mut impl Write, entries: impl Iterator<Item = &'a (u64, u32)>) -> io::Result<()> {
+    for (ssp, mask) in entries {
+        out.write_all(&ssp.to_le_bytes())?;
+        out.write_all(&mask.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+pub fn read_t2_map(input: impl Read) -> io::Result<T2Map> {
+    let mut input = BufReader::new(input);
+    let mut entries = Vec::new();
+    let mut entry = [0u8; T2_ENTRY_LEN as usize];
+
+    loop {
+        match input.read_exact(&mut entry) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e)
+        }
+
+        let ssp = u64::from_le_bytes(entry[..8].try_into().unwrap());
+        let mask = u32::from_le_bytes(entry[8..].try_into().unwrap());
+        entries.push((ssp, mask));
+    }
+
+    Ok(entries.into_iter().collect())
+}
+
+/// writes pseudoprimes one per line, each followed by its factors separated by commas
+pub fn write_results(out: &mut impl Write, results: &[Pseudoprime]) -> io::Result<()> {
+    for result in results.iter() {
+        let factors: Vec<String> = result.factors.iter().map(|factor| factor.to_string()).collect();
+        writeln!(out, "{} {}", result.pseudoprime, factors.join(","))?;
+    }
+
+    Ok(())
+}
+
+pub fn read_results(input: impl Read) -> io::Result<Vec<Pseudoprime>> {
+    let mut results = Vec::new();
+
+    for line in BufReader::new(input).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        results.push(parse_result(&line).ok_or_else(|| invalid(format!("bad result {:?}", line)))?);
+    }
+
+    Ok(results)
+}
+
+/// writes `path` through a temporary file, so it is either all there or not changed at all
+pub(crate) fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    write(&mut out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(&tmp, path)
+}
+
+fn read_manifest(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    parse_key_values(&fs::read_to_string(path)?)
+}
+
+/// parses `key=value` lines, skipping blank lines and `#` comments
+pub(crate) fn parse_key_values(text: &str) -> io::Result<BTreeMap<String, String>> {
+    let mut values = BTreeMap::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid(format!("bad line {:?}", line)))?;
+        values.insert(key.trim().to_string(), value.trim().to_string());
+    }
+
+    Ok(values)
+}
+
+/// identifies a set by its length and an FNV-1a hash of its elements
+fn fingerprint(set: &[u64]) -> String {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in set.iter().flat_map(|elem| elem.to_le_bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+    }
+
+    format!("{}:{:016x}", set.len(), hash)
+}
+
+/// e.g. "0..100,200..300"
+fn format_ranges(ranges: &[Range<u64>]) -> String {
+    let ranges: Vec<String> = ranges.iter().map(|range| format!("{}..{}", range.start, range.end)).collect();
+    ranges.join(",")
+}
+
+fn parse_ranges(ranges: &str) -> Option<Vec<Range<u64>>> {
+    ranges.split(',').filter(|range| !range.trim().is_empty()).map(|range| {
+        let (start, end) = range.trim().split_once("..")?;
+        Some(start.parse().ok()?..end.parse().ok()?)
+    }).collect()
+}
+
+/// parses a line of final_sieve.txt: the pseudoprime, then its factors separated by commas
+fn parse_result(line: &str) -> Option<Pseudoprime> {
+    let (pseudoprime, factors) = line.trim().split_once(' ')?;
+    let factors = factors.split(',').map(|factor| factor.parse::<Integer>().ok()).collect::<Option<Vec<_>>>()?;
+
+    Some(Pseudoprime { pseudoprime: pseudoprime.parse().ok()?, factors })
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bloomfilter::{Builder, InsertMode};
+
+    fn run_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pseudoprimes-checkpoint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn config() -> FilterConfig {
+        FilterConfig { size: 1 << 16, hashes: 2, insert_mode: InsertMode::Atomic, ..FilterConfig::default() }
+    }
+
+    fn outcome<T>(value: T, completed: Vec<Range<u64>>) -> Outcome<T> {
+        Outcome { value, completed, total: 0..100, cancelled: Some(CancelReason::TimeLimit) }
+    }
+
+    #[test]
+    pub fn test_manifest() {
+        let path = run_dir("manifest");
+        let config = FilterConfig { seed: Some(5), ..config() };
+
+        let run = RunDir::open(&path, &[1, 2, 3], &[4, 5], &config).unwrap();
+        assert_eq!(run.filter_seed().unwrap(), 5);
+        assert!(run.filter().unwrap().is_none());
+        assert!(run.t2_map().unwrap().is_none());
+
+        // the seed sticks with the run
+        let run = RunDir::open(&path, &[1, 2, 3], &[4, 5], &FilterConfig { seed: Some(6), ..config }).unwrap();
+        assert_eq!(run.filter_seed().unwrap(), 5);
+
+        let err = RunDir::open(&path, &[1, 2, 4], &[4, 5], &config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("t1"), "{}", err);
+
+        assert!(RunDir::open(&path, &[1, 2, 3], &[4, 5], &FilterConfig { hashes: 3, ..config }).is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_filter() {
+        let path = run_dir("filter");
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+
+        let builder = Builder::new(1 << 16, 2).seed(run.filter_seed().unwrap());
+        let filter = builder.build();
+        for i in 0..100u64 {
+            filter.put(&i);
+        }
+
+        let mut filters = HashMap::new();
+        filters.insert(0, Arc::new(filter));
+        run.save_filter(&outcome(filters, vec![0..10, 20..30])).unwrap();
+
+        let run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        let saved = run.filter().unwrap().unwrap();
+        assert_eq!(saved.completed, vec![0..10, 20..30]);
+        assert_eq!(saved.total, 0..100);
+        assert!(!saved.is_complete());
+
+        let loaded = builder.build();
+        saved.value.load_into(&loaded).unwrap();
+        for i in 0..100u64 {
+            assert!(loaded.maybe_present(&i));
+        }
+
+        // a filter of the wrong size is refused
+        let small = Builder::new(1 << 12, 2).seed(saved.value.seed).build();
+        assert!(saved.value.load_into(&small).is_err());
+
+        // on its own, the file says what filter to load it into
+        let standalone = path.join("filter.bin");
+        write_filter_file(&standalone, &loaded).unwrap();
+        let (file, config) = read_filter_file(&standalone).unwrap();
+        assert_eq!((file.seed, file.offset), (saved.value.seed, FILTER_HEADER_LEN));
+        assert_eq!((config.size, config.hashes, config.seed), (1 << 16, 2, Some(saved.value.seed)));
+
+        let reloaded = Builder::new(config.size, config.hashes).seed(file.seed).build();
+        file.load_into(&reloaded).unwrap();
+        assert!((0..100u64).all(|i| reloaded.maybe_present(&i)));
+        assert!(read_filter_file(&path.join(MANIFEST)).is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_t2_map_and_results() {
+        let path = run_dir("t2_map");
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+
+        // SSPs repeat, with different masks
+        let map: T2Map = (0..1000u64).map(|i| (i / 2 * 7919, i as u32)).collect();
+        let completed = crate::cancel::merge_ranges((0..10).map(|i| i * 10..(i + 1) * 10));
+        run.save_t2_map(&Outcome { value: map.clone(), completed, total: 0..100, cancelled: None }).unwrap();
+
+        let results = vec![
+            Pseudoprime { pseudoprime: Integer::from(7), factors: vec![Integer::from(2), Integer::from(3)] },
+            Pseudoprime { pseudoprime: Integer::from(31), factors: vec![Integer::from(30)] }
+        ];
+        run.save_results(&outcome(results, vec![0..25, 25..50])).unwrap();
+
+        let run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        let saved = run.t2_map().unwrap().unwrap();
+        assert!(saved.is_complete());
+        assert_eq!(saved.value, map);
+        assert_eq!(saved.value.duplicates(), 500);
+
+        let saved = run.results().unwrap().unwrap();
+        assert_eq!(saved.completed, [0..25, 25..50]);
+        assert_eq!(saved.value.len(), 2);
+        assert_eq!(saved.value[0].pseudoprime, 7);
+        assert_eq!(saved.value[0].factors, vec![Integer::from(2), Integer::from(3)]);
+        assert_eq!(saved.value[1].factors, vec![Integer::from(30)]);
+
+        // nothing left behind but the files themselves
+        let mut files: Vec<_> = fs::read_dir(&path).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, vec![RESULTS_FILE, MANIFEST, T2_MAP_FILE]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_append_t2_map() {
+        let path = run_dir("append_t2_map");
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+
+        run.append_t2_map(&outcome(vec![(5, 1), (3, 2)], vec![0..5, 5..10])).unwrap();
+        run.append_t2_map(&outcome(vec![(3, 2), (7, 4)], vec![0..10, 10..20])).unwrap();
+
+        // an append cut short leaves a piece of an entry behind, which doesn't count
+        let mut file = OpenOptions::new().append(true).open(path.join(T2_MAP_FILE)).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        let saved = run.t2_map().unwrap().unwrap();
+        assert_eq!(saved.completed, vec![0..10, 10..20]);
+        assert_eq!(saved.value.iter().copied().collect::<Vec<_>>(), vec![(3, 2), (5, 1), (7, 4)]);
+
+        run.append_t2_map(&outcome(vec![(1, 8)], vec![0..20, 20..30])).unwrap();
+        let saved = run.t2_map().unwrap().unwrap();
+        assert_eq!(saved.value.len(), 4);
+        assert_eq!(fs::metadata(path.join(T2_MAP_FILE)).unwrap().len(), 5 * T2_ENTRY_LEN);
+
+        // saving the whole map starts the file over
+        run.save_t2_map(&saved).unwrap();
+        assert_eq!(run.t2_map().unwrap().unwrap().value, saved.value);
+        assert_eq!(run.manifest["t2_map.entries"], "4");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_sieve_params() {
+        let path = run_dir("sieve_params");
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        let bounds = PseudoprimeBounds { bits: 513..=3072, factors: 3..=u32::MAX };
+
+        assert!(!run.sieve_params("check_prime", Some(&bounds)).unwrap());
+        run.save_t2_map(&outcome(T2Map::default(), vec![0..50, 50..100])).unwrap();
+        run.save_results(&outcome(Vec::new(), vec![0..50, 50..100])).unwrap();
+
+        // the same parameters keep the results
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        assert!(!run.sieve_params("check_prime", Some(&bounds)).unwrap());
+        assert!(run.results().unwrap().is_some());
+
+        // different ones drop them, but not the T2 map, and stick for next time
+        let narrower = PseudoprimeBounds { bits: 1024..=3072, ..bounds.clone() };
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        assert!(run.sieve_params("check_prime", Some(&narrower)).unwrap());
+        assert!(run.results().unwrap().is_none());
+        assert!(run.t2_map().unwrap().is_some());
+
+        let mut run = RunDir::open(&path, &[1], &[2], &config()).unwrap();
+        assert_eq!(run.manifest["final_sieve.bits"], "1024..=3072");
+        assert!(!run.sieve_params("check_prime", Some(&narrower)).unwrap());
+        run.save_results(&outcome(Vec::new(), vec![0..50, 50..100])).unwrap();
+        assert!(run.sieve_params("custom", None).unwrap());
+        assert_eq!(run.manifest["final_sieve.factors"], "any");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_ranges() {
+        assert_eq!(format_ranges(&[0..10, 20..30]), "0..10,20..30");
+        assert_eq!(parse_ranges("0..10,20..30"), Some(vec![0..10, 20..30]));
+        assert_eq!(parse_ranges(""), Some(vec![]));
+        assert_eq!(parse_ranges("0..x"), None);
+    }
+}
@@ -0,0 +1,1176 @@
+// cli.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// The command line. `run` is the whole search (and what runs with no arguments at all);
+// `phase1`, `phase2` and `phase3` run one phase each, handing their output on through
+// files; `gen-r`, `verify`, `estimate` and `find-moduli` are utilities. Nothing in here exits the
+// process: `main` returns the exit code, so that it can be tested.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rug::Integer;
+use rug::integer::IsPrime;
+
+use crate::bloomfilter::{Builder, FilterConfig, InsertMode, MemoryPolicy, PhaseContext, N_TASKS, bloom_t1, bloom_t1_kernel, bloom_t1_partitioned, build_t2, final_sieve};
+use crate::cancel::{CancellationToken, Outcome};
+use crate::checkpoint::{read_filter_file, read_t2_map, write_atomically, write_filter_file, write_results, write_t2_map};
+use crate::gray_prod_iter::{ProductIter, ProductSet};
+use crate::instance::ProblemInstance;
+use crate::magic_numbers::{M, Pseudoprime, PseudoprimeCheck, is_strong_probable_prime};
+use crate::moduli::{ModulusCandidate, ModulusSearch, find_moduli};
+use crate::modulus::{BasicDivisor, Modulus, MODULUS, inverse};
+use crate::numa_threadpool::{PoolConfig, Workers, split_range};
+use crate::progress::ProgressReporter;
+use crate::search::{CHECKPOINT_INTERVAL, MAX_HALF, Phase, PhaseReport, Search, SearchReport, balanced_halves};
+use crate::t2_map::{IndexKind, T2Index, T2Map};
+use crate::topology::Topology;
+
+pub const EXIT_OK: i32 = 0;
+/// something went wrong, or `verify` was given a number that doesn't pass every base
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+/// stopped by a signal or a time limit before finishing
+pub const EXIT_STOPPED: i32 = 3;
+
+/// what one entry of the phase 2 map costs: a 16-byte (SSP, mask) pair, held twice while
+/// the map is sorted
+const MAP_ENTRY_BYTES: f64 = 32.0;
+/// how many subset products `estimate` times
+const SAMPLE_SSPS: u64 = 1 << 22;
+/// the most SSPs in the phase 2 map `estimate` times lookups in
+const SAMPLE_MAP_LEN: u64 = 1 << 22;
+/// how many SSPs phase 3 looks up at once
+const LOOKUP_BATCH: usize = 64;
+/// the largest filter, in bits, `estimate` times inserts into
+const SAMPLE_FILTER_BITS: usize = 1 << 28;
+
+pub const USAGE: &str = "\
+usage: pseudoprimes [COMMAND] [OPTIONS]
+
+commands:
+  run                      the whole search (the default)
+  phase1 --out FILE        build the phase 1 Bloom filter and save it to FILE
+  phase2 --filter FILE --out FILE
+                           collect the T2 subset products that pass a saved filter
+  phase3 --t2map FILE      match T1 subset products against a saved phase 2 map, and
+                           check the matches; --out FILE also saves the pseudoprimes
+  gen-r                    print the candidate set R
+  verify N                 run Miller-Rabin on N for each of the bases; exits 0 if
+                           N passes them all
+  estimate                 estimate the memory, false positives and time the search needs
+  instance                 print the problem instance; --out FILE saves it instead
+  find-moduli              rank smooth moduli M by the candidates they give the bases;
+                           --out FILE saves the best as an instance file
+
+options:
+  --instance FILE          search the problem instance in FILE rather than the paper's
+  --bases B1,B2,...        fool these Miller-Rabin bases instead, with the Legendre
+                           symbols that leave the most candidates
+  --threads N              use at most N worker threads in all
+  --filter-size BITS       Bloom filter size in bits, e.g. 2^39; rounded up to a power of two
+  --hashes K               hash functions in the Bloom filter
+  --insert-mode MODE       atomic, or partitioned[:REGIONS]: the filter split into REGIONS
+                           (by default one per thread), each set without atomics by
+                           one thread at a time
+  --numa-policy POLICY     replicate, interleave, or bind:NODE
+  --tasks N                split each phase into N tasks
+  --format FORMAT          text, or json (one object, on the last line of output;
+                           big integers are strings)
+  --min-bits B             only report pseudoprimes of at least B bits (default: the
+                           instance's, 513 for the paper)
+  --max-bits B             only report pseudoprimes of at most B bits
+  --min-factors K          only report pseudoprimes that are 2 times at least K of the
+                           candidates, plus 1
+  --max-factors K          ... and at most K of them
+  --t2-index KIND          how phase 3 looks T1 subset products up in the phase 2 map:
+                           hashed (the default; 32 to 64 bytes per SSP on top of
+                           the map), eytzinger (12 bytes per SSP) or sorted (none)
+  --split ORDER            how to split the candidates into T1 and T2: sorted (the smaller
+                           half in T1, the default) or balanced (halves of about the same
+                           product); phase1 to phase3 need the same one
+  --run-dir DIR            save progress to DIR, and resume from what is there (run only)
+  --checkpoint-interval SECS
+                           with --run-dir, also save a running phase's progress every
+                           SECS seconds (default 1800)
+  --time-limit [PHASE=]SECS
+                           stop PHASE (bloom_t1, t2_map or final_sieve; every phase if
+                           left out) after SECS seconds; may be repeated
+  --modulus-bits B         find-moduli: only moduli below 2^B (default 64)
+  --target N               find-moduli: how many candidates the search will use (default 64)
+  --max-prime P            find-moduli: the largest prime factor of M (default 61)
+  --max-exponent E         find-moduli: the largest power of a prime in M (default 3)
+  --shortlist N            find-moduli: how many moduli to build R for (default 16)
+  --help                   print this message
+
+exit status: 0 when done, 1 on errors, 2 for bad usage, 3 if stopped early";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Phase1,
+    Phase2,
+    Phase3,
+    GenR,
+    Verify(Integer),
+    Estimate,
+    Instance,
+    FindModuli,
+    Help
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Run => "run",
+            Command::Phase1 => "phase1",
+            Command::Phase2 => "phase2",
+            Command::Phase3 => "phase3",
+            Command::GenR => "gen-r",
+            Command::Verify(_) => "verify",
+            Command::Estimate => "estimate",
+            Command::Instance => "instance",
+            Command::FindModuli => "find-moduli",
+            Command::Help => "help"
+        }
+    }
+
+    /// the flags that mean something to this command
+    fn flags(&self) -> &'static [&'static str] {
+        match self {
+            Command::Run => &["instance", "bases", "threads", "filter-size", "hashes", "insert-mode", "numa-policy",
+                "tasks", "format", "min-bits", "max-bits", "min-factors", "max-factors", "split", "t2-index", "run-dir",
+                "checkpoint-interval", "time-limit"],
+            Command::Phase1 => &["instance", "bases", "threads", "filter-size", "hashes", "insert-mode", "numa-policy",
+                "tasks", "split", "time-limit", "out"],
+            // the filter's size and hashes come from its file
+            Command::Phase2 => &["instance", "bases", "threads", "numa-policy", "tasks", "split", "time-limit", "filter", "out"],
+            Command::Phase3 => &["instance", "bases", "threads", "tasks", "format", "min-bits", "max-bits", "min-factors",
+                "max-factors", "split", "t2-index", "time-limit", "t2map", "out"],
+            Command::GenR | Command::Verify(_) => &["instance", "bases", "format"],
+            Command::Estimate => &["instance", "bases", "threads", "filter-size", "hashes", "insert-mode", "numa-policy", "format"],
+            Command::Instance => &["instance", "bases", "min-bits", "max-bits", "min-factors", "max-factors", "out"],
+            // the bounds come from the instance
+            Command::FindModuli => &["instance", "bases", "min-bits", "max-bits", "min-factors", "max-factors", "format",
+                "out", "modulus-bits", "target", "max-prime", "max-exponent", "shortlist"],
+            Command::Help => &[]
+        }
+    }
+
+    /// the flags this command can't do without
+    fn required(&self) -> &'static [&'static str] {
+        match self {
+            Command::Phase1 => &["out"],
+            Command::Phase2 => &["filter", "out"],
+            Command::Phase3 => &["t2map"],
+            _ => &[]
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json
+}
+
+/// Everything the flags can set. Each command only looks at the parts it uses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// the paper's if None
+    pub instance: Option<PathBuf>,
+    /// the bases to derive the instance's conditions for, if not its own
+    pub bases: Option<Vec<u64>>,
+    pub pool: PoolConfig,
+    pub filter: FilterConfig,
+    pub tasks: u64,
+    pub format: Format,
+    /// the fewest bits a pseudoprime may have, if not the instance's
+    pub min_bits: Option<u32>,
+    /// ... and the others of its bounds
+    pub max_bits: Option<u32>,
+    pub min_factors: Option<u32>,
+    pub max_factors: Option<u32>,
+    /// whether T1 and T2 are `balanced_halves` rather than the smaller and larger candidates
+    pub balanced: bool,
+    pub t2_index: IndexKind,
+    pub run_dir: Option<PathBuf>,
+    pub checkpoint_interval: Duration,
+    /// in the order given; a later limit for the same phase wins
+    pub time_limits: Vec<(Phase, Duration)>,
+    pub out: Option<PathBuf>,
+    pub filter_file: Option<PathBuf>,
+    pub t2_map_file: Option<PathBuf>,
+    /// what `find-moduli` looks through; the bases and bounds come from the instance
+    pub moduli: ModulusSearch
+}
+
+impl Default for Options {
+    /// the search from the paper
+    fn default() -> Self {
+        Options {
+            instance: None,
+            bases: None,
+            pool: PoolConfig::default(),
+            filter: FilterConfig::default(),
+            tasks: N_TASKS,
+            format: Format::Text,
+            min_bits: None,
+            max_bits: None,
+            min_factors: None,
+            max_factors: None,
+            balanced: false,
+            t2_index: IndexKind::default(),
+            run_dir: None,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            time_limits: Vec::new(),
+            out: None,
+            filter_file: None,
+            t2_map_file: None,
+            moduli: ModulusSearch::new(&[])
+        }
+    }
+}
+
+impl Options {
+    /// sets the option for the flag `--name`
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "instance" => self.instance = Some(PathBuf::from(value)),
+            "bases" => {
+                let bases = value.split(',').map(|base| number(name, base)).collect::<Result<Vec<u64>, _>>()?;
+                if bases.is_empty() {
+                    return Err("--bases needs at least one base".to_string());
+                }
+                self.bases = Some(bases);
+            },
+            "threads" => self.pool.threads = Some(positive(name, value)?),
+            "filter-size" => {
+                let size = match value.strip_prefix("2^") {
+                    Some(exponent) => 1usize.checked_shl(number(name, exponent)?).filter(|size| *size > 0),
+                    None => Some(number(name, value)?)
+                };
+                self.filter.size = size.filter(|size| *size >= 64)
+                    .ok_or_else(|| format!("--filter-size {} is out of range, it needs at least 64 bits", value))?;
+            },
+            "hashes" => self.filter.hashes = positive(name, value)?,
+            "insert-mode" => {
+                self.filter.insert_mode = match value.split_once(':') {
+                    None if value == "atomic" => InsertMode::Atomic,
+                    None if value == "partitioned" => InsertMode::Partitioned { regions: 0 },
+                    Some(("partitioned", regions)) => InsertMode::Partitioned { regions: positive(name, regions)? },
+                    _ => return Err(format!("--insert-mode {:?} isn't atomic or partitioned[:REGIONS]", value))
+                };
+            },
+            "numa-policy" => {
+                self.filter.memory_policy = match value.split_once(':') {
+                    None if value == "replicate" => MemoryPolicy::Replicate,
+                    None if value == "interleave" => MemoryPolicy::Interleave,
+                    Some(("bind", node)) => MemoryPolicy::Bind(number(name, node)?),
+                    _ => return Err(format!("--numa-policy {:?} isn't replicate, interleave or bind:NODE", value))
+                };
+            },
+            "tasks" => self.tasks = positive(name, value)?,
+            "format" => {
+                self.format = match value {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    _ => return Err(format!("--format {:?} isn't text or json", value))
+                };
+            },
+            "min-bits" => {
+                let bits: u32 = number(name, value)?;
+                if bits < 2 {
+                    return Err(format!("--min-bits {} is too small, it needs to be at least 2", value));
+                }
+                self.min_bits = Some(bits);
+            },
+            "max-bits" => self.max_bits = Some(number(name, value)?),
+            "min-factors" => self.min_factors = Some(number(name, value)?),
+            "max-factors" => self.max_factors = Some(number(name, value)?),
+            "split" => {
+                self.balanced = match value {
+                    "sorted" => false,
+                    "balanced" => true,
+                    _ => return Err(format!("--split {:?} isn't sorted or balanced", value))
+                };
+            },
+            "t2-index" => {
+                self.t2_index = *IndexKind::ALL.iter().find(|kind| kind.name() == value)
+                    .ok_or_else(|| format!("--t2-index {:?} isn't hashed, eytzinger or sorted", value))?;
+            },
+            "run-dir" => self.run_dir = Some(PathBuf::from(value)),
+            "checkpoint-interval" => {
+                let secs: f64 = number(name, value)?;
+                if !(secs > 0.0 && secs.is_finite()) {
+                    return Err(format!("--checkpoint-interval {:?} isn't a positive number of seconds", value));
+                }
+                self.checkpoint_interval = Duration::from_secs_f64(secs);
+            },
+            "time-limit" => {
+                let (phases, secs) = match value.split_once('=') {
+                    Some((phase, secs)) => {
+                        let phase = Phase::ALL.iter().find(|known| known.name() == phase)
+                            .ok_or_else(|| format!("--time-limit: no phase called {:?}", phase))?;
+                        (vec![*phase], secs)
+                    },
+                    None => (Phase::ALL.to_vec(), value)
+                };
+
+                let secs: f64 = number(name, secs)?;
+                if !(secs >= 0.0 && secs.is_finite()) {
+                    return Err(format!("--time-limit {:?} isn't a number of seconds", value));
+                }
+                self.time_limits.extend(phases.into_iter().map(|phase| (phase, Duration::from_secs_f64(secs))));
+            },
+            "out" => self.out = Some(PathBuf::from(value)),
+            "filter" => self.filter_file = Some(PathBuf::from(value)),
+            "t2map" => self.t2_map_file = Some(PathBuf::from(value)),
+            "modulus-bits" => {
+                self.moduli.max_modulus = match number(name, value)? {
+                    64 => u64::MAX,
+                    bits @ 2..=63 => (1u64 << bits) - 1,
+                    _ => return Err(format!("--modulus-bits {} is out of range, it needs to be 2 to 64", value))
+                };
+            },
+            "target" => self.moduli.target = positive(name, value)?,
+            "max-prime" => self.moduli.max_prime = positive(name, value)?,
+            "max-exponent" => self.moduli.max_exponent = positive(name, value)?,
+            "shortlist" => self.moduli.shortlist = positive(name, value)?,
+            _ => return Err(format!("unknown option --{}", name))
+        }
+
+        Ok(())
+    }
+
+    pub fn time_limit(&self, phase: Phase) -> Option<Duration> {
+        self.time_limits.iter().rev().find(|(limit_phase, _)| *limit_phase == phase).map(|(_, limit)| *limit)
+    }
+
+    /// the instance to search, with `--bases` and the bounds on pseudoprimes applied
+    pub fn instance(&self) -> Result<ProblemInstance, String> {
+        let mut instance = match &self.instance {
+            Some(path) => ProblemInstance::load(path).map_err(|e| e.to_string())?,
+            None => ProblemInstance::paper()
+        };
+
+        if let Some(bases) = &self.bases {
+            instance = instance.with_bases(bases).map_err(|e| format!("--bases: {}", e))?;
+        }
+        if let Some(min_bits) = self.min_bits {
+            instance.min_bits = min_bits;
+        }
+        instance.max_bits = self.max_bits.or(instance.max_bits);
+        instance.min_factors = self.min_factors.or(instance.min_factors);
+        instance.max_factors = self.max_factors.or(instance.max_factors);
+
+        let bounds = instance.bounds();
+        if bounds.bits.is_empty() || bounds.factors.is_empty() {
+            return Err(format!("no pseudoprime can have {:?} bits and {:?} factors", bounds.bits, bounds.factors));
+        }
+        Ok(instance)
+    }
+
+    fn context<'a, N: Modulus>(&self, workers: &'a Workers, modulus: N) -> PhaseContext<'a, N> {
+        PhaseContext { modulus, n_tasks: self.tasks, workers, checkpoint: None }
+    }
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("--{} {:?} isn't a valid number", name, value))
+}
+
+fn positive<T: FromStr + Default + PartialEq>(name: &str, value: &str) -> Result<T, String> {
+    let number = number(name, value)?;
+    if number == T::default() {
+        return Err(format!("--{} has to be at least 1", name));
+    }
+
+    Ok(number)
+}
+
+/// Parses the arguments after the program name into a command and its options. Flags can
+/// be given as `--name value` or `--name=value`, before or after the command.
+pub fn parse(args: &[String]) -> Result<(Command, Options), String> {
+    let mut options = Options::default();
+    let mut words = Vec::new();
+    let mut flags = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Ok((Command::Help, options));
+        }
+
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                words.push(arg.as_str());
+                continue;
+            }
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value),
+            None => (flag, args.next().ok_or_else(|| format!("--{} needs a value", flag))?.as_str())
+        };
+        options.set(name, value)?;
+        flags.push(name);
+    }
+
+    let (command, rest) = match words.split_first() {
+        None => (Command::Run, &words[..]),
+        Some((&"verify", rest)) => match rest.split_first() {
+            Some((n, rest)) => {
+                let n = n.parse::<Integer>().map_err(|_| format!("verify: {:?} isn't an integer", n))?;
+                (Command::Verify(n), rest)
+            },
+            None => return Err("verify needs the number to check".to_string())
+        },
+        Some((name, rest)) => {
+            let command = match *name {
+                "run" => Command::Run,
+                "phase1" => Command::Phase1,
+                "phase2" => Command::Phase2,
+                "phase3" => Command::Phase3,
+                "gen-r" => Command::GenR,
+                "estimate" => Command::Estimate,
+                "instance" => Command::Instance,
+                "find-moduli" => Command::FindModuli,
+                "help" => Command::Help,
+                _ => return Err(format!("unknown command {:?}", name))
+            };
+            (command, rest)
+        }
+    };
+
+    if let Some(extra) = rest.first() {
+        return Err(format!("{} doesn't take {:?}", command.name(), extra));
+    }
+    if let Some(flag) = flags.iter().find(|flag| !command.flags().contains(flag)) {
+        return Err(format!("--{} doesn't apply to {}", flag, command.name()));
+    }
+    if let Some(flag) = command.required().iter().find(|flag| !flags.contains(flag)) {
+        return Err(format!("{} needs --{}", command.name(), flag));
+    }
+
+    Ok((command, options))
+}
+
+/// runs the command line `args` (without the program name), returning the exit status
+pub fn main(args: &[String]) -> i32 {
+    let (command, options) = match parse(args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    match execute(&command, &options) {
+        Ok(status) => status,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Binds `$modulus` to the tuned `MODULUS` if `$instance` is mod the paper's M, or to a
+/// `BasicDivisor` for its own modulus if not, and evaluates `$body` with it.
+macro_rules! with_modulus {
+    ($instance:expr, $modulus:ident => $body:expr) => {
+        if $instance.modulus == M {
+            let $modulus = MODULUS;
+            $body
+        } else {
+            let $modulus = BasicDivisor::new($instance.modulus);
+            $body
+        }
+    };
+}
+
+pub fn execute(command: &Command, options: &Options) -> Result<i32, String> {
+    if *command == Command::Help {
+        println!("{}", USAGE);
+        return Ok(EXIT_OK);
+    }
+
+    let instance = options.instance()?;
+    match command {
+        Command::Run => with_modulus!(instance, modulus => run(options, &instance, modulus)),
+        Command::Phase1 => with_modulus!(instance, modulus => phase1(options, &instance, modulus)),
+        Command::Phase2 => with_modulus!(instance, modulus => phase2(options, &instance, modulus)),
+        Command::Phase3 => with_modulus!(instance, modulus => phase3(options, &instance, modulus)),
+        Command::GenR => {
+            gen_r(&instance, options.format);
+            Ok(EXIT_OK)
+        },
+        Command::Verify(n) => Ok(verify(&instance, n, options.format)),
+        Command::Estimate => {
+            with_modulus!(instance, modulus => estimate(options, &instance, modulus))?;
+            Ok(EXIT_OK)
+        },
+        Command::Instance => {
+            match &options.out {
+                Some(out) => instance.save(out).map_err(|e| file_error(out, e))?,
+                None => print!("{}", instance)
+            }
+            Ok(EXIT_OK)
+        },
+        Command::FindModuli => {
+            find(options, &instance)?;
+            Ok(EXIT_OK)
+        },
+        Command::Help => unreachable!()
+    }
+}
+
+/// the instance's candidates split into the halves T1 and T2, as `Search` splits them
+fn halves<N: Modulus>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<(Vec<u64>, Vec<u64>), String> {
+    let mut t1 = instance.candidates();
+    if options.balanced {
+        t1 = balanced_halves(&t1, t1.len() / 2);
+    }
+    let t2 = t1.split_off(t1.len() / 2);
+
+    if t1.is_empty() || t2.len() > MAX_HALF {
+        return Err(format!("the instance has {} candidates, the search needs 2 to {}", t1.len() + t2.len(), 2 * MAX_HALF));
+    }
+    if let Some(r) = t1.iter().chain(t2.iter()).find(|r| modulus.inverse(**r).is_none()) {
+        return Err(format!("candidate {} has no inverse mod {}", r, instance.modulus));
+    }
+
+    Ok((t1, t2))
+}
+
+fn run<N: Modulus + Send + Sync + 'static>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<i32, String> {
+    // SIGINT/SIGTERM stop the current phase; a second signal kills the process
+    let cancel = CancellationToken::on_signals();
+
+    let mut search = Search::new().candidates(&instance.candidates());
+    if options.balanced {
+        search = search.balanced();
+    }
+    let mut search = search
+        .modulus(modulus)
+        .filter(options.filter)
+        .t2_index(options.t2_index)
+        .tasks(options.tasks)
+        .workers(Workers::with_config(&options.pool))
+        .cancel(&cancel)
+        .bounds(instance.bounds());
+
+    if options.format == Format::Text {
+        search = search.sink(|result| println!("Found passing prime {}, vector {:?}", result.pseudoprime, result.factors));
+    }
+    for (phase, limit) in options.time_limits.iter() {
+        search = search.time_limit(*phase, *limit);
+    }
+    // with a run directory, every phase saves its progress, and we pick up from there
+    if let Some(path) = &options.run_dir {
+        search = search.run_dir(path).checkpoint_interval(options.checkpoint_interval);
+    }
+
+    let report = search.run().map_err(|e| format!("search failed: {}", e))?;
+
+    match options.format {
+        Format::Text => {
+            for phase in report.phases.iter().filter(|phase| !phase.is_complete()) {
+                println!("[{}] completed ranges: {:?}", phase, phase.completed);
+            }
+
+            println!("Total time: {} seconds, primes found: {}", report.elapsed.as_secs(), report.results.len());
+        },
+        Format::Json => println!("{}", report_json(&report))
+    }
+
+    Ok(if report.is_complete() { EXIT_OK } else { EXIT_STOPPED })
+}
+
+fn phase1<N: Modulus + Send + Sync + 'static>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<i32, String> {
+    let out = options.out.as_ref().unwrap();
+    let (t1, _) = halves(options, instance, modulus)?;
+    let cancel = CancellationToken::on_signals().child(options.time_limit(Phase::BloomT1));
+    let workers = Workers::with_config(&options.pool);
+    let start = Instant::now();
+
+    let filters = bloom_t1(&inverse(&t1, modulus), &options.filter, &options.context(&workers, modulus), &cancel, None);
+    if !filters.is_complete() {
+        return Ok(stopped(Phase::BloomT1, &filters));
+    }
+
+    let (_, filter) = filters.value.iter().min_by_key(|(node, _)| **node).unwrap();
+    write_filter_file(out, filter).map_err(|e| file_error(out, e))?;
+    println!("Wrote the filter to {} in {}s", out.display(), start.elapsed().as_secs());
+
+    Ok(EXIT_OK)
+}
+
+fn phase2<N: Modulus + Send + Sync + 'static>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<i32, String> {
+    let (path, out) = (options.filter_file.as_ref().unwrap(), options.out.as_ref().unwrap());
+    let (file, saved) = read_filter_file(path).map_err(|e| file_error(path, e))?;
+    let config = FilterConfig { memory_policy: options.filter.memory_policy, ..saved };
+    let (t1, t2) = halves(options, instance, modulus)?;
+
+    let signals = CancellationToken::on_signals();
+    let workers = Workers::with_config(&options.pool);
+    let context = options.context(&workers, modulus);
+    let start = Instant::now();
+
+    // loading is phase 1 resumed with nothing left to do
+    let all = 0..1u64 << t1.len();
+    let loaded = Outcome { value: file, completed: vec![all.clone()], total: all, cancelled: None };
+    let filters = bloom_t1(&inverse(&t1, modulus), &config, &context, &signals, Some(loaded));
+
+    let t2_map = build_t2(filters.value, &t2, &context, &signals.child(options.time_limit(Phase::T2Map)), None);
+    if !t2_map.is_complete() {
+        return Ok(stopped(Phase::T2Map, &t2_map));
+    }
+
+    write_atomically(out, |writer| write_t2_map(writer, &t2_map.value)).map_err(|e| file_error(out, e))?;
+    println!("Wrote {} T2 matches to {} in {}s", t2_map.value.len(), out.display(), start.elapsed().as_secs());
+
+    Ok(EXIT_OK)
+}
+
+fn phase3<N: Modulus + Send + Sync + 'static>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<i32, String> {
+    let path = options.t2_map_file.as_ref().unwrap();
+    let (t1, t2) = halves(options, instance, modulus)?;
+    let t2_map = File::open(path).and_then(read_t2_map).map_err(|e| file_error(path, e))?;
+    println!("T2 matches: {}", t2_map.len());
+
+    let cancel = CancellationToken::on_signals().child(options.time_limit(Phase::FinalSieve));
+    let workers = Workers::with_config(&options.pool);
+    let check = PseudoprimeCheck::new(instance.bounds(), &t1, &t2);
+
+    let t2_index = T2Index::new(&t2_map, options.t2_index);
+    let results = final_sieve(&inverse(&t1, modulus), &t2_index, Some(check.size()), &|t1_mask, t2_mask| check.check(t1_mask, t2_mask),
+        &options.context(&workers, modulus), &cancel, None);
+
+    match options.format {
+        Format::Text => {
+            for result in results.value.iter() {
+                println!("Found passing prime {}, vector {:?}", result.pseudoprime, result.factors);
+            }
+        },
+        Format::Json => println!("{}", json_object(&[
+            ("complete", results.is_complete().to_string()),
+            ("results", results_json(&results.value))
+        ]))
+    }
+
+    // what was found is right, but a partial list isn't worth saving
+    if !results.is_complete() {
+        return Ok(stopped(Phase::FinalSieve, &results));
+    }
+
+    if let Some(out) = &options.out {
+        write_atomically(out, |writer| write_results(writer, &results.value)).map_err(|e| file_error(out, e))?;
+        println!("Wrote {} pseudoprimes to {}", results.value.len(), out.display());
+    }
+
+    Ok(EXIT_OK)
+}
+
+/// a standalone phase that stopped early writes nothing, since the next one needs all of it
+fn stopped<T>(phase: Phase, outcome: &Outcome<T>) -> i32 {
+    println!("[{}] stopped, nothing written: {}", phase, outcome);
+    EXIT_STOPPED
+}
+
+fn file_error(path: &Path, e: std::io::Error) -> String {
+    format!("{}: {}", path.display(), e)
+}
+
+fn gen_r(instance: &ProblemInstance, format: Format) {
+    let candidates = instance.candidates();
+
+    match format {
+        Format::Text => {
+            for r in candidates.iter() {
+                println!("{}", r);
+            }
+        },
+        Format::Json => println!("{}", json_object(&[
+            ("r", json_array(candidates.iter().map(|r| json_string(&r.to_string()))))
+        ]))
+    }
+}
+
+/// Miller-Rabin on `n` for each of the instance's bases; EXIT_OK if it passes every one
+fn verify(instance: &ProblemInstance, n: &Integer, format: Format) -> i32 {
+    let rounds: Vec<(u64, bool)> = instance.bases().into_iter().map(|base| (base, is_strong_probable_prime(n, base))).collect();
+    let passes_all = rounds.iter().all(|(_, passes)| *passes);
+    let primality = match n.is_probably_prime(30) {
+        IsPrime::No => "composite",
+        IsPrime::Probably => "probably prime",
+        IsPrime::Yes => "prime"
+    };
+
+    match format {
+        Format::Text => {
+            println!("{}", n);
+            for (base, passes) in rounds.iter() {
+                println!("base {}: {}", base, if *passes { "strong probable prime" } else { "composite" });
+            }
+            println!("passes all {} bases: {}", rounds.len(), if passes_all { "yes" } else { "no" });
+            println!("is_probably_prime: {}", primality);
+        },
+        Format::Json => println!("{}", json_object(&[
+            ("n", json_string(&n.to_string())),
+            ("bases", json_array(rounds.iter().map(|(base, passes)| {
+                json_object(&[("base", base.to_string()), ("passes", passes.to_string())])
+            }))),
+            ("passes_all", passes_all.to_string()),
+            ("is_probably_prime", json_string(primality))
+        ]))
+    }
+
+    if passes_all { EXIT_OK } else { EXIT_FAILURE }
+}
+
+/// ranks moduli for the instance's bases, with its bounds
+fn find(options: &Options, instance: &ProblemInstance) -> Result<(), String> {
+    let search = ModulusSearch {
+        bases: instance.bases(),
+        min_r: instance.min_r,
+        max_r: instance.max_r,
+        min_bits: instance.min_bits,
+        ..options.moduli.clone()
+    };
+    let results = find_moduli(&search).map_err(|e| e.to_string())?;
+    let best = results.first().ok_or_else(|| "no modulus gives the bases any candidates".to_string())?;
+    // before the results, which json wants last
+    if let Some(out) = &options.out {
+        let found = ProblemInstance {
+            max_bits: instance.max_bits,
+            min_factors: instance.min_factors,
+            max_factors: instance.max_factors,
+            ..best.instance.clone()
+        };
+        found.save(out).map_err(|e| file_error(out, e))?;
+        println!("Wrote the instance for M = {} to {}", found.modulus, out.display());
+    }
+
+    match options.format {
+        Format::Text => {
+            println!("{:>20}  {:>5}  {:>8}  {:>9}  factors", "M", "|R|", "estimate", "solutions");
+            for result in results.iter() {
+                println!("{:>20}  {:>5}  {:>8.1}  {:>9.3e}  {}", result.instance.modulus, result.candidates(),
+                    result.estimated_candidates, result.expected_solutions, result.instance.factorization());
+            }
+        },
+        Format::Json => println!("{}", json_object(&[
+            ("moduli", json_array(results.iter().map(modulus_json)))
+        ]))
+    }
+
+    Ok(())
+}
+
+fn modulus_json(result: &ModulusCandidate) -> String {
+    json_object(&[
+        ("modulus", json_string(&result.instance.modulus.to_string())),
+        ("factors", json_array(result.instance.factors.iter().map(|(prime, exponent)| format!("[{},{}]", prime, exponent)))),
+        ("conditions", json_array(result.instance.conditions.iter().map(|(base, symbol)| format!("[{},{}]", base, symbol)))),
+        ("candidates", result.candidates().to_string()),
+        ("estimated_candidates", result.estimated_candidates.to_string()),
+        ("expected_solutions", result.expected_solutions.to_string())
+    ])
+}
+
+/// What a search should cost with a given filter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// the size of one copy of the filter
+    pub filter_bytes: f64,
+    /// one per node with `MemoryPolicy::Replicate`, otherwise one
+    pub filter_copies: usize,
+    /// the chance a T2 subset product passes the filter without being in T1
+    pub false_positive_rate: f64,
+    /// how many T2 subset products are expected to pass the filter
+    pub t2_matches: f64,
+    pub t2_map_bytes: f64,
+    pub threads: usize,
+    /// subset products each thread computes per second, measured
+    pub ssps_per_sec: f64,
+    /// a lower bound on each phase's time: only the subset products, on every thread
+    pub phase_secs: [f64; 3]
+}
+
+impl Estimate {
+    /// the estimate for halves of `t1_len` and `t2_len` candidates, mod `modulus`
+    pub fn new(config: &FilterConfig, t1_len: usize, t2_len: usize, modulus: u64, nodes: usize, threads: usize, ssps_per_sec: f64) -> Self {
+        let bits = config.size.next_power_of_two() as f64;
+        let hashes = config.hashes as f64;
+        let (t1_ssps, t2_ssps) = ((t1_len as f64).exp2(), (t2_len as f64).exp2());
+
+        // (1 - e^(-kn/m))^k, for n insertions into m bits with k hashes
+        let false_positive_rate = (1.0 - (-hashes * t1_ssps / bits).exp()).powf(hashes);
+        // the false positives, and the real matches (a pair's products agree mod M by chance)
+        let t2_matches = t2_ssps * false_positive_rate + t1_ssps * t2_ssps / modulus as f64;
+
+        let rate = ssps_per_sec * threads as f64;
+        Estimate {
+            filter_bytes: bits / 8.0,
+            filter_copies: if config.memory_policy == MemoryPolicy::Replicate { nodes } else { 1 },
+            false_positive_rate,
+            t2_matches,
+            t2_map_bytes: t2_matches * MAP_ENTRY_BYTES,
+            threads,
+            ssps_per_sec,
+            phase_secs: [t1_ssps / rate, t2_ssps / rate, t1_ssps / rate]
+        }
+    }
+}
+
+/// how many subset products of `t1` one thread computes per second
+fn sample_rate<N: Modulus + 'static>(t1: &[u64], modulus: N) -> f64 {
+    let product_set = ProductSet::new(t1, modulus);
+    let start = Instant::now();
+    let sum = ProductIter::new(&product_set, 0, SAMPLE_SSPS).fold(0u64, |sum, (_, ssp)| sum ^ ssp);
+    std::hint::black_box(sum);
+
+    SAMPLE_SSPS as f64 / start.elapsed().as_secs_f64()
+}
+
+/// How many lookups one thread makes per second with each kind of T2 index, on a map of
+/// `map_len` (at most `SAMPLE_MAP_LEN`) T2 subset products, looking up T1 subset products in
+/// batches as phase 3 does.
+fn sample_lookup_rates<N: Modulus + 'static>(t1_inverse: &[u64], t2: &[u64], map_len: u64, modulus: N) -> Vec<(IndexKind, f64)> {
+    let map_len = map_len.clamp(1, SAMPLE_MAP_LEN).min(1 << t2.len());
+    let map: T2Map = ProductIter::new(&ProductSet::new(t2, modulus), 0, map_len).map(|(mask, ssp)| (ssp, mask as u32)).collect();
+    let ssps: Vec<u64> = ProductIter::new(&ProductSet::new(t1_inverse, modulus), 0, SAMPLE_SSPS).map(|(_, ssp)| ssp).collect();
+
+    IndexKind::ALL.iter().map(|kind| {
+        let index = T2Index::new(&map, *kind);
+        let mut matches = Vec::with_capacity(LOOKUP_BATCH);
+        let start = Instant::now();
+        let found: usize = ssps.chunks(LOOKUP_BATCH).map(|batch| {
+            index.get_batch(batch, &mut matches);
+            matches.iter().map(|found| found.len()).sum::<usize>()
+        }).sum();
+        std::hint::black_box(found);
+
+        (*kind, ssps.len() as f64 / start.elapsed().as_secs_f64())
+    }).collect()
+}
+
+/// How many subset products of `t1` per second all of `workers` insert into a filter like
+/// `config`'s (but at most `SAMPLE_FILTER_BITS`), with each insert mode
+fn sample_insert_rates<N: Modulus + Send + Sync + 'static>(t1: &[u64], config: &FilterConfig, workers: &Workers, modulus: N) -> Vec<(InsertMode, f64)> {
+    let threads = workers.threads();
+    let ssps = (SAMPLE_SSPS * threads as u64).min(1 << t1.len());
+    let ranges = split_range(0..ssps, 4 * threads as u64);
+    let product_set = ProductSet::new(t1, modulus);
+    let regions = match config.insert_mode {
+        InsertMode::Partitioned { regions } if regions > 0 => regions,
+        _ => threads
+    };
+
+    [InsertMode::Atomic, InsertMode::Partitioned { regions }].iter().map(|mode| {
+        let builder = Builder::new(config.size.min(SAMPLE_FILTER_BITS), config.hashes).insert_mode(*mode);
+        let filter = Arc::new(builder.build());
+        let progress = ProgressReporter::new("estimate", ssps as usize);
+
+        let start = Instant::now();
+        let pool = workers.install(|_| filter.clone());
+        match mode {
+            InsertMode::Atomic => pool.scope(|scope| {
+                scope.map_ranges(ranges.iter().cloned(), |filter, range| {
+                    bloom_t1_kernel(&product_set, range.start, range.end, filter, None, &progress)
+                })
+            }),
+            InsertMode::Partitioned { .. } => bloom_t1_partitioned(&pool, &product_set, &ranges,
+                builder.regions(), config.hashes, &progress, |_| {})
+        }.unwrap_or_else(|panic| panic!("[estimate] {}", panic));
+        pool.join().unwrap_or_else(|panic| panic!("[estimate] {}", panic));
+
+        (*mode, ssps as f64 / start.elapsed().as_secs_f64())
+    }).collect()
+}
+
+fn estimate<N: Modulus + Send + Sync + 'static>(options: &Options, instance: &ProblemInstance, modulus: N) -> Result<(), String> {
+    let (t1, t2) = halves(options, instance, modulus)?;
+    let topology = options.pool.select_cpus(&Topology::discover());
+    let nodes = topology.nodes.len().max(1);
+    let t1_inverse = inverse(&t1, modulus);
+    let rate = sample_rate(&t1_inverse, modulus);
+    let estimate = Estimate::new(&options.filter, t1.len(), t2.len(), instance.modulus, nodes, topology.cpu_count().max(1), rate);
+    // nearly every match is a distinct SSP
+    let index_bytes = options.t2_index.bytes(estimate.t2_matches as usize);
+    let lookup_rates = sample_lookup_rates(&t1_inverse, &t2, estimate.t2_matches as u64, modulus);
+    let insert_rates = sample_insert_rates(&t1_inverse, &options.filter, &Workers::with_topology(&topology), modulus);
+    let insert_mode = |mode: &InsertMode| match mode {
+        InsertMode::Atomic => "atomic",
+        InsertMode::Partitioned { .. } => "partitioned"
+    };
+
+    match options.format {
+        Format::Text => {
+            println!("filter: {} x {} = {}, {} hashes", estimate.filter_copies, bytes(estimate.filter_bytes),
+                bytes(estimate.filter_bytes * estimate.filter_copies as f64), options.filter.hashes);
+            println!("false positive rate: {:.3e}", estimate.false_positive_rate);
+            println!("expected T2 matches: {:.0}, map: {}, {} index: {}", estimate.t2_matches, bytes(estimate.t2_map_bytes),
+                options.t2_index.name(), bytes(index_bytes as f64));
+            println!("subset products: {:.3e}/s per thread, {} threads", estimate.ssps_per_sec, estimate.threads);
+            let rates: Vec<String> = lookup_rates.iter().map(|(kind, rate)| format!("{} {:.3e}/s", kind.name(), rate)).collect();
+            println!("T2 map lookups per thread (--t2-index): {}", rates.join(", "));
+            let rates: Vec<String> = insert_rates.iter().map(|(mode, rate)| format!("{} {:.3e}/s", insert_mode(mode), rate)).collect();
+            println!("filter inserts on all threads (--insert-mode): {}", rates.join(", "));
+            for (phase, secs) in Phase::ALL.iter().zip(estimate.phase_secs.iter()) {
+                println!("{}: at least {:.0}s", phase, secs);
+            }
+            println!("(the phases are limited by memory, so expect them to take longer)");
+        },
+        Format::Json => println!("{}", json_object(&[
+            ("filter_bytes", estimate.filter_bytes.to_string()),
+            ("filter_copies", estimate.filter_copies.to_string()),
+            ("false_positive_rate", estimate.false_positive_rate.to_string()),
+            ("t2_matches", estimate.t2_matches.to_string()),
+            ("t2_map_bytes", estimate.t2_map_bytes.to_string()),
+            ("t2_index_bytes", index_bytes.to_string()),
+            ("threads", estimate.threads.to_string()),
+            ("ssps_per_sec", estimate.ssps_per_sec.to_string()),
+            ("lookups_per_sec", json_object(&lookup_rates.iter().map(|(kind, rate)| (kind.name(), rate.to_string())).collect::<Vec<_>>())),
+            ("inserts_per_sec", json_object(&insert_rates.iter().map(|(mode, rate)| (insert_mode(mode), rate.to_string())).collect::<Vec<_>>())),
+            ("phase_secs", json_object(&Phase::ALL.iter().zip(estimate.phase_secs.iter())
+                .map(|(phase, secs)| (phase.name(), secs.to_string())).collect::<Vec<_>>()))
+        ]))
+    }
+
+    Ok(())
+}
+
+/// e.g. "64.0 GiB"
+fn bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, units[unit])
+}
+
+fn report_json(report: &SearchReport) -> String {
+    let t2_matches = report.t2_matches.map_or("null".to_string(), |matches| matches.to_string());
+
+    json_object(&[
+        ("complete", report.is_complete().to_string()),
+        ("elapsed_secs", report.elapsed.as_secs_f64().to_string()),
+        ("t2_matches", t2_matches),
+        ("phases", json_array(report.phases.iter().map(phase_json))),
+        ("results", results_json(&report.results))
+    ])
+}
+
+fn phase_json(phase: &PhaseReport) -> String {
+    let range = |range: &std::ops::Range<u64>| format!("[{},{}]", range.start, range.end);
+
+    json_object(&[
+        ("phase", json_string(phase.phase.name())),
+        ("complete", phase.is_complete().to_string()),
+        ("stopped", phase.cancelled.map_or("null".to_string(), |reason| json_string(&reason.to_string()))),
+        ("elapsed_secs", phase.elapsed.as_secs_f64().to_string()),
+        ("completed", json_array(phase.completed.iter().map(range))),
+        ("total", range(&phase.total)),
+        ("resumed", phase.resumed.to_string())
+    ])
+}
+
+fn results_json(results: &[Pseudoprime]) -> String {
+    json_array(results.iter().map(|result| json_object(&[
+        ("pseudoprime", json_string(&result.pseudoprime.to_string())),
+        ("factors", json_array(result.factors.iter().map(|factor| json_string(&factor.to_string()))))
+    ])))
+}
+
+/// an object of already encoded values
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields.iter().map(|(key, value)| format!("{}:{}", json_string(key), value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::magic_numbers::PseudoprimeBounds;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    pub fn test_parse() {
+        assert_eq!(parse(&[]).unwrap(), (Command::Run, Options::default()));
+        assert_eq!(parse(&args("run")).unwrap(), (Command::Run, Options::default()));
+        assert_eq!(parse(&args("gen-r --help")).unwrap().0, Command::Help);
+
+        let (command, options) = parse(&args("--threads 8 run --filter-size 2^30 --hashes=3 --insert-mode partitioned:64 \
+            --numa-policy bind:1 --format json --min-bits 100 --max-bits 2048 --min-factors 3 --run-dir /tmp/run \
+            --checkpoint-interval 600 --tasks 16 --time-limit 60 --time-limit final_sieve=1.5 --split balanced --t2-index eytzinger")).unwrap();
+        assert_eq!(command, Command::Run);
+        assert!(options.balanced);
+        assert_eq!(options.t2_index, IndexKind::Eytzinger);
+        assert_eq!(options.pool.threads, Some(8));
+        assert_eq!(options.filter.size, 1 << 30);
+        assert_eq!(options.filter.hashes, 3);
+        assert_eq!(options.filter.insert_mode, InsertMode::Partitioned { regions: 64 });
+        assert_eq!(options.filter.memory_policy, MemoryPolicy::Bind(1));
+        assert_eq!(options.format, Format::Json);
+        assert_eq!(options.instance().unwrap().min_n(), Integer::from(Integer::u_pow_u(2, 99)));
+        assert_eq!(options.instance().unwrap().bounds(), PseudoprimeBounds { bits: 100..=2048, factors: 3..=u32::MAX });
+        assert_eq!(options.run_dir, Some(PathBuf::from("/tmp/run")));
+        assert_eq!(options.checkpoint_interval, Duration::from_secs(600));
+        assert_eq!(options.tasks, 16);
+        assert_eq!(options.time_limit(Phase::BloomT1), Some(Duration::from_secs(60)));
+        assert_eq!(options.time_limit(Phase::FinalSieve), Some(Duration::from_millis(1500)));
+
+        let (command, options) = parse(&args("phase2 --filter f.bin --out t2.bin --numa-policy interleave")).unwrap();
+        assert_eq!(command, Command::Phase2);
+        assert_eq!((options.filter_file, options.out), (Some(PathBuf::from("f.bin")), Some(PathBuf::from("t2.bin"))));
+        assert_eq!(options.filter.memory_policy, MemoryPolicy::Interleave);
+        assert!(!options.balanced);
+
+        let (command, options) = parse(&args("verify 2047 --format json")).unwrap();
+        assert_eq!((command, options.format), (Command::Verify(Integer::from(2047)), Format::Json));
+        assert_eq!(parse(&args("--insert-mode partitioned phase1 --out f.bin")).unwrap().1.filter.insert_mode,
+            InsertMode::Partitioned { regions: 0 });
+    }
+
+    #[test]
+    pub fn test_parse_errors() {
+        for line in ["frobnicate", "run extra", "--frobnicate 1", "--threads", "--threads 0", "--threads x",
+            "--filter-size 2^99", "--filter-size 8", "--insert-mode locked", "--numa-policy bind:x",
+            "--format xml", "--time-limit bloom=5", "--time-limit -1", "run --out f.bin", "phase1",
+            "phase2 --out t2.bin", "phase2 --filter f.bin --out t2.bin --filter-size 2^30", "verify",
+            "verify seven", "gen-r --threads 2", "--min-bits 1", "instance --format json", "--split even",
+            "gen-r --split balanced", "--t2-index btree", "phase2 --filter f.bin --out t2.bin --t2-index sorted",
+            "--checkpoint-interval 0", "phase1 --checkpoint-interval 60 --out f.bin"] {
+            assert!(parse(&args(line)).is_err(), "{:?} parsed", line);
+        }
+
+        assert_eq!(main(&args("frobnicate")), EXIT_USAGE);
+        assert_eq!(main(&args("gen-r --instance /nonexistent/instance")), EXIT_FAILURE);
+        assert_eq!(main(&args("instance --min-bits 100 --max-bits 50")), EXIT_FAILURE);
+        assert_eq!(main(&args("instance --min-factors 5 --max-factors 4")), EXIT_FAILURE);
+    }
+
+    #[test]
+    pub fn test_instance() {
+        let path = std::env::temp_dir().join(format!("pseudoprimes-cli-instance-{}", std::process::id()));
+        let out = path.to_str().unwrap();
+        assert_eq!(main(&args(&format!("instance --min-bits 100 --out {}", out))), EXIT_OK);
+
+        let saved = ProblemInstance::load(&path).unwrap();
+        assert_eq!(saved, ProblemInstance { min_bits: 100, ..ProblemInstance::paper() });
+
+        let (_, options) = parse(&args(&format!("run --instance {}", out))).unwrap();
+        assert_eq!(options.instance().unwrap(), saved);
+        assert_eq!(main(&args(&format!("gen-r --instance {}", out))), EXIT_OK);
+
+        let (_, options) = parse(&args(&format!("verify 7 --instance {} --bases 2,3,5", out))).unwrap();
+        let instance = options.instance().unwrap();
+        assert_eq!(instance.bases(), [2, 3, 5]);
+        assert_eq!(instance.min_bits, 100);
+        assert!(parse(&args("gen-r --bases 2,x")).is_err());
+        assert_eq!(main(&args("gen-r --bases 2,4")), EXIT_FAILURE);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_find_moduli() {
+        let (command, options) = parse(&args("find-moduli --bases 2,3 --modulus-bits 24 --target 20 --max-prime 13 \
+            --max-exponent 2 --shortlist 4 --format json")).unwrap();
+        assert_eq!(command, Command::FindModuli);
+        assert_eq!(options.moduli.max_modulus, (1 << 24) - 1);
+        assert_eq!((options.moduli.target, options.moduli.max_prime, options.moduli.max_exponent, options.moduli.shortlist),
+            (20, 13, 2, 4));
+        assert!(parse(&args("find-moduli --modulus-bits 65")).is_err());
+        assert!(parse(&args("gen-r --target 20")).is_err());
+
+        let path = std::env::temp_dir().join(format!("pseudoprimes-cli-moduli-{}", std::process::id()));
+        let line = format!("find-moduli --bases 2,3 --modulus-bits 24 --max-prime 13 --max-exponent 2 --shortlist 4 --min-bits 30 \
+            --max-bits 1024 --out {}", path.to_str().unwrap());
+        assert_eq!(main(&args(&line)), EXIT_OK);
+
+        let found = ProblemInstance::load(&path).unwrap();
+        assert_eq!(found.bases(), [2, 3]);
+        assert_eq!((found.min_bits, found.max_bits), (30, Some(1024)));
+        assert!(found.modulus < 1 << 24);
+        assert_eq!(found.expected_candidates, Some(found.candidates().len()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_verify() {
+        // 2047 = 23 * 89 is the smallest strong pseudoprime to base 2
+        let n = Integer::from(2047);
+        assert!(is_strong_probable_prime(&n, 2));
+        assert!(!is_strong_probable_prime(&n, 3));
+        let paper = ProblemInstance::paper();
+        assert_eq!(verify(&paper, &n, Format::Text), EXIT_FAILURE);
+
+        for p in [2u32, 3, 5, 41, 1_000_003] {
+            assert_eq!(verify(&paper, &Integer::from(p), Format::Json), EXIT_OK, "{}", p);
+        }
+
+        // ... but with only base 2 to fool, it passes
+        let base_2 = ProblemInstance { conditions: vec![(2, -1)], expected_candidates: None, ..paper };
+        assert_eq!(verify(&base_2, &n, Format::Text), EXIT_OK);
+        assert!(!is_strong_probable_prime(&Integer::from(1), 2));
+        assert!(!is_strong_probable_prime(&Integer::from(1_000_002), 3));
+    }
+
+    #[test]
+    pub fn test_estimate() {
+        let estimate = Estimate::new(&FilterConfig::default(), 32, 32, M, 2, 4, 1e8);
+        assert_eq!(estimate.filter_bytes, (1u64 << 36) as f64);
+        assert_eq!(estimate.filter_copies, 2);
+        // k = 2, n/m = 2^-7: (1 - e^(-1/64))^2
+        assert!((estimate.false_positive_rate - 2.4e-4).abs() < 1e-5, "{}", estimate.false_positive_rate);
+        assert!((estimate.t2_matches - 2.0f64.powi(32) * estimate.false_positive_rate).abs() < 2.0);
+        assert_eq!(estimate.phase_secs[0], 2.0f64.powi(32) / 4e8);
+
+        let interleaved = FilterConfig { memory_policy: MemoryPolicy::Interleave, ..FilterConfig::default() };
+        assert_eq!(Estimate::new(&interleaved, 32, 32, M, 2, 4, 1e8).filter_copies, 1);
+        assert_eq!(bytes(estimate.filter_bytes), "64.0 GiB");
+    }
+
+    #[test]
+    pub fn test_json() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+        assert_eq!(json_object(&[("a", "1".to_string()), ("b", json_array(["2".to_string()].into_iter()))]),
+            "{\"a\":1,\"b\":[2]}");
+
+        let results = [Pseudoprime { pseudoprime: Integer::from(7), factors: vec![Integer::from(2), Integer::from(3)] }];
+        assert_eq!(results_json(&results), "[{\"pseudoprime\":\"7\",\"factors\":[\"2\",\"3\"]}]");
+    }
+}
@@ -0,0 +1,476 @@
+// instance.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// A problem instance: everything that decides which candidates r go into R. The search
+// looks for subsets of R whose product is 1 mod M, where each r is a prime with r - 1
+// dividing M, r within the bounds, and (b/r) matching the target for each Miller-Rabin
+// base b. `ProblemInstance::paper()` is the instance from the paper, which the globals in
+// `magic_numbers` come from; others can be loaded from a file like this one:
+//
+//   # the instance from the paper
+//   modulus = 11908862398227544750
+//   factors = 2, 5^3, 7^2, 11^2, 13, 17, 19, 23, 29, 31, 37, 41, 61
+//   conditions = 2:-1, 3:1, 5:1, 7:-1, 11:-1, 13:1, 17:1, 19:-1, 23:-1, 29:1, 31:-1, 37:1, 41:1
+//   min_r = 256
+//   max_r = 1152921504606846976
+//   min_bits = 513
+//   candidates = 64
+//
+// `conditions` are the bases with the Jacobi symbol each candidate must have with them,
+// `min_bits` is the fewest bits a pseudoprime may have, and `candidates` (optional) is how
+// many candidates the instance is known to have. A pseudoprime can also be given at most
+// `max_bits` bits, and `min_factors` to `max_factors` of the candidates to be made of (all
+// three optional). In place of `conditions`, a file can give
+// just the bases, e.g. `bases = 2, 3, 5, 7`, and have the symbols worked out for it (see
+// `derive_conditions`).
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rug::Integer;
+use rug::integer::IsPrime;
+
+use crate::candidates::CandidateSet;
+use crate::checkpoint::{parse_key_values, write_atomically};
+use crate::magic_numbers::{M, MAGIC_PAIRS, MAX_R, MIN_R, PseudoprimeBounds};
+
+/// the fewest bits a pseudoprime may have in the paper: over 2^512
+const PAPER_MIN_BITS: u32 = 513;
+const PAPER_CANDIDATES: usize = 64;
+
+/// the keys an instance file must have, besides one of `conditions` or `bases`
+const KEYS: [&str; 5] = ["modulus", "factors", "min_r", "max_r", "min_bits"];
+const OPTIONAL_KEYS: [&str; 6] = ["conditions", "bases", "candidates", "max_bits", "min_factors", "max_factors"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProblemInstance {
+    /// M: every candidate r has r - 1 dividing it
+    pub modulus: u64,
+    /// M's prime factorization as (prime, exponent), in increasing order of the primes
+    pub factors: Vec<(u64, u32)>,
+    /// the Miller-Rabin bases b, each with the Jacobi symbol (b/r) a candidate r must have
+    pub conditions: Vec<(u64, i32)>,
+    pub min_r: u64,
+    pub max_r: u64,
+    /// the fewest bits a pseudoprime may have
+    pub min_bits: u32,
+    /// the most bits a pseudoprime may have, if there's a limit
+    pub max_bits: Option<u32>,
+    /// the fewest candidates a pseudoprime may be the product of (besides the 2)
+    pub min_factors: Option<u32>,
+    pub max_factors: Option<u32>,
+    /// how many candidates there should be, if known
+    pub expected_candidates: Option<usize>
+}
+
+impl ProblemInstance {
+    /// the instance from the paper
+    pub fn paper() -> Self {
+        ProblemInstance {
+            modulus: M,
+            factors: vec![(2, 1), (5, 3), (7, 2), (11, 2), (13, 1), (17, 1), (19, 1), (23, 1), (29, 1), (31, 1),
+                (37, 1), (41, 1), (61, 1)],
+            conditions: MAGIC_PAIRS.iter().map(|pair| (pair.b as u64, pair.c)).collect(),
+            min_r: MIN_R,
+            max_r: MAX_R,
+            min_bits: PAPER_MIN_BITS,
+            max_bits: None,
+            min_factors: None,
+            max_factors: None,
+            expected_candidates: Some(PAPER_CANDIDATES)
+        }
+    }
+
+    /// reads and validates an instance file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        ProblemInstance::parse(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, |out| {
+            use std::io::Write;
+            write!(out, "{}", self)
+        })
+    }
+
+    /// parses and validates the contents of an instance file
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let values = parse_key_values(text)?;
+        if let Some(key) = values.keys().find(|key| !KEYS.contains(&key.as_str()) && !OPTIONAL_KEYS.contains(&key.as_str())) {
+            return Err(invalid(format!("unknown key {:?}", key)));
+        }
+        if let Some(key) = KEYS.iter().find(|key| !values.contains_key(**key)) {
+            return Err(invalid(format!("no {} given", key)));
+        }
+        if values.contains_key("conditions") == values.contains_key("bases") {
+            return Err(invalid("give either the conditions or the bases".to_string()));
+        }
+
+        let number = |key: &str, value: &str| -> io::Result<u64> {
+            value.trim().parse().map_err(|_| invalid(format!("bad {} {:?}", key, value)))
+        };
+        let list = |key: &str| values.get(key).into_iter().flat_map(|list| list.split(',')).map(str::trim)
+            .filter(|item| !item.is_empty());
+
+        let factors = list("factors").map(|factor| {
+            let (prime, exponent) = factor.split_once('^').unwrap_or((factor, "1"));
+            Ok((number("factor", prime)?, number("exponent", exponent)? as u32))
+        }).collect::<io::Result<Vec<_>>>()?;
+
+        let conditions = list("conditions").map(|condition| {
+            let (base, symbol) = condition.split_once(':')
+                .ok_or_else(|| invalid(format!("condition {:?} isn't base:symbol", condition)))?;
+            let symbol = symbol.trim().parse().map_err(|_| invalid(format!("bad symbol in {:?}", condition)))?;
+            Ok((number("base", base)?, symbol))
+        }).collect::<io::Result<Vec<_>>>()?;
+
+        let bases = list("bases").map(|base| number("base", base)).collect::<io::Result<Vec<_>>>()?;
+        let optional = |key: &str| -> io::Result<Option<u32>> {
+            match values.get(key) {
+                Some(value) => Ok(Some(number(key, value)? as u32)),
+                None => Ok(None)
+            }
+        };
+
+        let mut instance = ProblemInstance {
+            modulus: number("modulus", &values["modulus"])?,
+            factors,
+            conditions,
+            min_r: number("min_r", &values["min_r"])?,
+            max_r: number("max_r", &values["max_r"])?,
+            min_bits: number("min_bits", &values["min_bits"])? as u32,
+            max_bits: optional("max_bits")?,
+            min_factors: optional("min_factors")?,
+            max_factors: optional("max_factors")?,
+            expected_candidates: optional("candidates")?.map(|count| count as usize)
+        };
+
+        if values.contains_key("bases") {
+            let expected_candidates = instance.expected_candidates;
+            instance = instance.with_bases(&bases)?;
+            instance.expected_candidates = expected_candidates;
+        }
+
+        instance.validate()?;
+        Ok(instance)
+    }
+
+    /// This instance, with the conditions for `bases` worked out by `derive_conditions`.
+    /// Since that changes the candidates, it forgets how many there should be.
+    pub fn with_bases(self, bases: &[u64]) -> io::Result<Self> {
+        // the rest has to make sense before there are candidates to look at
+        let instance = ProblemInstance { conditions: Vec::new(), expected_candidates: None, ..self };
+        instance.validate()?;
+        if let Some(base) = bases.iter().find(|base| !is_prime(**base)) {
+            return Err(invalid(format!("base {} isn't prime", base)));
+        }
+
+        let conditions = instance.derive_conditions(bases)
+            .ok_or_else(|| invalid("no candidate is coprime to every base".to_string()))?;
+        let instance = ProblemInstance { conditions, ..instance };
+        instance.validate()?;
+        Ok(instance)
+    }
+
+    /// Checks that M matches its factorization, the factors and bases are primes, the
+    /// symbols are +1 or -1, the bounds make sense, and that there are as many candidates
+    /// as expected.
+    pub fn validate(&self) -> io::Result<()> {
+        let mut product = 1u64;
+        for (i, (prime, exponent)) in self.factors.iter().enumerate() {
+            if !is_prime(*prime) {
+                return Err(invalid(format!("factor {} isn't prime", prime)));
+            }
+            if *exponent == 0 {
+                return Err(invalid(format!("factor {} has exponent 0", prime)));
+            }
+            if i > 0 && self.factors[i - 1].0 >= *prime {
+                return Err(invalid(format!("factors aren't in increasing order at {}", prime)));
+            }
+
+            product = prime.checked_pow(*exponent).and_then(|power| product.checked_mul(power))
+                .ok_or_else(|| invalid("the factors multiply to more than 64 bits".to_string()))?;
+        }
+
+        if product != self.modulus {
+            return Err(invalid(format!("the factors multiply to {}, not the modulus {}", product, self.modulus)));
+        }
+        if self.modulus & 1 == 1 {
+            return Err(invalid(format!("the modulus {} is odd, so no odd prime r has r - 1 dividing it", self.modulus)));
+        }
+
+        for (i, (base, symbol)) in self.conditions.iter().enumerate() {
+            if !is_prime(*base) {
+                return Err(invalid(format!("base {} isn't prime", base)));
+            }
+            if *symbol != 1 && *symbol != -1 {
+                return Err(invalid(format!("base {} has symbol {}, not 1 or -1", base, symbol)));
+            }
+            if self.conditions[..i].iter().any(|(earlier, _)| earlier == base) {
+                return Err(invalid(format!("base {} is given twice", base)));
+            }
+        }
+
+        if self.min_r < 3 || self.min_r > self.max_r {
+            return Err(invalid(format!("bounds {}..={} are empty, or admit r = 2", self.min_r, self.max_r)));
+        }
+        if self.min_bits < 2 {
+            return Err(invalid(format!("min_bits is {}, it needs to be at least 2", self.min_bits)));
+        }
+        if let Some(max_bits) = self.max_bits.filter(|max_bits| *max_bits < self.min_bits) {
+            return Err(invalid(format!("max_bits {} is less than min_bits {}", max_bits, self.min_bits)));
+        }
+        if let (Some(min), Some(max)) = (self.min_factors, self.max_factors) {
+            if min > max {
+                return Err(invalid(format!("min_factors {} is more than max_factors {}", min, max)));
+            }
+        }
+
+        if let Some(expected) = self.expected_candidates {
+            let found = self.candidates().len();
+            if found != expected {
+                return Err(invalid(format!("the instance has {} candidates, expected {}", found, expected)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the Miller-Rabin bases
+    pub fn bases(&self) -> Vec<u64> {
+        self.conditions.iter().map(|(base, _)| *base).collect()
+    }
+
+    /// True if `r` is prime, within the bounds and has the right symbol with every base.
+    /// That r - 1 divides M is left to the caller.
+    pub fn is_candidate(&self, r: u64) -> bool {
+        self.is_prime_in_bounds(r) && self.has_symbols(r)
+    }
+
+    fn is_prime_in_bounds(&self, r: u64) -> bool {
+        r >= self.min_r && r <= self.max_r && Integer::from(r).is_probably_prime(15) != IsPrime::No
+    }
+
+    fn has_symbols(&self, r: u64) -> bool {
+        let r = Integer::from(r);
+        self.conditions.iter().all(|(base, symbol)| Integer::from(*base).jacobi(&r) == *symbol)
+    }
+
+    /// The set R, in increasing order: every candidate r with r - 1 an even divisor of M.
+    pub fn candidates(&self) -> Vec<u64> {
+        CandidateSet::generate(&self.factors, |r| r & 1 == 1 && self.is_candidate(r)).into_vec()
+    }
+
+    /// The symbols for `bases` that give the most candidates: the symbols (b/r) shared by
+    /// the most primes r within the bounds with r - 1 an even divisor of M, or on a tie, by
+    /// the smallest of them. For the paper's M and bases this is the `MAGIC_PAIRS` table.
+    /// None if every such r divides one of the bases.
+    pub fn derive_conditions(&self, bases: &[u64]) -> Option<Vec<(u64, i32)>> {
+        let symbols: Vec<Vec<i32>> = self.divisor_primes().into_iter().map(|r| {
+            let r = Integer::from(r);
+            bases.iter().map(|base| Integer::from(*base).jacobi(&r)).collect::<Vec<i32>>()
+        }).filter(|symbols| !symbols.contains(&0)).collect();
+
+        let mut counts = HashMap::new();
+        for symbols in symbols.iter() {
+            *counts.entry(symbols).or_insert(0usize) += 1;
+        }
+
+        let best = symbols.iter().min_by_key(|symbols| Reverse(counts[symbols]))?;
+        Some(bases.iter().cloned().zip(best.iter().cloned()).collect())
+    }
+
+    /// Every prime r within the bounds with r - 1 an even divisor of M, whatever its
+    /// symbols, in increasing order.
+    fn divisor_primes(&self) -> Vec<u64> {
+        CandidateSet::generate(&self.factors, |r| r & 1 == 1 && self.is_prime_in_bounds(r)).into_vec()
+    }
+
+    /// M's factorization as the instance file has it, e.g. "2, 5^3, 7^2"
+    pub fn factorization(&self) -> String {
+        let factors: Vec<String> = self.factors.iter().map(|(prime, exponent)| match exponent {
+            1 => prime.to_string(),
+            _ => format!("{}^{}", prime, exponent)
+        }).collect();
+        factors.join(", ")
+    }
+
+    /// the sizes a pseudoprime may have
+    pub fn bounds(&self) -> PseudoprimeBounds {
+        PseudoprimeBounds {
+            bits: self.min_bits..=self.max_bits.unwrap_or(u32::MAX),
+            factors: self.min_factors.unwrap_or(0)..=self.max_factors.unwrap_or(u32::MAX)
+        }
+    }
+
+    /// the number a pseudoprime has to be greater than to have `min_bits` bits
+    pub fn min_n(&self) -> Integer {
+        Integer::from(Integer::u_pow_u(2, self.min_bits - 1))
+    }
+}
+
+impl fmt::Display for ProblemInstance {
+    /// the instance file format
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let conditions: Vec<String> = self.conditions.iter().map(|(base, symbol)| format!("{}:{}", base, symbol)).collect();
+
+        writeln!(f, "# pseudoprime search problem instance")?;
+        writeln!(f, "modulus = {}", self.modulus)?;
+        writeln!(f, "factors = {}", self.factorization())?;
+        writeln!(f, "conditions = {}", conditions.join(", "))?;
+        writeln!(f, "min_r = {}", self.min_r)?;
+        writeln!(f, "max_r = {}", self.max_r)?;
+        writeln!(f, "min_bits = {}", self.min_bits)?;
+        for (key, value) in [("max_bits", self.max_bits), ("min_factors", self.min_factors), ("max_factors", self.max_factors)] {
+            if let Some(value) = value {
+                writeln!(f, "{} = {}", key, value)?;
+            }
+        }
+        if let Some(expected) = self.expected_candidates {
+            writeln!(f, "candidates = {}", expected)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_prime(n: u64) -> bool {
+    Integer::from(n).is_probably_prime(30) != IsPrime::No
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// M = 2 * 3^2 * 5 * 7 * 11 * 13, with conditions on 2 and 3
+    fn small() -> ProblemInstance {
+        ProblemInstance {
+            modulus: 2 * 9 * 5 * 7 * 11 * 13,
+            factors: vec![(2, 1), (3, 2), (5, 1), (7, 1), (11, 1), (13, 1)],
+            conditions: vec![(2, 1), (3, -1)],
+            min_r: 5,
+            max_r: 100_000,
+            min_bits: 10,
+            max_bits: None,
+            min_factors: None,
+            max_factors: None,
+            expected_candidates: None
+        }
+    }
+
+    #[test]
+    pub fn test_paper() {
+        let paper = ProblemInstance::paper();
+        paper.validate().unwrap();
+
+        let candidates = paper.candidates();
+        assert_eq!(candidates.len(), 64);
+        assert_eq!(candidates[..3], [98308211, 202546499, 303353051]);
+        assert!(candidates.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(paper.min_n(), Integer::from(Integer::u_pow_u(2, 512)));
+        assert_eq!(paper.bases(), [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41]);
+    }
+
+    #[test]
+    pub fn test_candidates() {
+        let instance = small();
+
+        // every r in the bounds with r - 1 even and dividing M, found the slow way
+        let expected: Vec<u64> = (instance.min_r..=instance.max_r)
+            .filter(|r| r & 1 == 1 && instance.modulus.checked_rem(r - 1) == Some(0) && instance.is_candidate(*r))
+            .collect();
+        let candidates = instance.candidates();
+        assert!(!candidates.is_empty());
+        assert_eq!(candidates, expected);
+        for r in candidates {
+            assert_eq!(Integer::from(2).jacobi(&Integer::from(r)), 1);
+            assert_eq!(Integer::from(3).jacobi(&Integer::from(r)), -1);
+        }
+    }
+
+    #[test]
+    pub fn test_derive_conditions() {
+        let paper = ProblemInstance::paper();
+        assert_eq!(paper.derive_conditions(&paper.bases()), Some(paper.conditions.clone()));
+
+        let derived = paper.clone().with_bases(&paper.bases()).unwrap();
+        assert_eq!(derived.conditions, paper.conditions);
+        assert_eq!(derived.candidates(), paper.candidates());
+
+        // fewer bases to fool leaves more candidates, none of which have the wrong symbols
+        let few = paper.clone().with_bases(&[2, 3, 5]).unwrap();
+        assert_eq!(few.bases(), [2, 3, 5]);
+        let candidates = few.candidates();
+        assert!(candidates.len() > 64);
+        assert!(candidates.iter().all(|r| few.is_candidate(*r)));
+        // (2/r) splits them evenly, so the smallest r picks its symbol
+        assert_eq!(few.conditions[1..], paper.conditions[1..3]);
+        let paper_few = ProblemInstance { conditions: paper.conditions[..3].to_vec(), ..few.clone() };
+        assert_eq!(paper_few.candidates().len(), candidates.len());
+        assert!(paper.candidates().iter().all(|r| paper_few.candidates().contains(r)));
+
+        assert!(paper.clone().with_bases(&[2, 4]).is_err());
+        // every r is 2 mod 3, so (3/r) is always the same
+        assert_eq!(few.conditions[1], (3, 1));
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let paper = ProblemInstance::paper();
+        assert_eq!(ProblemInstance::parse(&paper.to_string()).unwrap(), paper);
+
+        let text = "# comment\nmodulus = 90090\nfactors = 2, 3^2, 5, 7, 11, 13\nconditions = 2:+1, 3:-1\n\
+            min_r = 5\nmax_r = 100000\nmin_bits = 10\n";
+        assert_eq!(ProblemInstance::parse(text).unwrap(), small());
+
+        let text = text.replace("conditions = 2:+1, 3:-1", "bases = 2, 3");
+        let derived = ProblemInstance::parse(&text).unwrap();
+        assert_eq!(derived, small().with_bases(&[2, 3]).unwrap());
+        assert!(ProblemInstance::parse(&format!("{}conditions = 2:1\n", text)).is_err());
+
+        let bounded = ProblemInstance { max_bits: Some(2048), min_factors: Some(3), max_factors: Some(40), ..paper.clone() };
+        assert_eq!(ProblemInstance::parse(&bounded.to_string()).unwrap(), bounded);
+        assert_eq!(bounded.bounds(), PseudoprimeBounds { bits: 513..=2048, factors: 3..=40 });
+        assert_eq!(paper.bounds(), PseudoprimeBounds { bits: 513..=u32::MAX, factors: 0..=u32::MAX });
+
+        let path = std::env::temp_dir().join(format!("pseudoprimes-instance-{}", std::process::id()));
+        paper.save(&path).unwrap();
+        assert_eq!(ProblemInstance::load(&path).unwrap(), paper);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let bad = [
+            ProblemInstance { modulus: 90091, ..small() },
+            ProblemInstance { modulus: 2 * 9 * 5 * 7 * 11 * 15, factors: vec![(2, 1), (3, 2), (5, 1), (7, 1), (11, 1), (15, 1)], ..small() },
+            ProblemInstance { factors: vec![(3, 2), (2, 1), (5, 1), (7, 1), (11, 1), (13, 1)], ..small() },
+            ProblemInstance { modulus: 45045, factors: vec![(3, 2), (5, 1), (7, 1), (11, 1), (13, 1)], ..small() },
+            ProblemInstance { conditions: vec![(4, 1)], ..small() },
+            ProblemInstance { conditions: vec![(2, 0)], ..small() },
+            ProblemInstance { conditions: vec![(2, 1), (2, 1)], ..small() },
+            ProblemInstance { min_r: 100, max_r: 10, ..small() },
+            ProblemInstance { min_r: 2, ..small() },
+            ProblemInstance { min_bits: 1, ..small() },
+            ProblemInstance { max_bits: Some(9), ..small() },
+            ProblemInstance { min_factors: Some(3), max_factors: Some(2), ..small() },
+            ProblemInstance { expected_candidates: Some(1000), ..small() },
+        ];
+        for instance in bad.iter() {
+            assert!(instance.validate().is_err(), "{:?} is valid", instance);
+        }
+
+        for text in ["modulus = 90090", "frobnicate = 1", "modulus = x\nfactors = 2\nconditions = \nmin_r = 3\nmax_r = 5\nmin_bits = 2"] {
+            assert!(ProblemInstance::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
@@ -3,7 +3,6 @@
 
 #![allow(dead_code)]
 #![cfg_attr(feature = "unstable", feature(asm))]
-#![cfg_attr(feature = "unstable", feature(core_intrinsics))]
 #![cfg_attr(feature = "unstable", feature(avx512_target_feature))]
 
 #[macro_use]
@@ -11,7 +10,6 @@ extern crate lazy_static;
 extern crate modinverse;
 extern crate rug;
 extern crate threadpool;
-#[cfg(feature="unstable")]
 extern crate libc;
 extern crate itertools;
 
@@ -22,4 +20,13 @@ pub mod gray_prod_iter;
 pub mod magic_numbers;
 pub mod bitset;
 pub mod modulus;
-pub mod numa_threadpool;
\ No newline at end of file
+pub mod numa_threadpool;
+pub mod topology;
+pub mod cancel;
+pub mod checkpoint;
+pub mod search;
+pub mod cli;
+pub mod instance;
+pub mod moduli;
+pub mod candidates;
+pub mod t2_map;
\ No newline at end of file
@@ -1,18 +1,22 @@
 // magic_numbers.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::ops::RangeInclusive;
+
 use rug::Integer;
 use rug::integer::IsPrime;
-use itertools::iproduct;
 use crate::modulus::*;
+use crate::instance::ProblemInstance;
 
 pub const M: u64 = 11908862398227544750;
 pub const MAX_R: u64 = 1152921504606846976;
 pub const MIN_R: u64 = 256;
+/// how far the log2 of a subset product may be off, from rounding
+const LOG2_SLACK: f64 = 1e-6;
 
 pub struct MagicPairs {
-    b: isize,
-    c: i32,
+    pub b: isize,
+    pub c: i32,
 }
 
 /// legendre symbol table
@@ -34,86 +38,37 @@ pub const MAGIC_PAIRS: [MagicPairs; 13] = [
 
 
 lazy_static! {
-    pub static ref R: Vec<u64> = {
-        // Find the set R
-        let mut results = Vec::new();
-
-        // we compute all subset products of the prime factorization of M,
-        // subject to the conditions laid out in the paper. In particular,
-        // the conditions require that the subset product is even, because
-        // 1+ssp must be prime to be in the set R.
-
-        // note that 2 is excluded here: all valid subset products must 
-        // be even, so we always multiply by 2 below
-        let m_prime: [u64; 9] = [13, 17, 19, 23, 29, 31, 37, 41, 61];
-        let mut m_pps = Vec::new();
-        for (i,j,k) in iproduct!(vec!(1,5,25,125),vec!(1,7,49),vec!(1,11,121)) {
-            m_pps.push(i*j*k);
-        }
-        let len = m_prime.len();
-        for counter in 1..2u32.pow(len as u32) {
-            let mut mask = counter;
-            // it would be nice to use gray code subsets here,
-            // but the gray_prod_iters is only for modular arithemtic
-            let mut prime_ssp: u64 = 1;
-            for i in 0..len {
-                if mask & 0b1 == 1 {
-                    prime_ssp *= m_prime[i];
-                }
-                mask = mask >> 1;
-            }
-            for pp_ssp in &m_pps {
-                let candidate = (2 as u64)*pp_ssp*prime_ssp + 1;
+    /// the instance from the paper, which everything below is derived from
+    pub static ref INSTANCE: ProblemInstance = ProblemInstance::paper();
 
-                if check_divisor(candidate) {
-                    if !results.contains(&candidate) {
-                        results.push(candidate);
-                    }
-                }
-            }
-        }
+    /// The set R: see `ProblemInstance::candidates`.
+    pub static ref R: Vec<u64> = {
+        let results = INSTANCE.candidates();
         // This checks that we got the expected size of this set,
         // which is given in the Bleichenbacher paper.
-        assert_eq!(64,results.len());
+        assert_eq!(INSTANCE.expected_candidates, Some(results.len()));
 
         results
     };
 }
 
-/// Find set  R of all integers r satisfying
+/// true if r belongs in the set R of the paper, that is
 /// 256 < r < 2^60
-/// r - 1 | M
 /// r is prime
-/// (bi/r) = ci for )<i<14
+/// (bi/r) = ci for 0<i<14
+/// given that r - 1 | M
 pub fn check_divisor(r: u64) -> bool {
-    if r < MIN_R || r > MAX_R {
-        return false;
-    }
-
-    let r_int = Integer::from(r);
-    let result = r_int.is_probably_prime(15);
-    if result == IsPrime::No {
-        return false;
-    }
-
-    for pair in MAGIC_PAIRS.iter() {
-        let b = Integer::from(pair.b);
-        if b.jacobi(&r_int) != pair.c {
-            return false;
-        }
-    }
-    
-    return true;
+    INSTANCE.is_candidate(r)
 }
 
 lazy_static! {
-    pub static ref T1: Vec<u64> = Vec::from(&R[0..32]);
-    pub static ref T2: Vec<u64> = Vec::from(&R[32..64]);
+    pub static ref T1: Vec<u64> = Vec::from(&R[..R.len() / 2]);
+    pub static ref T2: Vec<u64> = Vec::from(&R[R.len() / 2..]);
 
     pub static ref T1_INVERSE: Vec<u64> = inverse(&T1[..],MODULUS);
     pub static ref T2_INVERSE: Vec<u64> = inverse(&T2[..],MODULUS);
 
-    pub static ref MIN_N: Integer = Integer::from(Integer::i_pow_u(2, 512));
+    pub static ref MIN_N: Integer = INSTANCE.min_n();
 }
 
 /// collect the values indicated by the mask
@@ -151,7 +106,7 @@ pub fn check_prime(min_n: &Integer, t1: &[u64], t2: &[u64], t1_mask: u32, t2_mas
 
     let product = Integer::from(Integer::product(values_to_multiply.iter()));
     let n_result = Integer::from(&product + &Integer::from(1));
-    if n_result.cmp(&min_n) == Ordering::Greater {
+    if n_result.cmp(min_n) == Ordering::Greater {
         let result = n_result.is_probably_prime(15);
         if result == IsPrime::Probably || result == IsPrime::Yes {
             return Some(Pseudoprime { pseudoprime: n_result, factors: values_to_multiply });
@@ -160,3 +115,191 @@ pub fn check_prime(min_n: &Integer, t1: &[u64], t2: &[u64], t1_mask: u32, t2_mas
 
     return None;
 }
+
+/// The sizes a pseudoprime n = 2 r1 ... rk + 1 may have: its bits, and k, the number of
+/// candidates r it's made of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PseudoprimeBounds {
+    pub bits: RangeInclusive<u32>,
+    pub factors: RangeInclusive<u32>
+}
+
+/// Rules out subsets of halves `t1` and `t2` that are sure to be outside `PseudoprimeBounds`
+/// before they're multiplied out. Most subsets are too small or too big, and the log2 of a
+/// half's subset product is the sum of a lookup in a table for each byte of its mask.
+#[derive(Clone, Debug)]
+pub struct SizeFilter {
+    bounds: PseudoprimeBounds,
+    t1_logs: Vec<[f64; 256]>,
+    t2_logs: Vec<[f64; 256]>
+}
+
+impl SizeFilter {
+    pub fn new(bounds: PseudoprimeBounds, t1: &[u64], t2: &[u64]) -> Self {
+        SizeFilter { bounds, t1_logs: log_tables(t1), t2_logs: log_tables(t2) }
+    }
+
+    pub fn bounds(&self) -> &PseudoprimeBounds {
+        &self.bounds
+    }
+
+    /// log2 of n - 1 for the subset the masks indicate, give or take `LOG2_SLACK`
+    pub fn log2(&self, t1_mask: u32, t2_mask: u32) -> f64 {
+        let sum = |tables: &[[f64; 256]], mask: u32| -> f64 {
+            tables.iter().enumerate().map(|(i, table)| table[(mask >> (8 * i)) as usize & 0xff]).sum()
+        };
+        1.0 + sum(&self.t1_logs, t1_mask) + sum(&self.t2_logs, t2_mask)
+    }
+
+    /// False if the subset the masks indicate is sure to be out of bounds; true if it may
+    /// not be, which is only certain to within `LOG2_SLACK` of a power of two.
+    pub fn might_fit(&self, t1_mask: u32, t2_mask: u32) -> bool {
+        if !self.bounds.factors.contains(&(t1_mask.count_ones() + t2_mask.count_ones())) {
+            return false;
+        }
+
+        // n has floor(log2 n) + 1 bits, and log2 n rounds down to the same as log2 (n - 1)
+        let log2 = self.log2(t1_mask, t2_mask);
+        log2 + LOG2_SLACK >= (*self.bounds.bits.start() as f64) - 1.0 && log2 - LOG2_SLACK < *self.bounds.bits.end() as f64
+    }
+}
+
+/// `check_prime` for halves `t1` and `t2`, with `PseudoprimeBounds` rather than just a
+/// minimum, and the `SizeFilter` for them in front.
+pub struct PseudoprimeCheck {
+    size: SizeFilter,
+    t1: Vec<u64>,
+    t2: Vec<u64>
+}
+
+impl PseudoprimeCheck {
+    pub fn new(bounds: PseudoprimeBounds, t1: &[u64], t2: &[u64]) -> Self {
+        PseudoprimeCheck { size: SizeFilter::new(bounds, t1, t2), t1: t1.to_vec(), t2: t2.to_vec() }
+    }
+
+    /// the filter `check` starts with, which `final_sieve` can also run itself
+    pub fn size(&self) -> &SizeFilter {
+        &self.size
+    }
+
+    /// the pseudoprime the masks indicate, if it's within the bounds and passes as prime
+    pub fn check(&self, t1_mask: u32, t2_mask: u32) -> Option<Pseudoprime> {
+        if !self.size.might_fit(t1_mask, t2_mask) {
+            return None;
+        }
+
+        let values_to_multiply = get_vals_to_multiply(&self.t1, &self.t2, t1_mask, t2_mask);
+        let n = Integer::from(Integer::product(values_to_multiply.iter())) + 1u32;
+        if !self.size.bounds.bits.contains(&n.significant_bits()) {
+            return None;
+        }
+
+        match n.is_probably_prime(15) {
+            IsPrime::No => None,
+            _ => Some(Pseudoprime { pseudoprime: n, factors: values_to_multiply })
+        }
+    }
+}
+
+/// for each byte of a mask into `half`, the log2 of the product of the values it indicates
+fn log_tables(half: &[u64]) -> Vec<[f64; 256]> {
+    half.chunks(8).map(|values| {
+        let mut table = [0.0; 256];
+        for (byte, log2) in table.iter_mut().enumerate() {
+            *log2 = values.iter().enumerate().filter(|(i, _)| (byte >> i) & 1 == 1).map(|(_, r)| (*r as f64).log2()).sum();
+        }
+        table
+    }).collect()
+}
+
+/// the bases the pseudoprimes have to fool, from the legendre symbol table
+pub fn bases() -> Vec<u64> {
+    INSTANCE.bases()
+}
+
+/// one round of Miller-Rabin: true if `n` is a strong probable prime to `base`
+pub fn is_strong_probable_prime(n: &Integer, base: u64) -> bool {
+    if *n < 3 || n.is_even() {
+        return *n == 2;
+    }
+
+    let n_minus_1 = Integer::from(n - 1u64);
+    let a = Integer::from(base) % n;
+    // a multiple of n says nothing either way
+    if a == 0 {
+        return true;
+    }
+
+    // n - 1 = d * 2^s with d odd
+    let mut d = n_minus_1.clone();
+    let mut s = 0;
+    while d.is_even() {
+        d /= 2u32;
+        s += 1;
+    }
+
+    let two = Integer::from(2);
+    let mut x = a.pow_mod(&d, n).unwrap();
+    if x == 1 || x == n_minus_1 {
+        return true;
+    }
+
+    for _ in 1..s {
+        x = x.pow_mod(&two, n).unwrap();
+        if x == n_minus_1 {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    pub fn test_size_filter() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // random 40 to 64-bit values stand in for R, which only multiplies to about 2^2544 (too
+        // little for 3072 bits), and spreading their sizes out keeps the subset sizes from bunching up
+        let mut half = || (0..32).map(|_| rng.gen_range(1 << 63..u64::MAX) >> rng.gen_range(0..24)).collect::<Vec<u64>>();
+        let (t1, t2) = (half(), half());
+        let total: f64 = t1.iter().chain(t2.iter()).map(|r| (*r as f64).log2()).sum();
+
+        for bits in [1024, 2048, 3072] {
+            let bounds = PseudoprimeBounds { bits: bits..=bits, factors: 0..=64 };
+            let check = SizeFilter::new(bounds, &t1, &t2);
+            let (mut fits, mut ruled_out) = (0, 0);
+
+            for _ in 0..20000 {
+                // subsets around the size we're after
+                let density = bits as f64 / total;
+                let mut mask = || (0..32).filter(|_| rng.gen_bool(density)).fold(0u32, |mask, i| mask | 1 << i);
+                let (t1_mask, t2_mask) = (mask(), mask());
+
+                let product = Integer::from(Integer::product(get_vals_to_multiply(&t1, &t2, t1_mask, t2_mask).iter()));
+                let n = product + 1u32;
+                let log2 = check.log2(t1_mask, t2_mask);
+                assert!((log2 - (n.significant_bits() as f64 - 1.0)).abs() < 1.0 + LOG2_SLACK);
+
+                if n.significant_bits() == bits {
+                    assert!(check.might_fit(t1_mask, t2_mask), "{} bits ruled out", bits);
+                    fits += 1;
+                } else if !check.might_fit(t1_mask, t2_mask) {
+                    ruled_out += 1;
+                }
+            }
+
+            assert!(fits > 0 && ruled_out > 10 * fits, "{}: {} fit, {} ruled out", bits, fits, ruled_out);
+        }
+
+        let bounds = PseudoprimeBounds { bits: 2..=u32::MAX, factors: 2..=3 };
+        let check = SizeFilter::new(bounds, &T1, &T2);
+        assert!(!check.might_fit(0b1, 0));
+        assert!(check.might_fit(0b1, 0b1));
+        assert!(check.might_fit(0b11, 0b1));
+        assert!(!check.might_fit(0b11, 0b11));
+    }
+}
@@ -0,0 +1,386 @@
+// moduli.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Finding a modulus M for a set of bases, rather than picking one by hand. The moduli
+// looked at are the smooth ones: 2 times powers of the primes up to `max_prime`, each at
+// most `max_exponent` times, and no bigger than `max_modulus`. There are far too many of
+// those to build R for each, so `find_moduli` first scores them all with a rough estimate
+// of |R| (see `prime_factor`), keeps the `shortlist` best, and only builds R for
+// those, with the conditions `ProblemInstance::with_bases` derives for them.
+//
+// Only the maximal moduli are scored, those that no allowed prime can be multiplied into
+// without going over the bound: one more factor means more divisors, which outweighs M
+// getting bigger.
+
+use std::cmp::Ordering;
+use std::io;
+
+use rug::Integer;
+use rug::integer::IsPrime;
+
+use crate::instance::ProblemInstance;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModulusSearch {
+    /// the Miller-Rabin bases the pseudoprimes have to fool
+    pub bases: Vec<u64>,
+    /// the largest M to consider
+    pub max_modulus: u64,
+    /// how many candidates the search will use; any more don't help
+    pub target: usize,
+    /// the largest prime factor M may have
+    pub max_prime: u64,
+    /// the largest power of a prime M may have
+    pub max_exponent: u32,
+    /// how many of the best estimates to build R for
+    pub shortlist: usize,
+    pub min_r: u64,
+    pub max_r: u64,
+    pub min_bits: u32
+}
+
+impl ModulusSearch {
+    /// a search with the paper's bounds, for moduli like the paper's
+    pub fn new(bases: &[u64]) -> Self {
+        let paper = ProblemInstance::paper();
+        ModulusSearch {
+            bases: bases.to_vec(),
+            max_modulus: u64::MAX,
+            target: 64,
+            max_prime: 61,
+            max_exponent: 3,
+            shortlist: 16,
+            min_r: paper.min_r,
+            max_r: paper.max_r,
+            min_bits: paper.min_bits
+        }
+    }
+}
+
+/// A modulus that was looked at, with the instance it makes for the bases
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModulusCandidate {
+    /// with `expected_candidates` set to |R|
+    pub instance: ProblemInstance,
+    /// what the shortlist went by
+    pub estimated_candidates: f64,
+    /// roughly how many subsets of `target` candidates (or all of them, if fewer) have a
+    /// product that is 1 mod M: 2^|subset| / φ(M)
+    pub expected_solutions: f64
+}
+
+impl ModulusCandidate {
+    /// |R|
+    pub fn candidates(&self) -> usize {
+        self.instance.expected_candidates.unwrap_or(0)
+    }
+}
+
+/// The shortlisted moduli for `search`, best first: by the expected number of solutions,
+/// then by the most candidates. Moduli with no candidates at all are left out.
+pub fn find_moduli(search: &ModulusSearch) -> io::Result<Vec<ModulusCandidate>> {
+    if search.bases.is_empty() {
+        return Err(invalid("no bases to find a modulus for".to_string()));
+    }
+    if search.max_prime < 2 {
+        return Err(invalid("M is even, so the largest prime has to be at least 2".to_string()));
+    }
+    if search.max_modulus < 2 || search.max_exponent == 0 || search.shortlist == 0 {
+        return Err(invalid("the modulus bound, the exponent bound and the shortlist need to be at least 2, 1 and 1".to_string()));
+    }
+
+    let mut results = Vec::new();
+    for (estimate, factors) in shortlist(search) {
+        let modulus = factors.iter().map(|(prime, exponent)| prime.pow(*exponent)).product();
+        let instance = ProblemInstance {
+            modulus,
+            factors,
+            conditions: Vec::new(),
+            min_r: search.min_r,
+            max_r: search.max_r,
+            min_bits: search.min_bits,
+            max_bits: None,
+            min_factors: None,
+            max_factors: None,
+            expected_candidates: None
+        };
+
+        // with_bases fails when no candidate at all is coprime to the bases
+        let mut instance = match instance.with_bases(&search.bases) {
+            Ok(instance) => instance,
+            Err(_) => continue
+        };
+        let count = instance.candidates().len();
+        instance.expected_candidates = Some(count);
+
+        let phi: f64 = instance.factors.iter()
+            .map(|(prime, exponent)| (*prime as f64 - 1.0) * (*prime as f64).powi(*exponent as i32 - 1))
+            .product();
+        let expected_solutions = (count.min(search.target) as f64).exp2() / phi;
+        results.push(ModulusCandidate { instance, estimated_candidates: estimate, expected_solutions });
+    }
+
+    results.sort_by(|a, b| b.expected_solutions.partial_cmp(&a.expected_solutions).unwrap_or(Ordering::Equal)
+        .then(b.candidates().cmp(&a.candidates()))
+        .then(a.instance.modulus.cmp(&b.instance.modulus)));
+    Ok(results)
+}
+
+/// The maximal moduli with the `shortlist` best estimates, best first, each with its
+/// estimate and factorization.
+fn shortlist(search: &ModulusSearch) -> Vec<(f64, Vec<(u64, u32)>)> {
+    let primes: Vec<u64> = (2..=search.max_prime).filter(|n| is_prime(*n)).collect();
+    let factor = |prime: u64, exponent: u32, twos: u32| prime_factor(prime, exponent, twos, &search.bases);
+    let outside_bases: Vec<u64> = search.bases.iter().copied().filter(|base| !primes.contains(base)).collect();
+    let outside = |twos: u32| outside_bases.iter().map(|base| base_factor(*base, 0, twos)).product();
+
+    // 2 just once is the best case for every factor
+    let mut best = vec![1.0; primes.len() + 1];
+    let mut growth = vec![1u128; primes.len() + 1];
+    for (i, prime) in primes.iter().enumerate().rev() {
+        best[i] = best[i + 1] * (0..=search.max_exponent).map(|exponent| factor(*prime, exponent, 1)).fold(0.0, f64::max);
+        growth[i] = (0..search.max_exponent).fold(growth[i + 1], |growth, _| growth.saturating_mul(*prime as u128));
+    }
+
+    let mut walk = Walk { search, primes, best, growth, twos: 1, outside: 1.0, factors: Vec::new(), shortlist: Vec::new() };
+    // M is even, so 2 is always in it
+    let mut power = 1u64;
+    for exponent in 1..=search.max_exponent {
+        power = match power.checked_mul(2).filter(|power| *power <= search.max_modulus) {
+            Some(power) => power,
+            None => break
+        };
+        let room = if exponent < search.max_exponent { Some(2) } else { None };
+        walk.twos = exponent;
+        walk.outside = outside(exponent);
+        walk.factors.push((2, exponent));
+        walk.walk(1, power, room, factor(2, exponent, exponent));
+        walk.factors.pop();
+    }
+
+    walk.shortlist
+}
+
+/// A depth first walk over the moduli, one prime at a time, that keeps the maximal ones
+/// with the best estimates. The estimate is a product of a factor for each prime, so a
+/// branch whose best factors to come can't make the shortlist is cut off.
+struct Walk<'a> {
+    search: &'a ModulusSearch,
+    primes: Vec<u64>,
+    /// the product of the best factor each prime from each one on can have
+    best: Vec<f64>,
+    /// how much the primes from each one on could still multiply M by, at most
+    growth: Vec<u128>,
+    /// how many times 2 is in M, which the factor for base 3 depends on
+    twos: u32,
+    /// the factor for the bases that aren't among the primes
+    outside: f64,
+    /// the factorization so far
+    factors: Vec<(u64, u32)>,
+    /// best first
+    shortlist: Vec<(f64, Vec<(u64, u32)>)>
+}
+
+impl<'a> Walk<'a> {
+    /// Walks the primes from the `i`th on, with `modulus` so far, `room` the smallest
+    /// prime in it that could go in once more, and `score` the factors so far.
+    fn walk(&mut self, i: usize, modulus: u64, room: Option<u64>, score: f64) {
+        let bound = self.search.max_modulus as u128;
+        // it's only maximal if M gets over the bound divided by `room`
+        if let Some(room) = room {
+            if (modulus as u128).saturating_mul(self.growth[i]).saturating_mul(room as u128) <= bound {
+                return;
+            }
+        }
+        // M only gets bigger, past the bound divided by `room`, and the size factor smaller
+        let smallest = room.map_or(modulus, |room| modulus.max(self.search.max_modulus / room));
+        let most = score * self.best[i] * self.outside * size_factor(smallest);
+        if self.shortlist.len() >= self.search.shortlist && most <= self.shortlist[self.shortlist.len() - 1].0 {
+            return;
+        }
+
+        let prime = match self.primes.get(i) {
+            Some(prime) => *prime,
+            None => {
+                if room.is_none_or(|room| modulus as u128 * room as u128 > bound) {
+                    self.keep(score * self.outside * size_factor(modulus));
+                }
+                return;
+            }
+        };
+
+        let twos = self.twos;
+        let factor = |exponent: u32| prime_factor(prime, exponent, twos, &self.search.bases);
+        self.walk(i + 1, modulus, room.or(Some(prime)), score * factor(0));
+
+        let mut power = modulus;
+        for exponent in 1..=self.search.max_exponent {
+            power = match power.checked_mul(prime).filter(|power| *power <= self.search.max_modulus) {
+                Some(power) => power,
+                None => break
+            };
+            let room = if exponent < self.search.max_exponent { room.or(Some(prime)) } else { room };
+            let score = score * factor(exponent);
+            self.factors.push((prime, exponent));
+            self.walk(i + 1, power, room, score);
+            self.factors.pop();
+        }
+    }
+
+    fn keep(&mut self, estimate: f64) {
+        let at = self.shortlist.iter().position(|(other, _)| estimate > *other).unwrap_or(self.shortlist.len());
+        if at < self.search.shortlist {
+            self.shortlist.insert(at, (estimate, self.factors.clone()));
+            self.shortlist.truncate(self.search.shortlist);
+        }
+    }
+}
+
+/// The factor for `prime` in M `exponent` times (with 2 in it `twos` times), in a rough
+/// estimate of |R|. The estimate is the product of these for every prime M may have,
+/// `base_factor` for the bases that aren't among them, and `size_factor`. It counts
+/// - the even divisors d of M: each r = d + 1 is a candidate if it's prime and has the
+///   right symbols;
+/// - the chance r is prime, adjusted for each small prime p, since r is 1 mod p when p
+///   divides d, and 0 mod p a little more often than usual when it doesn't;
+/// - the chance r has the symbol with each base.
+fn prime_factor(prime: u64, exponent: u32, twos: u32, bases: &[u64]) -> f64 {
+    let symbol = if bases.contains(&prime) { base_factor(prime, exponent, twos) } else { 1.0 };
+    let (p, e) = (prime as f64, exponent as f64);
+    if prime == 2 {
+        // every r is odd, which `size_factor` allows for
+        return e * symbol;
+    }
+
+    // the share of the divisors that p divides
+    let divides = e / (e + 1.0);
+    // how much likelier r is to be coprime to p than a random number
+    let coprime = divides * p / (p - 1.0) + (1.0 - divides) * p * (p - 2.0) / ((p - 1.0) * (p - 1.0));
+    (e + 1.0) * coprime * symbol
+}
+
+/// The chance r has the right symbol with `base`, in M `exponent` times and 2 in M `twos`
+/// times: certain when the base divides d (so r is 1 mod it), and when it's 3, not in M,
+/// and M has 2 just once (so every r is 2 mod 3 and 3 mod 4, which fixes the symbol by
+/// reciprocity), and a coin flip otherwise.
+fn base_factor(base: u64, exponent: u32, twos: u32) -> f64 {
+    let e = exponent as f64;
+    match base {
+        2 => 0.5,
+        3 if exponent == 0 && twos == 1 => 1.0,
+        _ => (e + 0.5) / (e + 1.0)
+    }
+}
+
+/// the chance an odd r about the size of sqrt(M) is prime: 2 / ln r
+fn size_factor(modulus: u64) -> f64 {
+    2.0 / ((modulus as f64).ln() / 2.0).max(1.0)
+}
+
+fn is_prime(n: u64) -> bool {
+    Integer::from(n).is_probably_prime(30) != IsPrime::No
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// moduli up to 10^6 from the primes up to 13
+    fn small() -> ModulusSearch {
+        ModulusSearch {
+            max_modulus: 1_000_000,
+            max_prime: 13,
+            max_exponent: 2,
+            shortlist: 8,
+            target: 8,
+            min_r: 5,
+            max_r: 1_000_000,
+            min_bits: 10,
+            ..ModulusSearch::new(&[2, 3])
+        }
+    }
+
+    #[test]
+    pub fn test_maximal_moduli() {
+        let search = ModulusSearch { shortlist: usize::MAX, ..small() };
+        let mut moduli: Vec<u64> = shortlist(&search).into_iter()
+            .map(|(_, factors)| factors.iter().map(|(prime, exponent)| prime.pow(*exponent)).product())
+            .collect();
+
+        // every maximal modulus, found the slow way
+        let allowed = |m: u64| {
+            let mut rest = m;
+            for p in [2, 3, 5, 7, 11, 13] {
+                let mut exponent = 0;
+                while rest.checked_rem(p) == Some(0) {
+                    rest /= p;
+                    exponent += 1;
+                }
+                if exponent > 2 || (p == 2 && exponent == 0) {
+                    return false;
+                }
+            }
+            rest == 1
+        };
+        let mut expected: Vec<u64> = (2..=search.max_modulus).step_by(2)
+            .filter(|m| allowed(*m) && [2, 3, 5, 7, 11, 13].iter().all(|p| !allowed(m * p) || m * p > search.max_modulus))
+            .collect();
+        moduli.sort();
+        expected.sort();
+        assert!(!moduli.is_empty());
+        assert_eq!(moduli, expected);
+    }
+
+    #[test]
+    pub fn test_base_factor() {
+        // r = d + 1 for the even d dividing M = 2^twos 5 7 11 13, where 3 never divides d
+        let symbols = |twos: u32| {
+            let modulus = (1u64 << twos) * 5 * 7 * 11 * 13;
+            let mut symbols: Vec<i32> = (2..=modulus).step_by(2)
+                .filter(|d| modulus.is_multiple_of(*d) && is_prime(d + 1) && d + 1 != 3)
+                .map(|d| Integer::from(3).jacobi(&Integer::from(d + 1)))
+                .collect();
+            symbols.sort();
+            symbols.dedup();
+            symbols
+        };
+
+        // with 2 just once, every r has the same symbol with 3
+        assert_eq!(symbols(1).len(), 1);
+        assert_eq!(base_factor(3, 0, 1), 1.0);
+        // with 2 more often, r is 1 or 3 mod 4, and so is the symbol
+        assert_eq!(symbols(2).len(), 2);
+        assert_eq!(base_factor(3, 0, 2), 0.5);
+        assert_eq!(base_factor(3, 1, 2), base_factor(3, 1, 1));
+    }
+
+    #[test]
+    pub fn test_find_moduli() {
+        let search = small();
+        let results = find_moduli(&search).unwrap();
+        assert!(!results.is_empty() && results.len() <= search.shortlist);
+
+        for result in results.iter() {
+            let instance = &result.instance;
+            instance.validate().unwrap();
+            assert!(instance.modulus <= search.max_modulus);
+            assert_eq!(instance.bases(), [2, 3]);
+            assert_eq!(instance.candidates().len(), result.candidates());
+            assert!(result.candidates() > 0);
+        }
+        for pair in results.windows(2) {
+            assert!(pair[0].expected_solutions >= pair[1].expected_solutions);
+        }
+
+        // a bigger shortlist only adds moduli
+        let more = find_moduli(&ModulusSearch { shortlist: 32, ..small() }).unwrap();
+        assert!(results.iter().all(|result| more.contains(result)));
+
+        assert!(find_moduli(&ModulusSearch { bases: Vec::new(), ..small() }).is_err());
+        assert!(find_moduli(&ModulusSearch { max_exponent: 0, ..small() }).is_err());
+    }
+}
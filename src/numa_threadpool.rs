@@ -1,42 +1,554 @@
 // numa_threadpool.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::topology::{self, Topology};
+
+/// A task that panicked, as reported by `ThreadPool::join` or `TaskHandle::join`
+pub struct TaskPanic {
+    /// the task's index, counting every task submitted to the pool from 0
+    pub task: usize,
+    /// what the task panicked with
+    pub payload: Box<dyn Any + Send>
+}
+
+impl TaskPanic {
+    /// the panic message, if the task panicked with a string (as `panic!` does)
+    pub fn message(&self) -> Option<&str> {
+        if let Some(message) = self.payload.downcast_ref::<&str>() {
+            return Some(message);
+        }
+
+        self.payload.downcast_ref::<String>().map(|message| &message[..])
+    }
+}
+
+impl fmt::Display for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "task {} panicked: {}", self.task, message),
+            None => write!(f, "task {} panicked", self.task)
+        }
+    }
+}
+
+impl fmt::Debug for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskPanic").field("task", &self.task).field("message", &self.message()).finish()
+    }
+}
+
+impl std::error::Error for TaskPanic {}
+
+/// The result of a task started with `ThreadPool::submit`
+pub struct TaskHandle<T> {
+    task: usize,
+    result: Receiver<Result<T, TaskPanic>>
+}
+
+impl<T> TaskHandle<T> {
+    /// the task's index in the pool
+    pub fn task(&self) -> usize {
+        self.task
+    }
+
+    /// waits for the task to finish, and returns what it returned or what it panicked with
+    pub fn join(self) -> Result<T, TaskPanic> {
+        let task = self.task;
+
+        self.result.recv().unwrap_or_else(|_| Err(TaskPanic {
+            task,
+            payload: Box::new("task was dropped without running")
+        }))
+    }
+}
+
+/// Which hardware threads of a core get a worker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SmtPolicy {
+    /// a worker on every hardware thread
+    Hyperthreads,
+    /// a worker on one hardware thread (the lowest numbered usable one) of each physical core
+    PhysicalCores
+}
+
+/// Which CPUs the workers run on
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    /// at most this many workers on each node
+    pub threads_per_node: Option<usize>,
+    /// at most this many workers in all, taken evenly from the nodes
+    pub threads: Option<usize>,
+    /// CPUs to leave alone, e.g. for other services on the machine
+    pub exclude_cpus: Vec<u32>,
+    pub smt: SmtPolicy,
+    /// only use CPUs in the process's affinity mask (as set by taskset, cpusets, etc.)
+    pub respect_affinity: bool,
+    /// use no more workers than our cgroup's CPU quota, rounded up
+    pub respect_quota: bool
+}
+
+impl Default for PoolConfig {
+    /// every CPU we're allowed to use, within the cgroup quota
+    fn default() -> Self {
+        PoolConfig {
+            threads_per_node: None,
+            threads: None,
+            exclude_cpus: Vec::new(),
+            smt: SmtPolicy::Hyperthreads,
+            respect_affinity: true,
+            respect_quota: true
+        }
+    }
+}
+
+impl PoolConfig {
+    /// `topology`, with each node cut down to the CPUs this config puts a worker on, and the
+    /// nodes left with none dropped
+    pub fn select_cpus(&self, topology: &Topology) -> Topology {
+        let allowed = if self.respect_affinity { topology::process_affinity() } else { None };
+        let quota = if self.respect_quota { topology::cgroup_cpu_quota(&topology::sysfs_root()) } else { None };
+
+        self.select_cpus_within(topology, allowed.as_deref(), quota)
+    }
+
+    /// `select_cpus`, given the affinity mask and CPU quota to respect. Panics if that
+    /// leaves no CPU at all, since nothing would ever run.
+    pub fn select_cpus_within(&self, topology: &Topology, allowed: Option<&[u32]>, quota: Option<f64>) -> Topology {
+        let usable = |cpu: &u32| {
+            !self.exclude_cpus.contains(cpu) && allowed.is_none_or(|allowed| allowed.contains(cpu))
+        };
+
+        let mut selected = topology.clone();
+        for node in selected.nodes.iter_mut() {
+            let mut cpus: Vec<u32> = match self.smt {
+                SmtPolicy::Hyperthreads => node.cpus.iter().copied().filter(usable).collect(),
+                SmtPolicy::PhysicalCores => node.cores.iter()
+                    .filter_map(|core| core.iter().copied().find(usable))
+                    .collect()
+            };
+
+            if let Some(limit) = self.threads_per_node {
+                cpus.truncate(limit);
+            }
+
+            node.cores = node.cores.iter()
+                .map(|core| core.iter().copied().filter(|cpu| cpus.contains(cpu)).collect::<Vec<_>>())
+                .filter(|core| !core.is_empty())
+                .collect();
+            node.cpus = cpus;
+        }
+
+        // take workers away from whichever node has the most, so the nodes stay balanced
+        let quota = quota.map(|quota| (quota.ceil() as usize).max(1));
+        if let Some(limit) = quota.into_iter().chain(self.threads).min() {
+            while selected.cpu_count() > limit {
+                // on a tie, the highest numbered node
+                let busiest = selected.nodes.iter_mut().max_by_key(|node| node.cpus.len()).unwrap();
+                let cpu = busiest.cpus.pop().unwrap();
+
+                busiest.cores.iter_mut().for_each(|core| core.retain(|&other| other != cpu));
+                busiest.cores.retain(|core| !core.is_empty());
+            }
+        }
+
+        if selected.cpu_count() == 0 {
+            panic!("No CPUs left to run on with {:?}", self);
+        }
+
+        // a node without workers gets no context or replica; its distances go with it
+        let kept: Vec<bool> = selected.nodes.iter().map(|node| !node.cpus.is_empty()).collect();
+        selected.nodes.retain(|node| !node.cpus.is_empty());
+        for node in selected.nodes.iter_mut() {
+            node.distances = node.distances.iter().zip(kept.iter())
+                .filter(|(_, kept)| **kept)
+                .map(|(distance, _)| *distance)
+                .collect();
+        }
+
+        selected
+    }
+}
+
+/// splits `range` into `n_tasks` consecutive pieces of equal size, with the last one
+/// taking whatever doesn't divide evenly
+pub fn split_range(range: Range<u64>, n_tasks: u64) -> Vec<Range<u64>> {
+    let per_task = (range.end - range.start) / n_tasks;
+
+    (0..n_tasks).map(|task| {
+        let start = range.start + task * per_task;
+        let end = if task == n_tasks - 1 { range.end } else { start + per_task };
+
+        start..end
+    }).collect()
+}
+
+/// The panics caught in a pool's tasks. Only the one from the lowest numbered task is kept.
+struct Panics {
+    first: Mutex<Option<TaskPanic>>,
+    count: Mutex<usize>
+}
+
+impl Panics {
+    fn new() -> Self {
+        Panics { first: Mutex::new(None), count: Mutex::new(0) }
+    }
+
+    /// runs task number `task`, catching and recording a panic instead of letting it take
+    /// down the worker
+    fn run(&self, task: usize, f: impl FnOnce()) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            *self.count.lock().unwrap() += 1;
+
+            let mut first = self.first.lock().unwrap();
+            if first.as_ref().is_none_or(|first| task < first.task) {
+                *first = Some(TaskPanic { task, payload });
+            }
+        }
+    }
+
+    /// the first panic, if any task panicked
+    fn take(&self) -> Option<TaskPanic> {
+        let count = *self.count.lock().unwrap();
+        let first = self.first.lock().unwrap().take()?;
+
+        if count > 1 {
+            println!("Warning: {} tasks panicked, reporting the first", count);
+        }
+
+        Some(first)
+    }
+}
+
+/// wraps `task` so that its result (or panic) goes to the returned handle. If the handle is
+/// gone by then, a panic is passed on to the pool instead, so it still shows up in `join`.
+fn with_handle<'a, Context, T: Send + 'a>(
+    task_idx: usize,
+    task: impl FnOnce(&Context)->T + Send + 'a
+) -> (impl FnOnce(&Context) + Send + 'a, TaskHandle<T>) {
+    let (tx, rx) = channel();
+
+    let wrapped = move |context: &Context| {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| task(context)))
+            .map_err(|payload| TaskPanic { task: task_idx, payload });
+
+        if let Err(unsent) = tx.send(result) {
+            if let Err(panic) = unsent.0 {
+                panic::resume_unwind(panic.payload);
+            }
+        }
+    };
+
+    (wrapped, TaskHandle { task: task_idx, result: rx })
+}
+
+/// Counts a pool's tasks that haven't finished yet, so `join` can wait for just those
+struct Outstanding {
+    count: Mutex<usize>,
+    done: Condvar
+}
+
+impl Outstanding {
+    fn new() -> Self {
+        Outstanding { count: Mutex::new(0), done: Condvar::new() }
+    }
+
+    fn add(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn done(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+
+        if *count == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.done.wait(count).unwrap();
+        }
+    }
+}
+
+/// waits on each of `handles` in order, stopping at the first panic
+fn join_handles<T>(handles: Vec<TaskHandle<T>>) -> Result<Vec<T>, TaskPanic> {
+    handles.into_iter().map(TaskHandle::join).collect()
+}
+
+/// Tasks started through a `Scope` may borrow anything that outlives the call to
+/// `ThreadPool::scope`, which doesn't return until all of them have finished.
+/// They still run with the context of the node that picks them up.
+pub struct Scope<'scope, Context: Send + Sync + 'static> {
+    pool: &'scope ThreadPool<Context>,
+    outstanding: Arc<Outstanding>,
+    /// invariant, so tasks can't borrow anything that lives for less than all of 'scope
+    scope: PhantomData<&'scope mut &'scope ()>
+}
+
+/// marks a scoped task finished when dropped, even if the task panicked
+struct ScopedTask(Arc<Outstanding>);
+
+impl Drop for ScopedTask {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+impl<Context: Send + Sync + 'static> ThreadPool<Context> {
+    /// Runs `f` with a `Scope` for starting tasks that borrow from the caller, and waits for
+    /// every one of them to finish before returning. A panicking task is reported as usual,
+    /// by its handle or by `join`.
+    pub fn scope<'scope, T>(&'scope self, f: impl FnOnce(&Scope<'scope, Context>) -> T) -> T {
+        let scope = Scope { pool: self, outstanding: Arc::new(Outstanding::new()), scope: PhantomData };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // even if `f` panicked, its tasks may still be using what they borrowed
+        scope.outstanding.wait();
+
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+}
+
+impl<'scope, Context: Send + Sync + 'static> Scope<'scope, Context> {
+    /// hands `task` to the pool as task number `task_idx`
+    fn execute_indexed(&self, task_idx: usize, task: impl FnOnce(&Context) + Send + 'scope) {
+        let finished = ScopedTask(self.outstanding.clone());
+        self.outstanding.add();
+
+        let task: Box<dyn FnOnce(&Context) + Send + 'scope> = Box::new(move |context: &Context| {
+            let _finished = finished;
+            // calling `task` consumes it, so whatever it borrowed is released before `_finished`
+            task(context);
+        });
+
+        // Safety: `ThreadPool::scope` waits for `_finished` to drop before 'scope can end
+        let task: Box<dyn FnOnce(&Context) + Send + 'static> = unsafe { std::mem::transmute(task) };
+        self.pool.execute_indexed(task_idx, task);
+    }
+
+    pub fn execute(&self, task: impl FnOnce(&Context) + Send + 'scope) {
+        self.execute_indexed(self.pool.next_task(), task);
+    }
+
+    /// runs `task`, returning a handle to wait for its result
+    pub fn submit<T: Send + 'scope>(&self, task: impl FnOnce(&Context)->T + Send + 'scope) -> TaskHandle<T> {
+        let task_idx = self.pool.next_task();
+        let (task, handle) = with_handle(task_idx, task);
+
+        self.execute_indexed(task_idx, task);
+        handle
+    }
+
+    /// runs `kernel` on each of `ranges` in parallel, and returns the results in the same order
+    pub fn map_ranges<T: Send + 'scope>(
+        &self,
+        ranges: impl IntoIterator<Item = Range<u64>>,
+        kernel: impl Fn(&Context, Range<u64>)->T + Send + Sync + 'scope
+    ) -> Result<Vec<T>, TaskPanic> {
+        // shared rather than borrowed: after a panic we return without waiting for the rest
+        let kernel = Arc::new(kernel);
+        let handles = ranges.into_iter().map(|range| {
+            let kernel = kernel.clone();
+            self.submit(move |context| kernel(context, range))
+        }).collect();
+
+        join_handles(handles)
+    }
+}
+
 #[cfg(not(feature = "numa"))]
 pub use self::simple::*;
 
 mod simple {
     use threadpool;
+    use crate::topology::Topology;
+    use super::{Outstanding, Panics, PoolConfig, TaskPanic, TaskHandle};
+    use std::ops::Range;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::marker::Send;
 
+    /// The worker threads, which live as long as any clone of this (or any pool
+    /// installed on it) does
+    #[derive(Clone)]
+    pub struct Workers {
+        pool: threadpool::ThreadPool,
+        cpus: Arc<Vec<u32>>
+    }
+
+    impl Default for Workers {
+        fn default() -> Self {
+            Workers::new()
+        }
+    }
+
+    impl Workers {
+        pub fn new() -> Self {
+            Workers::with_config(&PoolConfig::default())
+        }
+
+        /// one (unpinned) worker for each CPU `config` selects
+        pub fn with_config(config: &PoolConfig) -> Self {
+            let topology = config.select_cpus(&Topology::discover());
+            println!("[workers] {} workers", topology.cpu_count());
+
+            Workers::with_topology(&topology)
+        }
+
+        /// one worker for each CPU in `topology`; the simple pool doesn't pin them,
+        /// and runs everything as node 0
+        pub fn with_topology(topology: &Topology) -> Self {
+            let cpus: Vec<u32> = topology.nodes.iter().flat_map(|node| node.cpus.iter().copied()).collect();
+            let pool = threadpool::ThreadPool::new(cpus.len().max(1));
+
+            Workers { pool, cpus: Arc::new(cpus) }
+        }
+
+        /// starts a phase of work on these workers, with the single context `context_ctor(0)`
+        pub fn install<Context>(&self, context_ctor: impl Fn(u32)->Context) -> ThreadPool<Context>
+            where Context: Send + Sync + 'static
+        {
+            ThreadPool {
+                workers: self.clone(),
+                context: Arc::new(context_ctor(0)),
+                next_task: AtomicUsize::new(0),
+                panics: Arc::new(Panics::new()),
+                outstanding: Arc::new(Outstanding::new())
+            }
+        }
+
+        pub fn node_ids(&self) -> Vec<u32> {
+            vec![0]
+        }
+
+        /// the CPUs there's a worker for; the simple pool doesn't pin them, though
+        pub fn node_cpus(&self, node_id: u32) -> Option<&[u32]> {
+            if node_id == 0 { Some(&self.cpus) } else { None }
+        }
+
+        /// the number of worker threads
+        pub fn threads(&self) -> usize {
+            self.cpus.len().max(1)
+        }
+    }
+
     pub struct ThreadPool<Context> {
+        workers: Workers,
         context: Arc<Context>,
-        pool: threadpool::ThreadPool
+        next_task: AtomicUsize,
+        panics: Arc<Panics>,
+        outstanding: Arc<Outstanding>
     }
 
     impl<Context> ThreadPool<Context>
         where Context: Send + Sync + 'static
     {
+        /// starts a pool with workers of its own
         pub fn new(context_ctor: impl Fn(u32)->Context) -> Self {
-            let context = context_ctor(0);
+            Workers::new().install(context_ctor)
+        }
 
-            ThreadPool { context: Arc::new(context), pool: threadpool::ThreadPool::default() }
+        /// the simple pool ignores the topology, and runs everything as node 0
+        pub fn with_topology(topology: &Topology, context_ctor: impl Fn(u32)->Context) -> Self {
+            Workers::with_topology(topology).install(context_ctor)
+        }
+
+        pub(super) fn next_task(&self) -> usize {
+            self.next_task.fetch_add(1, Ordering::Relaxed)
         }
 
         pub fn execute<Task>(&self, task: Task)
-            where Task: Fn(&Context)->() + Send + 'static
+            where Task: FnOnce(&Context) + Send + 'static
         {
+            self.execute_indexed(self.next_task(), task);
+        }
+
+        pub(super) fn execute_indexed(&self, task_idx: usize, task: impl FnOnce(&Context) + Send + 'static) {
             let context = self.context.clone();
-            self.pool.execute(move|| task(&context));
+            let panics = self.panics.clone();
+            let outstanding = self.outstanding.clone();
+
+            outstanding.add();
+            self.workers.pool.execute(move|| {
+                panics.run(task_idx, || task(&context));
+
+                // let go of the context before `join` can look for it
+                drop(context);
+                outstanding.done();
+            });
+        }
+
+        /// runs `task` on any node, same as `execute`
+        pub fn execute_on_any(&self, task: impl FnOnce(&Context) + Send + 'static) {
+            self.execute(task);
+        }
+
+        /// everything runs as node 0 here, so this is `execute` for any node
+        pub fn execute_on_node(&self, _node_id: u32, task: impl FnOnce(&Context) + Send + 'static) {
+            self.execute(task);
         }
 
-        pub fn join(self) -> Vec<(u32, Context)> {
-            self.pool.join();
+        /// runs `task` on any node, returning a handle to wait for its result
+        pub fn submit<T: Send + 'static>(&self, task: impl FnOnce(&Context)->T + Send + 'static) -> TaskHandle<T> {
+            let task_idx = self.next_task();
+            let (task, handle) = super::with_handle(task_idx, task);
+
+            self.execute_indexed(task_idx, task);
+            handle
+        }
+
+        /// runs `kernel` on each of `ranges` in parallel, and returns the results in the same order
+        pub fn map_ranges<T: Send + 'static>(
+            &self,
+            ranges: impl IntoIterator<Item = Range<u64>>,
+            kernel: impl Fn(&Context, Range<u64>)->T + Send + Sync + 'static
+        ) -> Result<Vec<T>, TaskPanic> {
+            let kernel = Arc::new(kernel);
+            let handles = ranges.into_iter().map(|range| {
+                let kernel = kernel.clone();
+                self.submit(move |context| kernel(context, range))
+            }).collect();
+
+            super::join_handles(handles)
+        }
+
+        pub fn node_ids(&self) -> Vec<u32> {
+            self.workers.node_ids()
+        }
+
+        pub fn node_cpus(&self, node_id: u32) -> Option<&[u32]> {
+            self.workers.node_cpus(node_id)
+        }
+
+        pub fn threads(&self) -> usize {
+            self.workers.threads()
+        }
+
+        /// waits for every task submitted to this pool to finish, then hands back the context,
+        /// or the first task that panicked. The workers carry on, ready for the next pool.
+        pub fn join(self) -> Result<Vec<(u32, Context)>, TaskPanic> {
+            self.outstanding.wait();
+
+            if let Some(panic) = self.panics.take() {
+                return Err(panic);
+            }
+
             let context = Arc::try_unwrap(self.context).unwrap_or_else(move|_|
                 panic!("Some threads didn't exit somehow")
             );
 
-            return vec![(0, context)];
+            return Ok(vec![(0, context)]);
         }
 
     }
@@ -47,204 +559,406 @@ pub use self::numa::*;
 
 #[cfg(feature = "numa")]
 mod numa {
-    extern crate nix;
-
-    use libc::{c_char, c_uint, c_int, c_ulong};
-    use std::ffi::CString;
+    use std::io;
     use std::sync::{Arc, Mutex, Condvar};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::thread::{JoinHandle, self};
     use std::collections::VecDeque;
     use std::marker::Send;
 
-    #[repr(C)]
-    struct bitmask { 
-        size: c_ulong, // in bits
-        words: *mut c_ulong
+    use crate::topology::Topology;
+    use super::{Outstanding, Panics, PoolConfig, TaskPanic, TaskHandle};
+    use std::ops::Range;
+
+    /// a queued task; it's handed the index of the node whose worker runs it
+    type Job = Box<dyn FnOnce(usize) + Send + 'static>;
+
+    struct NodeInfo {
+        node_id: u32,
+        cpuset: Vec<u32>
     }
 
-    #[link(name="numa")]
-    extern {
-        fn numa_available() -> i32;
+    /// The queues of a single node: tasks any node may run, and tasks only this node may run
+    struct NodeQueue {
+        shared: Mutex<VecDeque<Job>>,
+        pinned: Mutex<VecDeque<Job>>,
+        /// pinned tasks queued but not yet taken by a worker
+        pinned_pending: AtomicUsize
+    }
 
-        fn numa_allocate_cpumask() -> *mut bitmask;
-        fn numa_bitmask_free(bitmask: *mut bitmask);
-        fn numa_allocate_nodemask() -> *mut bitmask;
+    /// One pair of FIFO queues per node. Tasks submitted for any node are dealt out to the
+    /// nodes round-robin; a worker runs the oldest task pinned to its node, then the oldest
+    /// shared task on its node, and only when both are empty takes the oldest shared task of
+    /// the nearest node that has one. Pinned tasks are never stolen. Idle workers sleep on a
+    /// single condvar until more work arrives or the workers are shut down.
+    struct WorkQueues {
+        queues: Vec<NodeQueue>,
+        /// shared tasks queued but not yet taken by a worker
+        pending: AtomicUsize,
+        next_queue: AtomicUsize,
+        joining: AtomicBool,
+        sleep: Mutex<()>,
+        wake: Condvar
+    }
 
-        fn numa_bitmask_clearall(bitmask: *mut bitmask);
-        fn numa_sched_setaffinity(tid: c_uint, bitmask: *mut bitmask) -> c_int;
+    impl WorkQueues {
+        fn new(n_queues: usize) -> Self {
+            WorkQueues {
+                queues: (0..n_queues).map(|_| NodeQueue {
+                    shared: Mutex::new(VecDeque::new()),
+                    pinned: Mutex::new(VecDeque::new()),
+                    pinned_pending: AtomicUsize::new(0)
+                }).collect(),
+                pending: AtomicUsize::new(0),
+                next_queue: AtomicUsize::new(0),
+                joining: AtomicBool::new(false),
+                sleep: Mutex::new(()),
+                wake: Condvar::new()
+            }
+        }
 
-        fn numa_bitmask_isbitset(bitmask: *const bitmask, n: c_uint) -> bool;
-        fn numa_bitmask_setbit(bitmask: *mut bitmask, n: c_uint);
-        fn numa_bitmask_clearbit(bitmask: *mut bitmask, n: c_uint);
-        fn numa_bitmask_nbytes(bitmask: *mut bitmask) -> c_uint;
+        fn push(&self, job: Job) {
+            let queue = self.next_queue.fetch_add(1, Ordering::Relaxed) % self.queues.len();
 
-        fn numa_node_to_cpus(node: c_uint, bitmask: *mut bitmask) -> c_int;
-        fn numa_bitmask_weight(bitmask: *const bitmask) -> c_uint;
+            self.queues[queue].shared.lock().unwrap().push_back(job);
+            self.pending.fetch_add(1, Ordering::SeqCst);
 
-        fn numa_num_possible_nodes() -> c_uint;
+            // taking the lock orders this with a worker deciding to go to sleep,
+            // so it either sees the new task or gets woken up
+            let _guard = self.sleep.lock().unwrap();
+            self.wake.notify_one();
+        }
 
-        fn numa_error(desc: *const c_char);
+        fn push_pinned(&self, queue: usize, job: Job) {
+            let queue = &self.queues[queue];
 
-        static numa_all_nodes_ptr: *const bitmask;
-        static numa_all_cpus_ptr: *const bitmask;
-    }
+            queue.pinned.lock().unwrap().push_back(job);
+            queue.pinned_pending.fetch_add(1, Ordering::SeqCst);
 
-    struct Join {}
-    type Task<Context> = Box<dyn Fn(&Context)->() + Send + 'static>;
+            // only this node's workers can take it, and we can't pick them out of the sleepers
+            let _guard = self.sleep.lock().unwrap();
+            self.wake.notify_all();
+        }
 
-    struct NodeInfo<Context> {
-        node_id: c_uint,
-        cpuset: Vec<c_uint>,
-        context: Context
-    }
+        /// takes the next task for a worker on the node owning `order[0]`;
+        /// `order` lists the queue indices to look at, nearest first
+        fn take(&self, order: &[usize]) -> Option<Job> {
+            let home = &self.queues[order[0]];
+            if let Some(job) = home.pinned.lock().unwrap().pop_front() {
+                home.pinned_pending.fetch_sub(1, Ordering::SeqCst);
+                return Some(job);
+            }
 
-    struct WorkQueue<Context> {
-        queue: Mutex<VecDeque<Result<Task<Context>, Join>>>,
-        cvar : Condvar
-    }
+            for &idx in order.iter() {
+                if let Some(job) = self.queues[idx].shared.lock().unwrap().pop_front() {
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    return Some(job);
+                }
+            }
 
-    impl<Context: 'static> WorkQueue<Context> {
-        fn new() -> Self {
-            WorkQueue { queue: Mutex::new(VecDeque::new()), cvar: Condvar::new() }
+            None
         }
 
-        fn poll(&self) -> Result<Task<Context>, Join> {
-            let mut guard = self.queue.lock().unwrap();
+        /// blocks until there is a task to run, or returns None once the workers are
+        /// shutting down and every queue is empty
+        fn poll(&self, order: &[usize]) -> Option<Job> {
+            loop {
+                if let Some(job) = self.take(order) {
+                    return Some(job);
+                }
 
-            while guard.is_empty() {
-                guard = self.cvar.wait(guard).unwrap();
-            }
+                let guard = self.sleep.lock().unwrap();
+                if self.pending.load(Ordering::SeqCst) > 0
+                    || self.queues[order[0]].pinned_pending.load(Ordering::SeqCst) > 0
+                {
+                    // another worker may be about to take it, but it might be ours to steal
+                    continue;
+                }
+                if self.joining.load(Ordering::SeqCst) {
+                    return None;
+                }
 
-            let head = guard.pop_front().unwrap();
-            if let Err(_) = &head {
-                guard.push_front(Err(Join{}));
+                drop(self.wake.wait(guard).unwrap());
             }
-            head
         }
 
-        fn push(&self, task: Task<Context>) {
-            let mut guard = self.queue.lock().unwrap();
+        fn kill(&self) {
+            self.joining.store(true, Ordering::SeqCst);
+
+            let _guard = self.sleep.lock().unwrap();
+            self.wake.notify_all();
+        }
+    }
+
+    /// restricts the calling thread to run only on `cpu_id`
+    fn pin_to_cpu(cpu_id: u32) -> io::Result<()> {
+        use libc::{cpu_set_t, CPU_SET, CPU_SETSIZE, CPU_ZERO, sched_setaffinity};
+        use std::mem;
+
+        if cpu_id as usize >= CPU_SETSIZE as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU number out of range"));
+        }
+
+        unsafe {
+            let mut cpuset: cpu_set_t = mem::zeroed();
+            CPU_ZERO(&mut cpuset);
+            CPU_SET(cpu_id as usize, &mut cpuset);
 
-            if let Some(Err(_)) = guard.front() {
-                panic!("Tried to add to the queue while joining")
+            if sched_setaffinity(0, mem::size_of::<cpu_set_t>(), &cpuset) != 0 {
+                return Err(io::Error::last_os_error());
             }
+        }
 
-            guard.push_back(Ok(task));
-            self.cvar.notify_one();
+        Ok(())
+    }
+
+    fn worker(node_id: u32, cpu_id: u32, queues: &WorkQueues, order: &[usize]) {
+        // an unpinned worker is slower, not wrong; this also lets a made-up topology
+        // describe CPUs that this machine doesn't have
+        if let Err(err) = pin_to_cpu(cpu_id) {
+            println!("Warning: couldn't pin worker to CPU {} on node {}: {}; running unpinned",
+                cpu_id, node_id, err);
         }
 
-        fn kill(&self) {
-            let mut guard = self.queue.lock().unwrap();
-            guard.push_back(Err(Join{}));
-            self.cvar.notify_all();
+        while let Some(job) = queues.poll(order) {
+            job(order[0]);
         }
     }
 
-    pub struct ThreadPool<Context: 'static + Send> {
-        nodes: Vec<Arc<NodeInfo<Context>>>,
-        queue: Arc<WorkQueue<Context>>,
+    struct WorkerThreads {
+        nodes: Vec<NodeInfo>,
+        queues: Arc<WorkQueues>,
         threads: Vec<JoinHandle<()>>
     }
 
-    fn worker<Context: 'static>(node: &NodeInfo<Context>, cpu_id: c_uint, queue: &WorkQueue<Context>) {
-        use self::nix::unistd::gettid;
-        use libc::pid_t;
+    impl Drop for WorkerThreads {
+        /// lets the workers finish whatever is still queued, then waits for them to exit
+        fn drop(&mut self) {
+            self.queues.kill();
 
-        let tid = pid_t::from(gettid());
-        unsafe {
-            let bitmask = numa_allocate_cpumask();
-            numa_bitmask_clearall(bitmask);
-            numa_bitmask_setbit(bitmask, cpu_id);
-            let rv = numa_sched_setaffinity(tid as c_uint, bitmask);
-            if 0 != rv {
-                let mesg = CString::new("setaffinity").unwrap();
-                numa_error(mesg.as_ptr());
-            }
-            numa_bitmask_free(bitmask);
-            if 0 != rv {
-                panic!();
+            let current = thread::current().id();
+            for thread in self.threads.drain(..) {
+                // the last reference may be dropped by a task, on one of our own workers
+                if thread.thread().id() != current {
+                    thread.join().unwrap();
+                }
             }
         }
+    }
 
-        loop {
-            match queue.poll() {
-                Err(_) => return,
-                Ok(task) => task(&node.context)
-            }
+    /// The worker threads: one pinned to each CPU of each node of the topology. They live
+    /// as long as any clone of this (or any pool installed on it) does, so that every phase
+    /// of a search can run on the same threads.
+    #[derive(Clone)]
+    pub struct Workers {
+        inner: Arc<WorkerThreads>
+    }
+
+    impl Default for Workers {
+        fn default() -> Self {
+            Workers::new()
         }
     }
 
-    fn build_nodes<Context>(context_ctor: impl Fn(u32)->Context) -> Vec<Arc<NodeInfo<Context>>> {
-        let mut nodes = Vec::new();
+    impl Workers {
+        pub fn new() -> Self {
+            Workers::with_config(&PoolConfig::default())
+        }
 
-        let max = unsafe { numa_num_possible_nodes() };
-        for i in 0..max {
-            if unsafe { numa_bitmask_isbitset(numa_all_nodes_ptr, i) } {
-                let mut info = NodeInfo { node_id: (i as c_uint), cpuset: Vec::new(), context: context_ctor(i) };
+        /// a worker pinned to each CPU `config` selects
+        pub fn with_config(config: &PoolConfig) -> Self {
+            let topology = config.select_cpus(&Topology::discover());
+            let per_node: Vec<String> = topology.nodes.iter()
+                .map(|node| format!("{} on node {}", node.cpus.len(), node.id))
+                .collect();
+            println!("[workers] {} workers: {}", topology.cpu_count(), per_node.join(", "));
 
-                let  bitmask = unsafe { numa_allocate_cpumask() };
-                if unsafe { numa_node_to_cpus(i, bitmask) } != 0 {
-                    panic!("numa_node_to_cpus");
-                }
-                let size = unsafe { numa_bitmask_nbytes(bitmask) } * 8;
+            Workers::with_topology(&topology)
+        }
+
+        pub fn with_topology(topology: &Topology) -> Self {
+            let nodes: Vec<NodeInfo> = topology.nodes.iter()
+                .map(|node| NodeInfo { node_id: node.id, cpuset: node.cpus.clone() })
+                .collect();
+            let queues = Arc::new(WorkQueues::new(nodes.len()));
+            let mut threads = Vec::new();
 
-                for cpu in 0..size {
-                    if unsafe {numa_bitmask_isbitset(bitmask, cpu)} {
-                        info.cpuset.push(cpu as c_uint);
-                    }
+            for (idx, node) in nodes.iter().enumerate() {
+                // the queues are in the same order as the topology's nodes
+                let order: Vec<usize> = std::iter::once(idx)
+                    .chain(topology.nearest_nodes(node.node_id).iter()
+                        .map(|id| topology.nodes.iter().position(|other| other.id == *id).unwrap()))
+                    .collect();
+
+                for cpu in node.cpuset.iter() {
+                    let node_id = node.node_id;
+                    let cpu2 = *cpu;
+                    let queues = queues.clone();
+                    let order = order.clone();
+
+                    threads.push(thread::spawn(move ||
+                        worker(node_id, cpu2, &queues, &order)
+                    ));
                 }
+            }
 
-                unsafe {numa_bitmask_free(bitmask)};
+            Workers { inner: Arc::new(WorkerThreads { nodes, queues, threads }) }
+        }
+
+        /// starts a phase of work on these workers, with one context per node
+        pub fn install<Context>(&self, context_ctor: impl Fn(u32)->Context) -> ThreadPool<Context>
+            where Context: Send + Sync + 'static
+        {
+            let contexts = self.inner.nodes.iter()
+                .map(|node| (node.node_id, context_ctor(node.node_id)))
+                .collect();
 
-                nodes.push(Arc::new(info));
+            ThreadPool {
+                workers: self.clone(),
+                contexts: Arc::new(contexts),
+                next_task: AtomicUsize::new(0),
+                panics: Arc::new(Panics::new()),
+                outstanding: Arc::new(Outstanding::new())
             }
         }
 
-        nodes
+        /// the nodes there are workers for, in increasing order
+        pub fn node_ids(&self) -> Vec<u32> {
+            self.inner.nodes.iter().map(|node| node.node_id).collect()
+        }
+
+        /// the CPUs the workers for `node_id` run on, or None for an unknown node
+        pub fn node_cpus(&self, node_id: u32) -> Option<&[u32]> {
+            self.inner.nodes.iter().find(|node| node.node_id == node_id).map(|node| &node.cpuset[..])
+        }
+
+        /// the number of worker threads, on every node
+        pub fn threads(&self) -> usize {
+            self.inner.nodes.iter().map(|node| node.cpuset.len()).sum()
+        }
+    }
+
+    /// A phase of work on a set of `Workers`, with a context for each node
+    pub struct ThreadPool<Context: 'static + Send> {
+        workers: Workers,
+        /// in the same order as the workers' nodes
+        contexts: Arc<Vec<(u32, Context)>>,
+        next_task: AtomicUsize,
+        panics: Arc<Panics>,
+        outstanding: Arc<Outstanding>
     }
 
     impl<Context: 'static + Send + Sync> ThreadPool<Context> {
+        /// starts a pool with workers of its own
         pub fn new(context_ctor: impl Fn(u32)->Context) -> Self {
-            if unsafe { numa_available() } == -1 {
-                panic!("NUMA library unavailable");
-            }
+            Workers::new().install(context_ctor)
+        }
 
-            let nodes = build_nodes(context_ctor);
-            let queue = Arc::new(WorkQueue::new());
-            let mut threads = Vec::new();
+        /// creates a pool with one context per node of `topology`, and one worker
+        /// pinned to each of its CPUs
+        pub fn with_topology(topology: &Topology, context_ctor: impl Fn(u32)->Context) -> Self {
+            Workers::with_topology(topology).install(context_ctor)
+        }
 
-            for node in nodes.iter() {
-                for cpu in node.cpuset.iter() {
-                    let node = node.clone();
-                    let cpu2 = cpu.clone();
-                    let queue = queue.clone();
+        pub(super) fn next_task(&self) -> usize {
+            self.next_task.fetch_add(1, Ordering::Relaxed)
+        }
 
-                    threads.push(thread::spawn(move ||
-                        worker(&node, cpu2, &queue)
-                    ));
-                }
+        /// wraps `task` to run with the context of whichever node picks it up
+        fn job(&self, task_idx: usize, task: impl FnOnce(&Context) + Send + 'static) -> Job {
+            let contexts = self.contexts.clone();
+            let panics = self.panics.clone();
+            let outstanding = self.outstanding.clone();
+
+            outstanding.add();
+            Box::new(move |node_idx| {
+                panics.run(task_idx, || task(&contexts[node_idx].1));
+
+                // let go of the contexts before `join` can look for them
+                drop(contexts);
+                outstanding.done();
+            })
+        }
+
+        pub fn execute(&self, task: impl FnOnce(&Context) + Send + 'static) {
+            self.execute_indexed(self.next_task(), task);
+        }
+
+        pub(super) fn execute_indexed(&self, task_idx: usize, task: impl FnOnce(&Context) + Send + 'static) {
+            self.workers.inner.queues.push(self.job(task_idx, task));
+        }
+
+        /// runs `task` on any node, same as `execute`
+        pub fn execute_on_any(&self, task: impl FnOnce(&Context) + Send + 'static) {
+            self.execute(task);
+        }
+
+        /// runs `task` on any node, returning a handle to wait for its result
+        pub fn submit<T: Send + 'static>(&self, task: impl FnOnce(&Context)->T + Send + 'static) -> TaskHandle<T> {
+            let task_idx = self.next_task();
+            let (task, handle) = super::with_handle(task_idx, task);
+
+            self.execute_indexed(task_idx, task);
+            handle
+        }
+
+        /// runs `kernel` on each of `ranges` in parallel, and returns the results in the same order
+        pub fn map_ranges<T: Send + 'static>(
+            &self,
+            ranges: impl IntoIterator<Item = Range<u64>>,
+            kernel: impl Fn(&Context, Range<u64>)->T + Send + Sync + 'static
+        ) -> Result<Vec<T>, TaskPanic> {
+            let kernel = Arc::new(kernel);
+            let handles = ranges.into_iter().map(|range| {
+                let kernel = kernel.clone();
+                self.submit(move |context| kernel(context, range))
+            }).collect();
+
+            super::join_handles(handles)
+        }
+
+        /// runs `task` on one of `node_id`'s CPUs, with that node's context. Panics if the
+        /// pool has no workers on `node_id`, since the task could never run.
+        pub fn execute_on_node(&self, node_id: u32, task: impl FnOnce(&Context) + Send + 'static) {
+            let nodes = &self.workers.inner.nodes;
+            let idx = nodes.iter().position(|node| node.node_id == node_id)
+                .unwrap_or_else(|| panic!("No node {} in the thread pool", node_id));
+
+            if nodes[idx].cpuset.is_empty() {
+                panic!("Node {} has no CPUs to run tasks on", node_id);
             }
 
-            ThreadPool { nodes, queue, threads }
+            self.workers.inner.queues.push_pinned(idx, self.job(self.next_task(), task));
+        }
+
+        /// the nodes the pool has a context for, in increasing order
+        pub fn node_ids(&self) -> Vec<u32> {
+            self.workers.node_ids()
         }
 
-        pub fn execute(&self, task: impl Fn(&Context)->() + Send + 'static) {
-            self.queue.push(Box::new(task));
+        /// the CPUs the pool's workers for `node_id` run on, or None for an unknown node
+        pub fn node_cpus(&self, node_id: u32) -> Option<&[u32]> {
+            self.workers.node_cpus(node_id)
         }
 
-        pub fn join(self) -> Vec<(u32, Context)> {
-            self.queue.kill();
-            for thread in self.threads {
-                thread.join().unwrap();
+        pub fn threads(&self) -> usize {
+            self.workers.threads()
+        }
+
+        /// waits for every task submitted to this pool to finish, then hands back each node's
+        /// context, or the first task that panicked. The workers carry on, ready for the next pool.
+        pub fn join(self) -> Result<Vec<(u32, Context)>, TaskPanic> {
+            self.outstanding.wait();
+
+            if let Some(panic) = self.panics.take() {
+                return Err(panic);
             }
 
-            return self.nodes.into_iter().map(|arc| {
-                let info = Arc::try_unwrap(arc).unwrap_or_else(
-                    move|_| panic!("Threads didn't exit somehow")
-                );
+            let contexts = Arc::try_unwrap(self.contexts).unwrap_or_else(
+                move|_| panic!("Threads didn't exit somehow")
+            );
 
-                (info.node_id, info.context)
-            }).collect();
+            return Ok(contexts);
         }
 
     }
@@ -253,9 +967,382 @@ mod numa {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::topology::Topology;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     pub fn test() {
         ThreadPool::new(|_| ());
     }
+
+    #[test]
+    pub fn test_select_cpus() {
+        let root = crate::topology::test::fake_sysfs("select", 2, 4);
+        let topology = Topology::from_sysfs(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let cpus = |config: &PoolConfig, allowed: Option<&[u32]>, quota: Option<f64>| {
+            let selected = config.select_cpus_within(&topology, allowed, quota);
+            selected.nodes.iter().map(|node| node.cpus.clone()).collect::<Vec<_>>()
+        };
+        let all = PoolConfig::default();
+
+        assert_eq!(cpus(&all, None, None), vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]);
+
+        let exclude = PoolConfig { exclude_cpus: vec![1, 4], ..PoolConfig::default() };
+        assert_eq!(cpus(&exclude, None, None), vec![vec![0, 2, 3], vec![5, 6, 7]]);
+
+        let cores = PoolConfig { smt: SmtPolicy::PhysicalCores, ..PoolConfig::default() };
+        assert_eq!(cpus(&cores, None, None), vec![vec![0, 2], vec![4, 6]]);
+
+        // a core with its first thread excluded still gets a worker
+        let cores_exclude = PoolConfig { exclude_cpus: vec![0], ..cores.clone() };
+        assert_eq!(cpus(&cores_exclude, None, None), vec![vec![1, 2], vec![4, 6]]);
+
+        let capped = PoolConfig { threads_per_node: Some(1), ..PoolConfig::default() };
+        assert_eq!(cpus(&capped, None, None), vec![vec![0], vec![4]]);
+
+        assert_eq!(cpus(&all, Some(&[0, 1, 2, 5]), None), vec![vec![0, 1, 2], vec![5]]);
+        assert_eq!(cpus(&all, None, Some(2.5)), vec![vec![0, 1], vec![4]]);
+        // a node left without CPUs is dropped, distances and all
+        assert_eq!(cpus(&cores, None, Some(0.5)), vec![vec![0]]);
+        let selected = cores.select_cpus_within(&topology, None, Some(0.5));
+        assert_eq!(selected.node_ids(), vec![0]);
+        assert_eq!(selected.nodes[0].distances.len(), 1);
+        assert!(selected.nearest_nodes(0).is_empty());
+
+        let threads = PoolConfig { threads: Some(3), ..PoolConfig::default() };
+        assert_eq!(cpus(&threads, None, None), vec![vec![0, 1], vec![4]]);
+        assert_eq!(cpus(&threads, None, Some(1.5)), vec![vec![0], vec![4]]);
+
+        let selected = cores.select_cpus_within(&topology, None, None);
+        assert_eq!(selected.node(0).unwrap().cores, vec![vec![0], vec![2]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No CPUs left")]
+    pub fn test_select_no_cpus() {
+        let config = PoolConfig { exclude_cpus: vec![0, 1], ..PoolConfig::default() };
+        config.select_cpus_within(&Topology::fake(1, 2), None, None);
+    }
+
+    #[test]
+    pub fn test_with_config() {
+        // whatever this machine allows, there's at least one worker and it runs tasks
+        let config = PoolConfig { threads_per_node: Some(1), smt: SmtPolicy::PhysicalCores, ..PoolConfig::default() };
+        let workers = Workers::with_config(&config);
+
+        assert!(workers.node_ids().iter().all(|&node| workers.node_cpus(node).unwrap().len() <= 1));
+
+        let pool = workers.install(|_| ());
+        assert_eq!(pool.submit(|_| 42).join().unwrap(), 42);
+        pool.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_fake_topology() {
+        // more nodes and CPUs than the machine running the test is likely to have
+        let topology = Topology::fake(4, 2);
+        let pool = ThreadPool::with_topology(&topology, |_| AtomicUsize::new(0));
+
+        for _ in 0..1000 {
+            pool.execute(|counter| { counter.fetch_add(1, Ordering::Relaxed); });
+        }
+
+        let contexts = pool.join().unwrap();
+
+        #[cfg(feature = "numa")]
+        assert_eq!(contexts.iter().map(|(node, _)| *node).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        let total: usize = contexts.iter().map(|(_, counter)| counter.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    pub fn test_submission_order() {
+        let pool = ThreadPool::with_topology(&Topology::fake(1, 1), |_| Mutex::new(Vec::new()));
+
+        for i in 0..100 {
+            pool.execute(move |order| order.lock().unwrap().push(i));
+        }
+
+        let (_, order) = pool.join().unwrap().pop().unwrap();
+
+        #[cfg(feature = "numa")]
+        assert_eq!(order.into_inner().unwrap(), (0..100).collect::<Vec<_>>());
+
+        #[cfg(not(feature = "numa"))]
+        assert_eq!(order.into_inner().unwrap().len(), 100);
+    }
+
+    #[test]
+    pub fn test_node_queries() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 3), |_| ());
+
+        #[cfg(feature = "numa")]
+        {
+            assert_eq!(pool.node_ids(), vec![0, 1]);
+            assert_eq!(pool.node_cpus(1), Some(&[3, 4, 5][..]));
+        }
+
+        assert!(pool.node_ids().iter().all(|&node| pool.node_cpus(node).is_some()));
+        assert_eq!(pool.node_cpus(7), None);
+        pool.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_execute_on_node() {
+        let pool = ThreadPool::with_topology(&Topology::fake(3, 2), |node| (node, AtomicUsize::new(0)));
+
+        for node in pool.node_ids() {
+            for _ in 0..100 {
+                pool.execute_on_node(node, move |(context_node, counter)| {
+                    assert_eq!(*context_node, node);
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        }
+        pool.execute_on_any(|(_, counter)| { counter.fetch_add(1, Ordering::Relaxed); });
+
+        let contexts = pool.join().unwrap();
+        let total: usize = contexts.iter().map(|(_, (_, counter))| counter.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, contexts.len() * 100 + 1);
+
+        for (node, (_, counter)) in contexts.iter() {
+            assert!(counter.load(Ordering::Relaxed) >= 100, "node {}", node);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "numa")]
+    pub fn test_pinned_not_stolen() {
+        // node 1's worker is busy until node 0 has run all of its pinned tasks; if node 1
+        // stole any of them they'd run with node 1's context and be counted there
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 1), |_| AtomicUsize::new(0));
+        let done = std::sync::Arc::new(AtomicUsize::new(0));
+
+        let wait = done.clone();
+        pool.execute_on_node(1, move |_| {
+            while wait.load(Ordering::SeqCst) < 50 {
+                std::thread::yield_now();
+            }
+        });
+
+        for _ in 0..50 {
+            let done = done.clone();
+            pool.execute_on_node(0, move |counter| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let contexts = pool.join().unwrap();
+        assert_eq!(contexts[0].1.load(Ordering::Relaxed), 50);
+        assert_eq!(contexts[1].1.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "numa")]
+    #[should_panic(expected = "No node 5")]
+    pub fn test_execute_on_missing_node() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 1), |_| ());
+        pool.execute_on_node(5, |_| ());
+    }
+
+    #[test]
+    pub fn test_phases_share_workers() {
+        let workers = Workers::with_topology(&Topology::fake(2, 2));
+        let n_workers: usize = workers.node_ids().iter().map(|&node| workers.node_cpus(node).unwrap().len()).sum();
+        let seen = std::sync::Arc::new(Mutex::new(std::collections::HashSet::new()));
+
+        for phase in 0..3usize {
+            let pool = workers.install(move |node| (node, AtomicUsize::new(phase)));
+
+            for _ in 0..100 {
+                let seen = seen.clone();
+                pool.execute(move |(_, counter)| {
+                    seen.lock().unwrap().insert(std::thread::current().id());
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+
+            let contexts = pool.join().unwrap();
+            let total: usize = contexts.iter().map(|(_, (_, counter))| counter.load(Ordering::Relaxed)).sum();
+            assert_eq!(total, 100 + contexts.len() * phase);
+        }
+
+        // a different context type, on the same threads
+        let pool = workers.install(|node| format!("node {}", node));
+        assert!(pool.submit(|name: &String| name.starts_with("node")).join().unwrap());
+        pool.join().unwrap();
+
+        // no phase started threads of its own
+        assert!(seen.lock().unwrap().len() <= n_workers);
+    }
+
+    #[test]
+    pub fn test_scope_borrows() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 2), |_| AtomicUsize::new(0));
+        let values: Vec<u64> = (0..1000).collect();
+        let total = AtomicUsize::new(0);
+
+        pool.scope(|scope| {
+            for chunk in values.chunks(100) {
+                let total = &total;
+                scope.execute(move |counter| {
+                    total.fetch_add(chunk.iter().sum::<u64>() as usize, Ordering::Relaxed);
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        // no join needed to see the results
+        assert_eq!(total.load(Ordering::Relaxed), (0..1000).sum::<usize>());
+
+        let sums = pool.scope(|scope| {
+            scope.map_ranges(split_range(0..1000, 8), |_, range| values[range.start as usize..range.end as usize].iter().sum::<u64>())
+        }).unwrap();
+        assert_eq!(sums.iter().sum::<u64>(), values.iter().sum::<u64>());
+
+        let first = pool.scope(|scope| scope.submit(|_| values[0])).join().unwrap();
+        assert_eq!(first, 0);
+
+        let contexts = pool.join().unwrap();
+        assert_eq!(contexts.iter().map(|(_, counter)| counter.load(Ordering::Relaxed)).sum::<usize>(), 10);
+    }
+
+    #[test]
+    pub fn test_scope_waits_after_panic() {
+        let pool = ThreadPool::with_topology(&Topology::fake(1, 2), |_| ());
+        let finished = AtomicUsize::new(0);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                for _ in 0..10 {
+                    scope.execute(|_| {
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+                panic!("scope body gave up");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 10);
+
+        pool.scope(|scope| scope.execute(|_| panic!("scoped task gave up")));
+        assert_eq!(pool.join().unwrap_err().message(), Some("scoped task gave up"));
+    }
+
+    #[test]
+    pub fn test_submit() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 2), |node| node);
+
+        let handles: Vec<_> = (0..100u64).map(|i| pool.submit(move |_| i * i)).collect();
+        let node = pool.submit(|node| *node);
+
+        assert_eq!(handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>(),
+            (0..100u64).map(|i| i * i).collect::<Vec<_>>());
+        assert!(node.join().unwrap() < 2);
+        pool.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_map_ranges() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 2), |_| ());
+        let ranges = split_range(0..1003, 10);
+
+        assert_eq!(ranges.len(), 10);
+        assert_eq!(ranges[0], 0..100);
+        assert_eq!(ranges[9], 900..1003);
+
+        let sums = pool.map_ranges(ranges, |_, range| range.sum::<u64>()).unwrap();
+        assert_eq!(sums[1], (100..200).sum::<u64>());
+        assert_eq!(sums.iter().sum::<u64>(), (0..1003).sum::<u64>());
+
+        pool.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_panic_in_join() {
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 1), |_| AtomicUsize::new(0));
+
+        for i in 0..20 {
+            pool.execute(move |counter| {
+                if i == 7 {
+                    panic!("task {} gave up", i);
+                }
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        let panic = pool.join().unwrap_err();
+        assert_eq!(panic.task, 7);
+        assert_eq!(panic.message(), Some("task 7 gave up"));
+        assert_eq!(panic.to_string(), "task 7 panicked: task 7 gave up");
+    }
+
+    #[test]
+    pub fn test_panic_in_handle() {
+        let pool = ThreadPool::with_topology(&Topology::fake(1, 2), |_| ());
+
+        let fine = pool.submit(|_| 1);
+        let broken = pool.submit(|_| -> u32 { panic!("no result") });
+        assert_eq!(fine.join().unwrap(), 1);
+
+        let panic = broken.join().unwrap_err();
+        assert_eq!(panic.task, 1);
+        assert_eq!(panic.message(), Some("no result"));
+
+        // the handle took the panic, so the pool itself is fine
+        assert!(pool.join().is_ok());
+
+        let pool = ThreadPool::with_topology(&Topology::fake(1, 2), |_| ());
+        let result = pool.map_ranges(split_range(0..100, 4), |_, range| {
+            assert!(!range.contains(&60), "range {:?} failed", range);
+        });
+
+        assert_eq!(result.unwrap_err().task, 2);
+        pool.join().unwrap();
+
+        // nobody waited for this one, so the panic goes to the pool instead
+        let pool = ThreadPool::with_topology(&Topology::fake(1, 2), |_| ());
+        let dropped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let wait = dropped.clone();
+
+        drop(pool.submit(move |_| -> u32 {
+            while !wait.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            panic!("dropped")
+        }));
+        dropped.store(true, Ordering::SeqCst);
+
+        assert_eq!(pool.join().unwrap_err().message(), Some("dropped"));
+    }
+
+    #[test]
+    #[cfg(feature = "numa")]
+    pub fn test_stealing() {
+        // node 0's only worker is stuck in the first task until every other task has run,
+        // which can only happen if node 1 steals the rest of node 0's queue
+        let done = std::sync::Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::with_topology(&Topology::fake(2, 1), |_| ());
+
+        let stuck = done.clone();
+        pool.execute(move |_| {
+            while stuck.load(Ordering::SeqCst) < 99 {
+                std::thread::yield_now();
+            }
+        });
+
+        for _ in 1..100 {
+            let done = done.clone();
+            pool.execute(move |_| { done.fetch_add(1, Ordering::SeqCst); });
+        }
+
+        pool.join().unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 99);
+    }
 }
@@ -4,19 +4,24 @@
 use std::time::Instant;
 use std::sync::atomic::{Ordering, AtomicUsize};
 
+use crate::cancel::CancellationToken;
+
 pub struct ProgressReporter {
     desc: String,
     start_time: Instant,
     interval: AtomicUsize,
     counter: AtomicUsize,
-    total: usize
+    total: usize,
+    cancel: Option<CancellationToken>
 }
 
 pub struct ProgressHandle<'a> {
     reporter: &'a ProgressReporter,
     last_report: Instant,
     interval: usize,
-    local_counter: usize
+    local_counter: usize,
+    /// the reporter's token, as of the last push
+    cancelled: bool
 }
 
 impl<'a> Drop for ProgressHandle<'a> {
@@ -40,6 +45,14 @@ impl<'a> ProgressHandle<'a> {
         }
     }
 
+    /// true once the reporter's cancellation token has been cancelled. This only looks at
+    /// the token when the handle is created and when `report` pushes its count up, so
+    /// kernels can check it as often as they like.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
     #[cold]
     fn push(&mut self) {
         let elapsed = self.last_report.elapsed();
@@ -55,12 +68,19 @@ impl<'a> ProgressHandle<'a> {
         self.reporter.report_up(self.local_counter);
         self.local_counter = 0;
         self.last_report = Instant::now();
+        self.cancelled = self.reporter.is_cancelled();
     }
 }
 
 impl ProgressReporter {
     pub fn handle<'a>(&'a self) -> ProgressHandle<'a> {
-        ProgressHandle { reporter: self, last_report: Instant::now(), interval: 10000, local_counter: 0 }
+        ProgressHandle {
+            reporter: self,
+            last_report: Instant::now(),
+            interval: 10000,
+            local_counter: 0,
+            cancelled: self.is_cancelled()
+        }
     }
 
     pub fn new(desc: &str, total: usize) -> Self {
@@ -69,10 +89,22 @@ impl ProgressReporter {
             start_time: Instant::now(),
             interval: AtomicUsize::new(1000),
             counter: AtomicUsize::new(0),
-            total
+            total,
+            cancel: None
         }
     }
 
+    /// a reporter whose handles also pass on `cancel`
+    pub fn with_cancel(desc: &str, total: usize, cancel: &CancellationToken) -> Self {
+        let mut reporter = ProgressReporter::new(desc, total);
+        reporter.cancel = Some(cancel.clone());
+        reporter
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled())
+    }
+
     fn report_up(&self, count: usize) {
         let interval = self.interval.load(Ordering::Relaxed);
         let prior = self.counter.fetch_add(count, Ordering::Relaxed);
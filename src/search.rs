@@ -0,0 +1,722 @@
+// search.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// The whole search as a library call. `Search::new()` is the search from the paper, which
+// is what `main` runs; everything it uses (the candidate set R, the T1/T2 split, the
+// modulus, the filter, the thread pool, the final check) can be swapped out, and the
+// results come back in a `SearchReport` rather than only being printed.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bloomfilter::{Checkpoint, FilterConfig, PhaseContext, N_TASKS, bloom_t1, build_t2, final_sieve};
+use crate::cancel::{CancelReason, CancellationToken, Outcome};
+use crate::checkpoint::RunDir;
+use crate::magic_numbers::{INSTANCE, Pseudoprime, PseudoprimeBounds, PseudoprimeCheck, SizeFilter, R};
+use crate::modulus::{Modulus, OptiM, MODULUS, inverse};
+use crate::numa_threadpool::Workers;
+use crate::t2_map::{IndexKind, T2Index};
+
+/// the most elements either half can have: subsets are passed around as u32 masks
+pub const MAX_HALF: usize = 32;
+/// how often a search with a run directory saves a running phase's progress, by default
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The phases of the search, in the order they run
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// inserting the T1 subset products into the Bloom filter
+    BloomT1,
+    /// collecting the T2 subset products that pass the filter
+    T2Map,
+    /// matching T1 subset products against the T2 map, and checking the matches
+    FinalSieve
+}
+
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::BloomT1, Phase::T2Map, Phase::FinalSieve];
+
+    /// the name the phase reports its progress under
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::BloomT1 => "bloom_t1",
+            Phase::T2Map => "t2_map",
+            Phase::FinalSieve => "final_sieve"
+        }
+    }
+
+    fn index(&self) -> usize {
+        Phase::ALL.iter().position(|phase| phase == self).unwrap()
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How far one phase got
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhaseReport {
+    pub phase: Phase,
+    /// how long it ran for this time
+    pub elapsed: Duration,
+    /// the parts of its work that are done, including any done by an earlier run
+    pub completed: Vec<Range<u64>>,
+    pub total: Range<u64>,
+    /// why it stopped before covering `total`, if it did
+    pub cancelled: Option<CancelReason>,
+    /// true if it picked up from progress saved in the run directory
+    pub resumed: bool
+}
+
+impl PhaseReport {
+    fn new<T>(phase: Phase, outcome: &Outcome<T>, start: Instant, resumed: bool) -> Self {
+        PhaseReport {
+            phase,
+            elapsed: start.elapsed(),
+            completed: outcome.completed.clone(),
+            total: outcome.total.clone(),
+            cancelled: outcome.cancelled,
+            resumed
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.cancelled.is_none()
+    }
+}
+
+impl fmt::Display for PhaseReport {
+    /// e.g. "t2_map: time limit reached after 1024/4096 in 60s"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let done: u64 = self.completed.iter().map(|range| range.end - range.start).sum();
+
+        match self.cancelled {
+            None => write!(f, "{}: complete", self.phase)?,
+            Some(reason) => write!(f, "{}: {} after {}/{}", self.phase, reason, done, self.total.end - self.total.start)?
+        }
+
+        write!(f, " in {}s{}", self.elapsed.as_secs(), if self.resumed { " (resumed)" } else { "" })
+    }
+}
+
+/// What a search found, and how far it got
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchReport {
+    /// the pseudoprimes found, in increasing order. Only partial if the last phase stopped early.
+    pub results: Vec<Pseudoprime>,
+    /// how many T2 subset products passed the filter, if phase 2 was needed this time
+    pub t2_matches: Option<usize>,
+    /// The phases this run needed, in order. A phase that stopped early is the last one.
+    /// Phases before the last whose complete output was already saved in the run directory
+    /// are left out.
+    pub phases: Vec<PhaseReport>,
+    pub elapsed: Duration
+}
+
+impl SearchReport {
+    /// true if the final phase ran to completion
+    pub fn is_complete(&self) -> bool {
+        self.phases.last().is_some_and(|last| last.phase == Phase::FinalSieve && last.is_complete())
+    }
+
+    pub fn phase(&self, phase: Phase) -> Option<&PhaseReport> {
+        self.phases.iter().find(|report| report.phase == phase)
+    }
+}
+
+/// the final check, given T1, T2 and the masks of a match
+type CheckFn = dyn Fn(&[u64], &[u64], u32, u32) -> Option<Pseudoprime> + Send + Sync;
+
+type SinkFn = dyn FnMut(&Pseudoprime) + Send;
+
+/// A search for subsets of a candidate set whose product is 1 mod a modulus, set up with
+/// the builder methods and then `run`:
+///
+/// ```ignore
+/// let report = Search::new()
+///     .filter(FilterConfig { size: 1 << 36, ..FilterConfig::default() })
+///     .time_limit(Phase::FinalSieve, Duration::from_secs(3600))
+///     .run()?;
+/// ```
+pub struct Search<M: Modulus = OptiM> {
+    candidates: Vec<u64>,
+    t1_len: usize,
+    modulus: M,
+    filter: FilterConfig,
+    t2_index: IndexKind,
+    n_tasks: u64,
+    workers: Option<Workers>,
+    /// None for a `PseudoprimeCheck` with `bounds`, made for the halves when the search runs
+    check: Option<Arc<CheckFn>>,
+    check_name: String,
+    bounds: Option<PseudoprimeBounds>,
+    sink: Option<Box<SinkFn>>,
+    cancel: CancellationToken,
+    time_limits: [Option<Duration>; 3],
+    run_dir: Option<PathBuf>,
+    checkpoint_interval: Duration
+}
+
+impl Default for Search<OptiM> {
+    fn default() -> Self {
+        Search::new()
+    }
+}
+
+impl Search<OptiM> {
+    /// The search from the paper: R split into halves, mod `M`, with the default filter,
+    /// `N_TASKS` tasks per phase, and a `PseudoprimeCheck` for the paper's bounds (with the
+    /// matches too small for that ruled out by size first).
+    pub fn new() -> Self {
+        Search {
+            candidates: R.clone(),
+            t1_len: R.len() / 2,
+            modulus: MODULUS,
+            filter: FilterConfig::default(),
+            t2_index: IndexKind::default(),
+            n_tasks: N_TASKS,
+            workers: None,
+            check: None,
+            check_name: "pseudoprime_check".to_string(),
+            bounds: Some(INSTANCE.bounds()),
+            sink: None,
+            cancel: CancellationToken::new(),
+            time_limits: [None; 3],
+            run_dir: None,
+            checkpoint_interval: CHECKPOINT_INTERVAL
+        }
+    }
+}
+
+impl<M: Modulus + Send + Sync + 'static> Search<M> {
+    /// searches subsets of `candidates` instead of R, split into halves
+    pub fn candidates(mut self, candidates: &[u64]) -> Self {
+        self.candidates = candidates.to_vec();
+        self.t1_len = candidates.len() / 2;
+        self
+    }
+
+    /// puts the first `t1_len` candidates in T1, and the rest in T2
+    pub fn split(mut self, t1_len: usize) -> Self {
+        self.t1_len = t1_len;
+        self
+    }
+
+    /// reorders the candidates so that T1 and T2 keep their sizes, but their products are
+    /// as near the same size as `balanced_halves` gets them; do it after `candidates` and `split`
+    pub fn balanced(mut self) -> Self {
+        self.candidates = balanced_halves(&self.candidates, self.t1_len);
+        self
+    }
+
+    /// takes subset products mod `modulus` instead of `M`
+    pub fn modulus<N: Modulus>(self, modulus: N) -> Search<N> {
+        Search {
+            candidates: self.candidates,
+            t1_len: self.t1_len,
+            modulus,
+            filter: self.filter,
+            t2_index: self.t2_index,
+            n_tasks: self.n_tasks,
+            workers: self.workers,
+            check: self.check,
+            check_name: self.check_name,
+            bounds: self.bounds,
+            sink: self.sink,
+            cancel: self.cancel,
+            time_limits: self.time_limits,
+            run_dir: self.run_dir,
+            checkpoint_interval: self.checkpoint_interval
+        }
+    }
+
+    pub fn filter(mut self, config: FilterConfig) -> Self {
+        self.filter = config;
+        self
+    }
+
+    /// looks the T1 subset products up in the phase 2 map with a `kind` index
+    pub fn t2_index(mut self, kind: IndexKind) -> Self {
+        self.t2_index = kind;
+        self
+    }
+
+    /// divides each phase's work into `n_tasks` tasks
+    pub fn tasks(mut self, n_tasks: u64) -> Self {
+        self.n_tasks = n_tasks;
+        self
+    }
+
+    /// runs on `workers`, rather than on a new `Workers::new()`
+    pub fn workers(mut self, workers: Workers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Decides whether a match is a pseudoprime, given T1, T2 and the masks of the match
+    /// (see `PseudoprimeCheck`). This drops the `bounds` that went with the old check, and
+    /// its name: the new one is called "custom" unless given a `check_name`.
+    pub fn check(mut self, check: impl Fn(&[u64], &[u64], u32, u32) -> Option<Pseudoprime> + Send + Sync + 'static) -> Self {
+        self.check = Some(Arc::new(check));
+        self.check_name = "custom".to_string();
+        self.bounds = None;
+        self
+    }
+
+    /// names the check for the run directory, which only keeps the final phase's results
+    /// while the name and `bounds` stay the same
+    pub fn check_name(mut self, name: &str) -> Self {
+        self.check_name = name.to_string();
+        self
+    }
+
+    /// Only finds pseudoprimes within `bounds`. The default check is made anew for them; one
+    /// from `check` is left as it is, but matches outside them are ruled out from the log2 of
+    /// their factors before they get to it (see `SizeFilter`).
+    pub fn bounds(mut self, bounds: PseudoprimeBounds) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// hands each pseudoprime to `sink` when the final phase ends, in increasing order
+    pub fn sink(mut self, sink: impl FnMut(&Pseudoprime) + Send + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// stops the search (keeping what it has) when `cancel` is cancelled
+    pub fn cancel(mut self, cancel: &CancellationToken) -> Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    /// stops the search if `phase` runs for longer than `limit`
+    pub fn time_limit(mut self, phase: Phase, limit: Duration) -> Self {
+        self.time_limits[phase.index()] = Some(limit);
+        self
+    }
+
+    /// saves each phase's progress to `path`, and picks up from whatever is there already;
+    /// see `checkpoint`
+    pub fn run_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.run_dir = Some(path.into());
+        self
+    }
+
+    /// with a `run_dir`, also saves a running phase's progress every `interval`, rather than
+    /// only when it ends; defaults to `CHECKPOINT_INTERVAL`
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    /// the T1 half of the candidates
+    pub fn t1(&self) -> &[u64] {
+        &self.candidates[..self.t1_len.min(self.candidates.len())]
+    }
+
+    /// the T2 half of the candidates
+    pub fn t2(&self) -> &[u64] {
+        &self.candidates[self.t1_len.min(self.candidates.len())..]
+    }
+
+    /// the check the final phase runs: the one from `check`, or else a `PseudoprimeCheck`
+    /// with `bounds` for these halves
+    fn final_check(&self) -> Arc<CheckFn> {
+        if let Some(check) = &self.check {
+            return check.clone();
+        }
+
+        let bounds = self.bounds.clone().unwrap_or_else(|| INSTANCE.bounds());
+        let check = PseudoprimeCheck::new(bounds, self.t1(), self.t2());
+        Arc::new(move |_: &[u64], _: &[u64], t1_mask, t2_mask| check.check(t1_mask, t2_mask))
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        for (name, half) in [("T1", self.t1()), ("T2", self.t2())].iter() {
+            if half.is_empty() || half.len() > MAX_HALF {
+                return Err(invalid(format!("{} has {} candidates, it needs 1 to {}", name, half.len(), MAX_HALF)));
+            }
+        }
+
+        if let Some(candidate) = self.candidates.iter().find(|candidate| self.modulus.inverse(**candidate).is_none()) {
+            return Err(invalid(format!("candidate {} has no inverse mod the modulus", candidate)));
+        }
+
+        if self.n_tasks == 0 {
+            return Err(invalid("the phases need at least one task".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// the token `phase` runs under: cancelled with the search's, or when its time is up
+    fn phase_token(&self, phase: Phase) -> CancellationToken {
+        self.cancel.child(self.time_limits[phase.index()])
+    }
+
+    /// Runs the search. Errors are bad parameters, or trouble with the run directory;
+    /// stopping early (on `cancel` or a time limit) is not an error, but shows in the report.
+    pub fn run(mut self) -> io::Result<SearchReport> {
+        self.validate()?;
+
+        let start = Instant::now();
+        let t1_inverse = inverse(self.t1(), self.modulus);
+        let mut config = self.filter;
+
+        let run = match &self.run_dir {
+            Some(path) => {
+                let mut run = RunDir::open(path, &t1_inverse, self.t2(), &config)?;
+                config.seed = Some(run.filter_seed()?);
+                if run.sieve_params(&self.check_name, self.bounds.as_ref())? {
+                    println!("The check or bounds have changed since the saved results, so the final sieve starts over");
+                }
+                Some(run)
+            },
+            None => None
+        };
+
+        let saved_results = match &run {
+            Some(run) => run.results()?,
+            None => None
+        };
+        if let Some(results) = saved_results.as_ref().filter(|results| results.is_complete()) {
+            println!("Search already complete, primes found: {}", results.value.len());
+            let phases = vec![PhaseReport::new(Phase::FinalSieve, results, start, true)];
+            return Ok(self.finish(start, results.value.clone(), None, phases));
+        }
+
+        let workers = self.workers.take().unwrap_or_default();
+        // the phases hand their progress to the run directory along the way, as well as at the end
+        let run = RefCell::new(run);
+        let save_progress = |progress: Checkpoint| checkpoint(&mut run.borrow_mut(), progress);
+        let save_progress: &dyn Fn(Checkpoint) = &save_progress;
+        let context = PhaseContext {
+            modulus: self.modulus,
+            n_tasks: self.n_tasks,
+            workers: &workers,
+            checkpoint: run.borrow().is_some().then_some((self.checkpoint_interval, save_progress))
+        };
+        let mut phases = Vec::new();
+
+        let saved_t2_map = match &*run.borrow() {
+            Some(run) => run.t2_map()?,
+            None => None
+        };
+        let t2_map = match saved_t2_map {
+            // phase 1 is only needed to finish phase 2
+            Some(t2_map) if t2_map.is_complete() => t2_map,
+            saved_t2_map => {
+                let phase_start = Instant::now();
+                let saved_filter = match &*run.borrow() {
+                    Some(run) => run.filter()?,
+                    None => None
+                };
+                let resumed = saved_filter.is_some();
+                let saved_completed = saved_filter.as_ref().map(|saved| saved.completed.clone());
+
+                let filter = bloom_t1(&t1_inverse, &config, &context, &self.phase_token(Phase::BloomT1), saved_filter);
+                // a resumed filter that had nothing left to insert is already saved
+                if saved_completed.as_ref() != Some(&filter.completed) {
+                    save(&mut run.borrow_mut(), "filter", |run| run.save_filter(&filter));
+                }
+                phases.push(PhaseReport::new(Phase::BloomT1, &filter, phase_start, resumed));
+                if !filter.is_complete() {
+                    return Ok(self.finish(start, Vec::new(), None, phases));
+                }
+
+                let phase_start = Instant::now();
+                let resumed = saved_t2_map.is_some();
+                let t2_map = build_t2(filter.value, self.t2(), &context, &self.phase_token(Phase::T2Map), saved_t2_map);
+                save(&mut run.borrow_mut(), "T2 map", |run| run.save_t2_map(&t2_map));
+                phases.push(PhaseReport::new(Phase::T2Map, &t2_map, phase_start, resumed));
+                t2_map
+            }
+        };
+
+        let t2_matches = t2_map.value.len();
+        println!("T2 matches: {}", t2_matches);
+        if !t2_map.is_complete() {
+            return Ok(self.finish(start, Vec::new(), Some(t2_matches), phases));
+        }
+
+        let phase_start = Instant::now();
+        let resumed = saved_results.is_some();
+        let (t1, t2, check) = (self.t1(), self.t2(), self.final_check());
+        let size = self.bounds.clone().map(|bounds| SizeFilter::new(bounds, t1, t2));
+        let t2_index = T2Index::new(&t2_map.value, self.t2_index);
+        let results = final_sieve(&t1_inverse, &t2_index, size.as_ref(), &|t1_mask, t2_mask| check(t1, t2, t1_mask, t2_mask),
+            &context, &self.phase_token(Phase::FinalSieve), saved_results);
+        save(&mut run.borrow_mut(), "results", |run| run.save_results(&results));
+        phases.push(PhaseReport::new(Phase::FinalSieve, &results, phase_start, resumed));
+
+        Ok(self.finish(start, results.value, Some(t2_matches), phases))
+    }
+
+    /// hands the results to the sink, and puts the report together
+    fn finish(&mut self, start: Instant, results: Vec<Pseudoprime>, t2_matches: Option<usize>, phases: Vec<PhaseReport>) -> SearchReport {
+        if let Some(sink) = self.sink.as_mut() {
+            for result in results.iter() {
+                sink(result);
+            }
+        }
+
+        SearchReport { results, t2_matches, phases, elapsed: start.elapsed() }
+    }
+}
+
+/// Puts `candidates` in an order where the first `t1_len` multiply to about the same as the
+/// rest: largest first, each one goes to the half with the smaller product so far that still
+/// has room. Each half is in increasing order.
+pub fn balanced_halves(candidates: &[u64], t1_len: usize) -> Vec<u64> {
+    let t1_len = t1_len.min(candidates.len());
+    let mut sorted = candidates.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+
+    let t2_len = candidates.len() - t1_len;
+    let (mut t1, mut t2) = (Vec::with_capacity(candidates.len()), Vec::with_capacity(t2_len));
+    let (mut t1_log2, mut t2_log2) = (0.0, 0.0);
+    for r in sorted {
+        let log2 = (r as f64).log2();
+        if t2.len() == t2_len || (t1.len() < t1_len && t1_log2 <= t2_log2) {
+            t1.push(r);
+            t1_log2 += log2;
+        } else {
+            t2.push(r);
+            t2_log2 += log2;
+        }
+    }
+
+    t1.reverse();
+    t2.reverse();
+    t1.extend(t2);
+    t1
+}
+
+/// saves the progress a running phase hands over
+fn checkpoint(run: &mut Option<RunDir>, progress: Checkpoint) {
+    match progress {
+        Checkpoint::Filter(filter) => save(run, "filter so far", |run| run.save_filter(&filter)),
+        Checkpoint::T2Entries(entries) => save(run, "T2 map so far", |run| run.append_t2_map(&entries)),
+        Checkpoint::Results(results) => save(run, "results so far", |run| run.save_results(&results))
+    }
+}
+
+/// saves a phase's progress; failing to is worth a warning, but not losing the search over
+fn save(run: &mut Option<RunDir>, what: &str, save: impl FnOnce(&mut RunDir) -> io::Result<()>) {
+    if let Some(run) = run {
+        match save(run) {
+            Ok(()) => println!("Saved the {} to {}", what, run.path().display()),
+            Err(e) => println!("Warning: couldn't save the {} to {}: {}", what, run.path().display(), e)
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use rug::Integer;
+    use rug::integer::IsPrime;
+    use crate::gray_prod_iter::{ProductIter, ProductSet};
+    use crate::magic_numbers::{M, get_vals_to_multiply};
+    use crate::modulus::BasicDivisor;
+    use crate::t2_map::T2Map;
+
+    /// a search small enough for a test, which accepts every match
+    fn small_search() -> Search {
+        Search::new()
+            .candidates(&R[..24])
+            .filter(FilterConfig { size: 1 << 16, ..FilterConfig::default() })
+            .tasks(64)
+            .check(|t1, t2, t1_mask, t2_mask| {
+                let factors = get_vals_to_multiply(t1, t2, t1_mask, t2_mask);
+                Some(Pseudoprime { pseudoprime: Integer::from(Integer::product(factors.iter())) + 1u32, factors })
+            })
+    }
+
+    /// the masks of every match, found the slow way
+    fn matches<N: Modulus + 'static>(t1: &[u64], t2: &[u64], modulus: N) -> Vec<(u32, u32)> {
+        let t1_inverse = ProductSet::new(&inverse(t1, modulus), modulus);
+        let t2 = ProductSet::new(t2, modulus);
+        let t2_map: T2Map = ProductIter::new(&t2, 0, 1 << 12).map(|(mask, ssp)| (ssp, mask as u32)).collect();
+
+        ProductIter::new(&t1_inverse, 0, 1 << 12)
+            .flat_map(|(mask, ssp)| t2_map.get(ssp).iter().map(move |(_, t2_mask)| (mask as u32, *t2_mask)))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_small_search() {
+        let found = Arc::new(Mutex::new(Vec::new()));
+        let sink = found.clone();
+
+        let search = small_search().sink(move |result| sink.lock().unwrap().push(result.pseudoprime.clone()));
+        let (t1, t2) = (search.t1().to_vec(), search.t2().to_vec());
+        assert_eq!((t1.len(), t2.len()), (12, 12));
+
+        let report = search.run().unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.phases.iter().map(|phase| phase.phase).collect::<Vec<_>>(), Phase::ALL);
+
+        let mut expected: Vec<Integer> = matches(&t1, &t2, MODULUS).into_iter().map(|(t1_mask, t2_mask)| {
+            Integer::from(Integer::product(get_vals_to_multiply(&t1, &t2, t1_mask, t2_mask).iter())) + 1u32
+        }).collect();
+        expected.sort();
+
+        let results: Vec<Integer> = report.results.iter().map(|result| result.pseudoprime.clone()).collect();
+        assert_eq!(results, expected);
+        assert_eq!(*found.lock().unwrap(), expected);
+
+        // the general modulus agrees with the tuned one
+        let report = small_search().modulus(BasicDivisor::new(M)).run().unwrap();
+        assert_eq!(report.results.len(), expected.len());
+    }
+
+    #[test]
+    pub fn test_shared_ssps() {
+        // 2^12 T2 subsets mod 65537 have plenty of SSPs in common, and each mask counts
+        let modulus = BasicDivisor::new(65537);
+        let search = small_search().modulus(modulus);
+        let (t1, t2) = (search.t1().to_vec(), search.t2().to_vec());
+        let t2_map: T2Map = ProductIter::new(&ProductSet::new(&t2, modulus), 0, 1 << 12).map(|(mask, ssp)| (ssp, mask as u32)).collect();
+        assert!(t2_map.duplicates() > 0);
+
+        let matches = matches(&t1, &t2, modulus);
+        // keeping one mask per SSP would have found one match per T1 mask
+        let mut t1_masks: Vec<u32> = matches.iter().map(|(t1_mask, _)| *t1_mask).collect();
+        t1_masks.dedup();
+        assert!(matches.len() > t1_masks.len());
+
+        let mut expected: Vec<Integer> = matches.into_iter().map(|(t1_mask, t2_mask)| {
+            Integer::from(Integer::product(get_vals_to_multiply(&t1, &t2, t1_mask, t2_mask).iter())) + 1u32
+        }).collect();
+        expected.sort();
+
+        for kind in IndexKind::ALL {
+            let report = small_search().modulus(modulus).t2_index(kind).run().unwrap();
+            let results: Vec<Integer> = report.results.iter().map(|result| result.pseudoprime.clone()).collect();
+            assert_eq!(results, expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    pub fn test_bounded_search() {
+        // a small modulus, for matches of all sizes
+        let search = || small_search().modulus(BasicDivisor::new(65537));
+        let all = search().run().unwrap().results;
+        let mut sizes: Vec<u32> = all.iter().map(|result| result.pseudoprime.significant_bits()).collect();
+        sizes.sort();
+        let bounds = PseudoprimeBounds { bits: sizes[sizes.len() / 2]..=u32::MAX, factors: 0..=u32::MAX };
+
+        // the check still accepts every match, so the size filter is all that turns any down
+        let report = search().bounds(bounds.clone()).run().unwrap();
+        let expected: Vec<&Pseudoprime> = all.iter().filter(|result| bounds.bits.contains(&result.pseudoprime.significant_bits())).collect();
+        assert!(!expected.is_empty() && expected.len() < all.len());
+        assert_eq!(report.results.iter().collect::<Vec<_>>(), expected);
+
+        // the default check is made for the bounds it's given, rather than the paper's
+        let default_search = || Search::new().candidates(&R[..24]).filter(FilterConfig { size: 1 << 16, ..FilterConfig::default() })
+            .tasks(64).modulus(BasicDivisor::new(65537));
+        let primes: Vec<&Pseudoprime> = all.iter().filter(|result| result.pseudoprime.is_probably_prime(15) != IsPrime::No).collect();
+        assert!(default_search().run().unwrap().results.is_empty());
+        let unbounded = PseudoprimeBounds { bits: 0..=u32::MAX, factors: 0..=u32::MAX };
+        assert_eq!(default_search().bounds(unbounded).run().unwrap().results.iter().collect::<Vec<_>>(), primes);
+        let report = default_search().bounds(bounds.clone()).run().unwrap();
+        let expected: Vec<&Pseudoprime> = primes.into_iter().filter(|result| bounds.bits.contains(&result.pseudoprime.significant_bits())).collect();
+        assert_eq!(report.results.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    pub fn test_balanced_halves() {
+        let search = Search::new().balanced();
+        let (t1, t2) = (search.t1(), search.t2());
+        assert_eq!((t1.len(), t2.len()), (R.len() / 2, R.len() - R.len() / 2));
+
+        let mut together = [t1, t2].concat();
+        together.sort();
+        assert_eq!(together, *R);
+
+        let log2 = |half: &[u64]| half.iter().map(|r| (*r as f64).log2()).sum::<f64>();
+        let largest = (*R.iter().max().unwrap() as f64).log2();
+        assert!((log2(t1) - log2(t2)).abs() <= largest, "{} and {}", log2(t1), log2(t2));
+        assert!(t1.windows(2).all(|pair| pair[0] < pair[1]) && t2.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // a lopsided split evens out the products as far as it can
+        let candidates = [1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(balanced_halves(&candidates, 2), [4, 7, 1, 2, 3, 5, 6]);
+        assert_eq!(balanced_halves(&candidates, 0), candidates);
+        assert_eq!(balanced_halves(&candidates, 9), candidates);
+    }
+
+    #[test]
+    pub fn test_cancelled_search() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let report = small_search().cancel(&cancel).run().unwrap();
+
+        assert!(!report.is_complete());
+        assert_eq!(report.phases.len(), 1);
+        assert_eq!(report.phases[0].cancelled, Some(CancelReason::Requested));
+        assert!(report.results.is_empty());
+
+        let report = small_search().time_limit(Phase::T2Map, Duration::from_secs(0)).run().unwrap();
+        assert_eq!(report.phases.len(), 2);
+        assert!(report.phase(Phase::BloomT1).unwrap().is_complete());
+        assert_eq!(report.phase(Phase::T2Map).unwrap().cancelled, Some(CancelReason::TimeLimit));
+    }
+
+    #[test]
+    pub fn test_invalid_search() {
+        let err = |search: Search| search.run().err().unwrap().kind();
+
+        assert_eq!(err(small_search().split(0)), io::ErrorKind::InvalidInput);
+        assert_eq!(err(small_search().candidates(&R[..]).split(40)), io::ErrorKind::InvalidInput);
+        assert_eq!(err(small_search().tasks(0)), io::ErrorKind::InvalidInput);
+        // 2 divides M
+        assert_eq!(err(small_search().candidates(&[2, 3])), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    pub fn test_resumed_search() {
+        let path = std::env::temp_dir().join(format!("pseudoprimes-search-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        // a small modulus, for plenty of results
+        let search = || small_search().modulus(BasicDivisor::new(65537)).run_dir(&path).checkpoint_interval(Duration::ZERO);
+        let first = search().run().unwrap();
+        assert!(first.is_complete());
+
+        // nothing left to do the second time round
+        let second = search().run().unwrap();
+        assert!(second.is_complete());
+        assert_eq!(second.phases.len(), 1);
+        assert!(second.phases[0].resumed);
+        assert_eq!(second.results, first.results);
+
+        // other bounds only redo the final sieve (the factors include the 2 of n = 2 r1 ... rk + 1)
+        let bounds = PseudoprimeBounds { bits: 0..=u32::MAX, factors: 12..=u32::MAX };
+        let third = search().bounds(bounds).run().unwrap();
+        assert_eq!(third.phases.len(), 1);
+        assert_eq!(third.phases[0].phase, Phase::FinalSieve);
+        assert!(!third.phases[0].resumed);
+        let expected: Vec<&Pseudoprime> = first.results.iter().filter(|result| result.factors.len() > 12).collect();
+        assert!(!expected.is_empty() && expected.len() < first.results.len());
+        assert_eq!(third.results.iter().collect::<Vec<_>>(), expected);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+// t2_map.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// The output of phase 2: the T2 subset products that passed the Bloom filter, with the
+// masks that make them. Two T2 subsets can have the same product mod M, and each of them
+// can make a pseudoprime with a matching T1 subset, so an SSP keeps every one of its
+// masks. The entries are kept sorted, with the masks of an SSP next to each other.
+//
+// Phase 3 looks up every T1 subset product in the map, 2^32 times for the paper, almost
+// always for an SSP that isn't there. Once the map is complete a `T2Index` is built on it
+// for those lookups: a binary search on the map itself, the SSPs in Eytzinger order (a
+// balanced search tree laid out breadth first, so that the first few levels, which every
+// search goes through, stay in cache), or an open addressing hash table. The lookups can be
+// batched, which lets the memory accesses for the whole batch overlap.
+
+use std::iter::FromIterator;
+
+/// (SSP, T2 mask) pairs, sorted, without repeats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct T2Map {
+    entries: Vec<(u64, u32)>
+}
+
+impl T2Map {
+    pub fn new() -> Self {
+        T2Map { entries: Vec::new() }
+    }
+
+    /// the masks whose subsets have product `ssp`, as (SSP, mask) pairs
+    pub fn get(&self, ssp: u64) -> &[(u64, u32)] {
+        let start = self.entries.partition_point(|(key, _)| *key < ssp);
+        // an SSP seldom has more than a mask or two
+        let len = self.entries[start..].iter().take_while(|(key, _)| *key == ssp).count();
+        &self.entries[start..start + len]
+    }
+
+    /// Adds `entries` to the map. A pair that's already there (say, from a range of T2
+    /// that was searched again on resuming) is only kept once.
+    pub fn extend(&mut self, entries: impl IntoIterator<Item = (u64, u32)>) {
+        self.entries.extend(entries);
+        self.entries.sort_unstable();
+        self.entries.dedup();
+    }
+
+    /// the number of (SSP, mask) pairs
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the number of different SSPs
+    pub fn ssps(&self) -> usize {
+        self.entries.chunk_by(|a, b| a.0 == b.0).count()
+    }
+
+    /// the pairs whose SSP another pair already has: `len() - ssps()`
+    pub fn duplicates(&self) -> usize {
+        self.len() - self.ssps()
+    }
+
+    /// the (SSP, mask) pairs in increasing order
+    pub fn iter(&self) -> std::slice::Iter<'_, (u64, u32)> {
+        self.entries.iter()
+    }
+}
+
+/// How a `T2Index` finds an SSP
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IndexKind {
+    /// binary search on the map, which needs no more memory
+    Sorted,
+    /// a search tree in Eytzinger order, 12 bytes per SSP
+    Eytzinger,
+    /// an open addressing table with linear probing, at most half full: 16-byte slots, 2 to 4
+    /// per SSP, so 32 to 64 bytes per SSP
+    #[default]
+    Hashed
+}
+
+impl IndexKind {
+    pub const ALL: [IndexKind; 3] = [IndexKind::Sorted, IndexKind::Eytzinger, IndexKind::Hashed];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IndexKind::Sorted => "sorted",
+            IndexKind::Eytzinger => "eytzinger",
+            IndexKind::Hashed => "hashed"
+        }
+    }
+
+    /// the memory an index of this kind takes on a map of `ssps` distinct SSPs, on top of the map
+    pub fn bytes(&self, ssps: usize) -> usize {
+        match self {
+            IndexKind::Sorted => 0,
+            IndexKind::Eytzinger => (ssps + 1) * (size_of::<u64>() + size_of::<u32>()),
+            IndexKind::Hashed => hashed_slots(ssps) * size_of::<(u64, u32)>()
+        }
+    }
+}
+
+/// the slots in a hashed index of `ssps` SSPs: a power of two, at least twice as many
+fn hashed_slots(ssps: usize) -> usize {
+    (2 * ssps).next_power_of_two().max(2)
+}
+
+/// marks a free slot in a hashed index
+const EMPTY: u32 = u32::MAX;
+
+/// how many lookups `T2Index::get_batch` does at a time
+const BATCH: usize = 64;
+
+/// A read-only index on a complete `T2Map`, for looking SSPs up
+pub struct T2Index<'a> {
+    map: &'a T2Map,
+    layout: Layout
+}
+
+enum Layout {
+    Sorted,
+    /// the SSPs from `keys[1]` on, with the children of `k` at `2k` and `2k + 1`, and where
+    /// each one's masks start in the map
+    Eytzinger { keys: Vec<u64>, starts: Vec<u32> },
+    /// (SSP, where its masks start in the map) at the slot its hash picks or the first free
+    /// one after, with `EMPTY` for the start of a free slot
+    Hashed { slots: Vec<(u64, u32)>, shift: u32 }
+}
+
+impl<'a> T2Index<'a> {
+    pub fn new(map: &'a T2Map, kind: IndexKind) -> Self {
+        assert!(map.len() < EMPTY as usize, "Too many entries to index");
+
+        // each SSP, with where its masks start
+        let runs = || {
+            let mut start = 0;
+            map.entries.chunk_by(|a, b| a.0 == b.0).map(move |run| {
+                start += run.len();
+                (run[0].0, (start - run.len()) as u32)
+            })
+        };
+
+        let layout = match kind {
+            IndexKind::Sorted => Layout::Sorted,
+            IndexKind::Eytzinger => {
+                let sorted: Vec<(u64, u32)> = runs().collect();
+                let mut keys = vec![0; sorted.len() + 1];
+                let mut starts = vec![0; sorted.len() + 1];
+                eytzinger(&sorted, &mut keys, &mut starts, &mut 0, 1);
+                Layout::Eytzinger { keys, starts }
+            },
+            IndexKind::Hashed => {
+                let len = hashed_slots(map.ssps());
+                let shift = 64 - len.trailing_zeros();
+                let mut slots = vec![(0, EMPTY); len];
+                for (ssp, start) in runs() {
+                    let mut slot = hash(ssp, shift);
+                    while slots[slot].1 != EMPTY {
+                        slot = (slot + 1) & (len - 1);
+                    }
+                    slots[slot] = (ssp, start);
+                }
+                Layout::Hashed { slots, shift }
+            }
+        };
+
+        T2Index { map, layout }
+    }
+
+    pub fn map(&self) -> &'a T2Map {
+        self.map
+    }
+
+    /// the memory the index takes, on top of the map
+    pub fn bytes(&self) -> usize {
+        match &self.layout {
+            Layout::Sorted => 0,
+            Layout::Eytzinger { keys, starts } => keys.len() * size_of::<u64>() + starts.len() * size_of::<u32>(),
+            Layout::Hashed { slots, .. } => slots.len() * size_of::<(u64, u32)>()
+        }
+    }
+
+    /// the masks whose subsets have product `ssp`, as (SSP, mask) pairs, as `T2Map::get`
+    pub fn get(&self, ssp: u64) -> &'a [(u64, u32)] {
+        match &self.layout {
+            Layout::Sorted => self.map.get(ssp),
+            Layout::Eytzinger { keys, starts } => {
+                let mut k = 1;
+                while k < keys.len() {
+                    prefetch(keys.get(8 * k));
+                    k = 2 * k + (keys[k] < ssp) as usize;
+                }
+                self.found(eytzinger_match(keys, starts, k, ssp))
+            },
+            Layout::Hashed { slots, shift } => self.found(probe(slots, hash(ssp, *shift), ssp))
+        }
+    }
+
+    /// Sets `matches` to what `get` gives for each of `ssps`, in the same order. The
+    /// lookups are done `BATCH` at a time, interleaved so their cache misses overlap.
+    pub fn get_batch(&self, ssps: &[u64], matches: &mut Vec<&'a [(u64, u32)]>) {
+        matches.clear();
+
+        match &self.layout {
+            Layout::Sorted => matches.extend(ssps.iter().map(|ssp| self.map.get(*ssp))),
+            Layout::Eytzinger { keys, starts } => {
+                let mut nodes = [1usize; BATCH];
+                for chunk in ssps.chunks(BATCH) {
+                    let nodes = &mut nodes[..chunk.len()];
+                    nodes.fill(1);
+
+                    // a level at a time, for every search that hasn't reached the bottom
+                    let mut descending = true;
+                    while descending {
+                        descending = false;
+                        for (k, ssp) in nodes.iter_mut().zip(chunk.iter()) {
+                            if *k < keys.len() {
+                                *k = 2 * *k + (keys[*k] < *ssp) as usize;
+                                prefetch(keys.get(*k));
+                                descending = true;
+                            }
+                        }
+                    }
+
+                    matches.extend(nodes.iter().zip(chunk.iter()).map(|(k, ssp)| self.found(eytzinger_match(keys, starts, *k, *ssp))));
+                }
+            },
+            Layout::Hashed { slots, shift } => {
+                let mut first_slots = [0usize; BATCH];
+                for chunk in ssps.chunks(BATCH) {
+                    for (slot, ssp) in first_slots.iter_mut().zip(chunk.iter()) {
+                        *slot = hash(*ssp, *shift);
+                        prefetch(slots.get(*slot));
+                    }
+
+                    matches.extend(first_slots.iter().zip(chunk.iter()).map(|(slot, ssp)| self.found(probe(slots, *slot, *ssp))));
+                }
+            }
+        }
+    }
+
+    /// the masks that start at `start` in the map, if an SSP was found
+    fn found(&self, start: Option<u32>) -> &'a [(u64, u32)] {
+        let entries = &self.map.entries;
+        match start {
+            Some(start) => {
+                let start = start as usize;
+                let ssp = entries[start].0;
+                let len = entries[start..].iter().take_while(|(key, _)| *key == ssp).count();
+                &entries[start..start + len]
+            },
+            None => &[]
+        }
+    }
+}
+
+/// fills in `keys` and `starts` from `sorted` for the subtree at `k`, in order
+fn eytzinger(sorted: &[(u64, u32)], keys: &mut [u64], starts: &mut [u32], next: &mut usize, k: usize) {
+    if k < keys.len() {
+        eytzinger(sorted, keys, starts, next, 2 * k);
+        (keys[k], starts[k]) = sorted[*next];
+        *next += 1;
+        eytzinger(sorted, keys, starts, next, 2 * k + 1);
+    }
+}
+
+/// Given `k` where the search for `ssp` fell off the bottom of the tree, where its masks
+/// start if it's there. The turns the search took are the bits of `k` below its leading
+/// one, and the last node it went right from is the greatest key less than `ssp`, so the
+/// node after that (one more level up from the last left turn) is the least key that isn't.
+fn eytzinger_match(keys: &[u64], starts: &[u32], k: usize, ssp: u64) -> Option<u32> {
+    let k = k >> (k.trailing_ones() + 1);
+    (k != 0 && keys[k] == ssp).then(|| starts[k])
+}
+
+/// the first slot to look for `ssp` in, of 2^(64 - `shift`)
+fn hash(ssp: u64, shift: u32) -> usize {
+    (ssp.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> shift) as usize
+}
+
+/// where `ssp`'s masks start, looking from `slot` up to the next free one
+fn probe(slots: &[(u64, u32)], mut slot: usize, ssp: u64) -> Option<u32> {
+    loop {
+        let (key, start) = slots[slot];
+        if start == EMPTY {
+            return None;
+        }
+        if key == ssp {
+            return Some(start);
+        }
+        slot = (slot + 1) & (slots.len() - 1);
+    }
+}
+
+/// hints the CPU to pull `value` into cache ahead of an upcoming access
+#[inline(always)]
+fn prefetch<T>(value: Option<&T>) {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(value) = value {
+        unsafe {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch::<_MM_HINT_T0>(value as *const T as *const i8);
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = value;
+}
+
+impl FromIterator<(u64, u32)> for T2Map {
+    fn from_iter<I: IntoIterator<Item = (u64, u32)>>(entries: I) -> Self {
+        let mut map = T2Map::new();
+        map.extend(entries);
+        map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    pub fn test_t2_map() {
+        let mut map: T2Map = [(7, 1), (3, 2), (7, 4), (9, 8), (7, 1)].iter().cloned().collect();
+        assert_eq!((map.len(), map.ssps(), map.duplicates()), (4, 3, 1));
+        assert_eq!(map.get(7), [(7, 1), (7, 4)]);
+        assert_eq!(map.get(3), [(3, 2)]);
+        assert!(map.get(5).is_empty() && map.get(0).is_empty() && map.get(u64::MAX).is_empty());
+
+        map.extend(vec![(7, 16), (9, 8), (1, 32)]);
+        assert_eq!(map.get(7), [(7, 1), (7, 4), (7, 16)]);
+        assert_eq!((map.len(), map.ssps(), map.duplicates()), (6, 4, 2));
+        assert_eq!(map.iter().map(|(ssp, _)| *ssp).collect::<Vec<u64>>(), [1, 3, 7, 7, 7, 9]);
+
+        assert!(T2Map::new().is_empty() && T2Map::new().get(1).is_empty());
+    }
+
+    #[test]
+    pub fn test_t2_index() {
+        // the hashed index's cost per SSP depends on how far it is from a power of two
+        assert_eq!(IndexKind::Hashed.bytes(1 << 20), 32 << 20);
+        assert_eq!(IndexKind::Hashed.bytes((1 << 20) + 1), 64 << 20);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        // sizes either side of full levels of the tree, and SSPs with several masks
+        for len in [0, 1, 2, 3, 7, 8, 9, 1000] {
+            let map: T2Map = (0..len).map(|_| (rng.gen_range(0..2 * len as u64) * 1_000_003, rng.gen())).collect();
+            let mut ssps: Vec<u64> = map.iter().map(|(ssp, _)| *ssp).collect();
+            ssps.extend((0..200).map(|_| rng.gen_range(0..2 * len as u64 + 2) * 1_000_003 + rng.gen_range(0..2)));
+            ssps.extend([0, u64::MAX]);
+
+            for kind in IndexKind::ALL {
+                let index = T2Index::new(&map, kind);
+                assert_eq!(index.bytes(), kind.bytes(map.ssps()));
+                let mut matches = Vec::new();
+                index.get_batch(&ssps, &mut matches);
+                assert_eq!(matches.len(), ssps.len());
+
+                for (ssp, batched) in ssps.iter().zip(matches.iter()) {
+                    assert_eq!(index.get(*ssp), map.get(*ssp), "{} in {} {:?}", ssp, len, kind);
+                    assert_eq!(*batched, map.get(*ssp), "{} in {} {:?}", ssp, len, kind);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,543 @@
+// topology.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// NUMA topology, read straight from sysfs rather than through libnuma.
+// Everything is read relative to a sysfs root, so tests (or a CI run with
+// `PSEUDOPRIMES_SYSFS_ROOT` set) can describe a machine we aren't running on.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// environment variable overriding the sysfs root used by `Topology::discover`
+pub const SYSFS_ROOT_VAR: &str = "PSEUDOPRIMES_SYSFS_ROOT";
+
+/// the distances the kernel reports for a node to itself and, by default, to other nodes
+pub const LOCAL_DISTANCE: u32 = 10;
+pub const REMOTE_DISTANCE: u32 = 20;
+
+/// the pool of hugepages of one size on one node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HugePages {
+    pub page_size_kb: u64,
+    pub total: u64,
+    pub free: u64
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub id: u32,
+    pub cpus: Vec<u32>,
+    /// the node's CPUs grouped by physical core, so hyperthreads of one core are together
+    pub cores: Vec<Vec<u32>>,
+    pub mem_total_kb: u64,
+    pub mem_free_kb: u64,
+    /// one entry per hugepage size, smallest first
+    pub hugepages: Vec<HugePages>,
+    /// the relative cost of reaching each node's memory from this one, in the same order
+    /// as `Topology::nodes` (the local node is conventionally 10)
+    pub distances: Vec<u32>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+    /// ordered by node id
+    pub nodes: Vec<Node>
+}
+
+impl Topology {
+    /// Reads the topology of this machine from `/sys` (or from `$PSEUDOPRIMES_SYSFS_ROOT`).
+    /// Kernels without NUMA support have no node directory; we treat those as a single
+    /// node holding every CPU.
+    pub fn discover() -> Self {
+        let root = sysfs_root();
+
+        match Topology::from_sysfs(&root) {
+            Ok(topology) => topology,
+            Err(err) => {
+                println!("Warning: couldn't read NUMA topology from {}: {}; assuming one node",
+                    root.display(), err);
+                Topology::single_node()
+            }
+        }
+    }
+
+    /// reads the topology from the sysfs tree mounted at `root`
+    pub fn from_sysfs(root: &Path) -> io::Result<Self> {
+        let node_dir = root.join("devices/system/node");
+        let mut nodes = Vec::new();
+
+        for entry in fs::read_dir(&node_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let id = match name.to_str().and_then(|name| name.strip_prefix("node")) {
+                Some(id) => id,
+                None => continue
+            };
+
+            if let Ok(id) = id.parse::<u32>() {
+                nodes.push(read_node(id, &entry.path(), root)?);
+            }
+        }
+
+        if nodes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("no nodes under {}", node_dir.display())));
+        }
+
+        nodes.sort_by_key(|node| node.id);
+
+        // a node's distance file lists one entry per online node, but if it doesn't line up
+        // with the nodes we found the kernel default is better than a misattributed number
+        let ids: Vec<u32> = nodes.iter().map(|node| node.id).collect();
+        for node in nodes.iter_mut() {
+            if node.distances.len() != ids.len() {
+                node.distances = ids.iter()
+                    .map(|&other| if other == node.id { LOCAL_DISTANCE } else { REMOTE_DISTANCE })
+                    .collect();
+            }
+        }
+
+        Ok(Topology { nodes })
+    }
+
+    /// a single node with every CPU we can see and no memory information
+    pub fn single_node() -> Self {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Topology::fake(1, cpus)
+    }
+
+    /// a made-up machine with `nodes` nodes of `cpus_per_node` consecutively numbered CPUs
+    /// (each CPU a core of its own)
+    pub fn fake(nodes: usize, cpus_per_node: usize) -> Self {
+        let nodes = (0..nodes).map(|id| Node {
+            id: id as u32,
+            cpus: ((id * cpus_per_node)..((id + 1) * cpus_per_node)).map(|cpu| cpu as u32).collect(),
+            cores: ((id * cpus_per_node)..((id + 1) * cpus_per_node)).map(|cpu| vec![cpu as u32]).collect(),
+            mem_total_kb: 0,
+            mem_free_kb: 0,
+            hugepages: Vec::new(),
+            distances: (0..nodes).map(|other| if other == id { LOCAL_DISTANCE } else { REMOTE_DISTANCE }).collect()
+        }).collect();
+
+        Topology { nodes }
+    }
+
+    pub fn node(&self, id: u32) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    pub fn node_ids(&self) -> Vec<u32> {
+        self.nodes.iter().map(|node| node.id).collect()
+    }
+
+    pub fn cpu_count(&self) -> usize {
+        self.nodes.iter().map(|node| node.cpus.len()).sum()
+    }
+
+    /// the relative cost of reaching `to`'s memory from `from`, or None for an unknown node
+    pub fn distance(&self, from: u32, to: u32) -> Option<u32> {
+        let to_idx = self.nodes.iter().position(|node| node.id == to)?;
+        self.node(from)?.distances.get(to_idx).copied()
+    }
+
+    /// every node other than `id`, nearest first (ties broken by node id)
+    pub fn nearest_nodes(&self, id: u32) -> Vec<u32> {
+        let mut others: Vec<u32> = self.node_ids().into_iter().filter(|&other| other != id).collect();
+        others.sort_by_key(|&other| (self.distance(id, other).unwrap_or(u32::MAX), other));
+
+        others
+    }
+}
+
+/// the sysfs root `Topology::discover` reads from: `/sys`, or `$PSEUDOPRIMES_SYSFS_ROOT`
+pub fn sysfs_root() -> PathBuf {
+    std::env::var_os(SYSFS_ROOT_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/sys"))
+}
+
+fn read_node(id: u32, dir: &Path, root: &Path) -> io::Result<Node> {
+    let cpus = parse_cpulist(&fs::read_to_string(dir.join("cpulist"))?)?;
+    let cores = read_cores(&cpus, root);
+
+    // memory information is nice to have, but not worth failing over
+    let meminfo = fs::read_to_string(dir.join("meminfo")).unwrap_or_default();
+    let mem_total_kb = meminfo_field(&meminfo, "MemTotal").unwrap_or(0);
+    let mem_free_kb = meminfo_field(&meminfo, "MemFree").unwrap_or(0);
+
+    let mut hugepages = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir.join("hugepages")) {
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let page_size_kb = name.to_str()
+                .and_then(|name| name.strip_prefix("hugepages-"))
+                .and_then(|name| name.strip_suffix("kB"))
+                .and_then(|size| size.parse().ok());
+
+            if let Some(page_size_kb) = page_size_kb {
+                hugepages.push(HugePages {
+                    page_size_kb,
+                    total: read_number(&entry.path().join("nr_hugepages"))?,
+                    free: read_number(&entry.path().join("free_hugepages"))?
+                });
+            }
+        }
+    }
+    hugepages.sort_by_key(|pages| pages.page_size_kb);
+
+    // checked against the node list by the caller
+    let distances = fs::read_to_string(dir.join("distance")).unwrap_or_default()
+        .split_whitespace()
+        .map(|distance| distance.parse().map_err(|_| invalid(dir.join("distance").display(), distance)))
+        .collect::<io::Result<_>>()?;
+
+    Ok(Node { id, cpus, cores, mem_total_kb, mem_free_kb, hugepages, distances })
+}
+
+/// Groups `cpus` by physical core, using each CPU's `thread_siblings_list`. A CPU whose
+/// siblings we can't read is taken to be a core of its own.
+fn read_cores(cpus: &[u32], root: &Path) -> Vec<Vec<u32>> {
+    let mut cores: Vec<Vec<u32>> = Vec::new();
+
+    for &cpu in cpus.iter() {
+        if cores.iter().any(|core| core.contains(&cpu)) {
+            continue;
+        }
+
+        let path = root.join(format!("devices/system/cpu/cpu{}/topology/thread_siblings_list", cpu));
+        let mut siblings = fs::read_to_string(path).ok()
+            .and_then(|list| parse_cpulist(&list).ok())
+            .unwrap_or_default();
+
+        siblings.retain(|sibling| cpus.contains(sibling));
+        if !siblings.contains(&cpu) {
+            siblings = vec![cpu];
+        }
+
+        cores.push(siblings);
+    }
+
+    cores
+}
+
+/// the CPUs this process is allowed to run on, or None if the kernel won't say
+#[cfg(target_os = "linux")]
+pub fn process_affinity() -> Option<Vec<u32>> {
+    use libc::{cpu_set_t, CPU_ISSET, CPU_SETSIZE, sched_getaffinity};
+    use std::mem;
+
+    unsafe {
+        let mut cpuset: cpu_set_t = mem::zeroed();
+        if sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut cpuset) != 0 {
+            return None;
+        }
+
+        Some((0..CPU_SETSIZE as usize).filter(|&cpu| CPU_ISSET(cpu, &cpuset)).map(|cpu| cpu as u32).collect())
+    }
+}
+
+/// there's no sched_getaffinity outside Linux, so we don't know
+#[cfg(not(target_os = "linux"))]
+pub fn process_affinity() -> Option<Vec<u32>> {
+    None
+}
+
+/// How many CPUs' worth of time our cgroup may use: the smallest limit on it or any of its
+/// parents, from `cpu.max` (cgroup v2) or `cpu.cfs_quota_us` (cgroup v1) under
+/// `root`/fs/cgroup, finding our cgroup in /proc/self/cgroup. None means unlimited, or unknown.
+pub fn cgroup_cpu_quota(root: &Path) -> Option<f64> {
+    let membership = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    cgroup_cpu_quota_of(root, &membership)
+}
+
+/// `cgroup_cpu_quota` for a process whose /proc/<pid>/cgroup reads `membership`; a
+/// hierarchy it has no line for is looked at from its root
+fn cgroup_cpu_quota_of(root: &Path, membership: &str) -> Option<f64> {
+    let cgroup = root.join("fs/cgroup");
+
+    // "<id>:<controllers>:<path>", where v2 is the one hierarchy "0::<path>"
+    let (mut v2_path, mut v1) = ("/", None);
+    for line in membership.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue
+        };
+
+        if id == "0" && controllers.is_empty() {
+            v2_path = path;
+        } else if controllers.split(',').any(|controller| controller == "cpu") {
+            v1 = Some((controllers, path));
+        }
+    }
+
+    // v2: "<quota> <period>", where the quota may be "max"
+    let v2 = smallest_limit(&cgroup, v2_path, |dir| {
+        let max = fs::read_to_string(dir.join("cpu.max")).ok()?;
+        let mut fields = max.split_whitespace();
+        let quota: Option<f64> = fields.next().and_then(|quota| quota.parse().ok());
+        let period: Option<f64> = fields.next().and_then(|period| period.parse().ok());
+
+        Some(quota.zip(period).map(|(quota, period)| quota / period))
+    });
+    if let Some(quota) = v2 {
+        return quota;
+    }
+
+    // v1: usually mounted at cpu, a link to cpu,cpuacct; a quota of -1 is unlimited
+    let (controllers, path) = v1.unwrap_or(("cpu", "/"));
+    let mount = [cgroup.join("cpu"), cgroup.join(controllers)].into_iter().find(|mount| mount.is_dir())?;
+    smallest_limit(&mount, path, |dir| {
+        let quota: i64 = fs::read_to_string(dir.join("cpu.cfs_quota_us")).ok()?.trim().parse().ok()?;
+        let period: i64 = fs::read_to_string(dir.join("cpu.cfs_period_us")).ok()?.trim().parse().ok()?;
+
+        Some(if quota <= 0 || period <= 0 { None } else { Some(quota as f64 / period as f64) })
+    })?
+}
+
+/// The smallest `limit` of the cgroup at `path` under `mount` and its parents. `limit` gives
+/// None where there's no limit file, and Some(None) for no limit; so does the result.
+fn smallest_limit(mount: &Path, path: &str, limit: impl Fn(&Path) -> Option<Option<f64>>) -> Option<Option<f64>> {
+    let mut dir = mount.to_path_buf();
+    let mut limits = vec![limit(&dir)];
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        dir.push(component);
+        limits.push(limit(&dir));
+    }
+
+    let found: Vec<Option<f64>> = limits.into_iter().flatten().collect();
+    if found.is_empty() {
+        return None;
+    }
+
+    Some(found.into_iter().flatten().min_by(|a, b| a.total_cmp(b)))
+}
+
+fn read_number(path: &Path) -> io::Result<u64> {
+    let contents = fs::read_to_string(path)?;
+    contents.trim().parse().map_err(|_| invalid(path.display(), &contents))
+}
+
+fn invalid(what: impl std::fmt::Display, contents: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad contents in {}: {:?}", what, contents))
+}
+
+/// finds e.g. `MemTotal` in a node meminfo line like "Node 0 MemTotal:  32768 kB"
+fn meminfo_field(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.ends_with(field) {
+            return None;
+        }
+
+        value.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// parses a kernel cpu list such as "0-3,8,10-11"
+pub fn parse_cpulist(list: &str) -> io::Result<Vec<u32>> {
+    let mut cpus = Vec::new();
+
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let parse = |cpu: &str| cpu.parse::<u32>().map_err(|_| invalid("cpu list", list));
+
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(parse(first)?..=parse(last)?),
+            None => cpus.push(parse(range)?)
+        }
+    }
+
+    Ok(cpus)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Writes a sysfs tree describing `nodes` nodes of `cpus_per_node` CPUs under a fresh
+    /// temporary directory, and returns the root. Nodes are laid out in a line, so the
+    /// distance between two nodes grows with the difference of their ids, and each pair
+    /// of consecutive CPUs are hyperthreads of one core.
+    pub fn fake_sysfs(name: &str, nodes: usize, cpus_per_node: usize) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("pseudoprimes-sysfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        for node in 0..nodes {
+            let dir = root.join(format!("devices/system/node/node{}", node));
+            let first = node * cpus_per_node;
+
+            fs::create_dir_all(dir.join("hugepages/hugepages-2048kB")).unwrap();
+            fs::create_dir_all(dir.join("hugepages/hugepages-1048576kB")).unwrap();
+
+            fs::write(dir.join("cpulist"), format!("{}-{}\n", first, first + cpus_per_node - 1)).unwrap();
+            fs::write(dir.join("meminfo"), format!(
+                "Node {} MemTotal:       {} kB\nNode {} MemFree:        {} kB\n",
+                node, 1 << 20, node, 1 << 19)).unwrap();
+            fs::write(dir.join("hugepages/hugepages-2048kB/nr_hugepages"), "0\n").unwrap();
+            fs::write(dir.join("hugepages/hugepages-2048kB/free_hugepages"), "0\n").unwrap();
+            fs::write(dir.join("hugepages/hugepages-1048576kB/nr_hugepages"), "64\n").unwrap();
+            fs::write(dir.join("hugepages/hugepages-1048576kB/free_hugepages"), "60\n").unwrap();
+            fs::write(dir.join("distance"), format!("{}\n", line_distances(node, nodes)
+                .iter().map(|d| d.to_string()).collect::<Vec<_>>().join(" "))).unwrap();
+
+            for cpu in first..(first + cpus_per_node) {
+                let cpu_dir = root.join(format!("devices/system/cpu/cpu{}/topology", cpu));
+                let core = cpu & !1;
+
+                fs::create_dir_all(&cpu_dir).unwrap();
+                fs::write(cpu_dir.join("thread_siblings_list"), format!("{}-{}\n", core, core + 1)).unwrap();
+            }
+        }
+
+        // sysfs has a few non-node entries in the same directory
+        fs::write(root.join("devices/system/node/online"), format!("0-{}\n", nodes - 1)).unwrap();
+
+        root
+    }
+
+    fn line_distances(node: usize, nodes: usize) -> Vec<u32> {
+        (0..nodes).map(|other| LOCAL_DISTANCE + 10 * (node as i64 - other as i64).unsigned_abs() as u32).collect()
+    }
+
+    #[test]
+    pub fn test_parse_cpulist() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n").unwrap(), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpulist("5").unwrap(), vec![5]);
+        assert_eq!(parse_cpulist("\n").unwrap(), Vec::<u32>::new());
+        assert!(parse_cpulist("1-x").is_err());
+    }
+
+    #[test]
+    pub fn test_fake_machines() {
+        for &nodes in [2, 4, 8].iter() {
+            let root = fake_sysfs(&format!("machine{}", nodes), nodes, 4);
+            let topology = Topology::from_sysfs(&root).unwrap();
+
+            assert_eq!(topology.node_ids(), (0..nodes as u32).collect::<Vec<_>>());
+            assert_eq!(topology.cpu_count(), nodes * 4);
+            assert_eq!(topology, Topology {
+                nodes: topology.nodes.iter().map(|node| Node {
+                    id: node.id,
+                    cpus: Topology::fake(nodes, 4).node(node.id).unwrap().cpus.clone(),
+                    cores: node.cpus.chunks(2).map(|core| core.to_vec()).collect(),
+                    mem_total_kb: 1 << 20,
+                    mem_free_kb: 1 << 19,
+                    hugepages: vec![
+                        HugePages { page_size_kb: 2048, total: 0, free: 0 },
+                        HugePages { page_size_kb: 1048576, total: 64, free: 60 }
+                    ],
+                    distances: line_distances(node.id as usize, nodes)
+                }).collect()
+            });
+
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_nearest_nodes() {
+        let root = fake_sysfs("distances", 4, 1);
+        let topology = Topology::from_sysfs(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(topology.distance(1, 1), Some(LOCAL_DISTANCE));
+        assert_eq!(topology.distance(0, 3), Some(40));
+        assert_eq!(topology.distance(0, 4), None);
+
+        assert_eq!(topology.nearest_nodes(0), vec![1, 2, 3]);
+        assert_eq!(topology.nearest_nodes(2), vec![1, 3, 0]);
+        assert_eq!(Topology::fake(3, 1).nearest_nodes(1), vec![0, 2]);
+    }
+
+    #[test]
+    pub fn test_cores() {
+        let root = fake_sysfs("cores", 2, 4);
+        let topology = Topology::from_sysfs(&root).unwrap();
+
+        assert_eq!(topology.node(1).unwrap().cores, vec![vec![4, 5], vec![6, 7]]);
+
+        // a CPU with no siblings list is a core of its own
+        fs::remove_file(root.join("devices/system/cpu/cpu6/topology/thread_siblings_list")).unwrap();
+        fs::remove_file(root.join("devices/system/cpu/cpu7/topology/thread_siblings_list")).unwrap();
+        let topology = Topology::from_sysfs(&root).unwrap();
+        assert_eq!(topology.node(1).unwrap().cores, vec![vec![4, 5], vec![6], vec![7]]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_cgroup_cpu_quota() {
+        let root = fake_sysfs("quota", 1, 2);
+        let cgroup = root.join("fs/cgroup");
+        const V1_AND_V2: &str = "4:cpu,cpuacct:/\n0::/\n";
+        assert_eq!(cgroup_cpu_quota_of(&root, V1_AND_V2), None);
+
+        fs::create_dir_all(cgroup.join("cpu")).unwrap();
+        fs::write(cgroup.join("cpu/cpu.cfs_quota_us"), "-1\n").unwrap();
+        fs::write(cgroup.join("cpu/cpu.cfs_period_us"), "100000\n").unwrap();
+        assert_eq!(cgroup_cpu_quota_of(&root, V1_AND_V2), None);
+
+        fs::write(cgroup.join("cpu/cpu.cfs_quota_us"), "250000\n").unwrap();
+        assert_eq!(cgroup_cpu_quota_of(&root, V1_AND_V2), Some(2.5));
+
+        // v2 takes precedence
+        fs::write(cgroup.join("cpu.max"), "max 100000\n").unwrap();
+        assert_eq!(cgroup_cpu_quota_of(&root, V1_AND_V2), None);
+
+        fs::write(cgroup.join("cpu.max"), "150000 100000\n").unwrap();
+        assert_eq!(cgroup_cpu_quota_of(&root, V1_AND_V2), Some(1.5));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_nested_cgroup_cpu_quota() {
+        let root = fake_sysfs("nested-quota", 1, 2);
+        let cgroup = root.join("fs/cgroup");
+
+        // v2: the tightest limit on the way up from our own cgroup
+        fs::create_dir_all(cgroup.join("a/b/c")).unwrap();
+        fs::write(cgroup.join("a/cpu.max"), "200000 100000\n").unwrap();
+        fs::write(cgroup.join("a/b/cpu.max"), "max 100000\n").unwrap();
+        fs::write(cgroup.join("a/b/c/cpu.max"), "max 100000\n").unwrap();
+        assert_eq!(cgroup_cpu_quota_of(&root, "0::/a/b/c\n"), Some(2.0));
+        // a sibling's limit doesn't count
+        assert_eq!(cgroup_cpu_quota_of(&root, "0::/b\n"), None);
+        assert_eq!(cgroup_cpu_quota_of(&root, "0::/\n"), None);
+        fs::remove_dir_all(&cgroup).unwrap();
+
+        // v1: the hierarchy with the cpu controller, mounted under its controllers' name
+        let cpu = cgroup.join("cpu,cpuacct");
+        fs::create_dir_all(cpu.join("x/y")).unwrap();
+        for (dir, quota) in [("", "400000"), ("x", "-1"), ("x/y", "150000")] {
+            fs::write(cpu.join(dir).join("cpu.cfs_quota_us"), format!("{}\n", quota)).unwrap();
+            fs::write(cpu.join(dir).join("cpu.cfs_period_us"), "100000\n").unwrap();
+        }
+        let membership = "5:memory:/elsewhere\n4:cpu,cpuacct:/x/y\n0::/\n";
+        assert_eq!(cgroup_cpu_quota_of(&root, membership), Some(1.5));
+        assert_eq!(cgroup_cpu_quota_of(&root, "4:cpu,cpuacct:/x\n"), Some(4.0));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_process_affinity() {
+        let allowed = process_affinity().unwrap();
+        assert!(!allowed.is_empty());
+    }
+
+    #[test]
+    pub fn test_missing_sysfs() {
+        let root = std::env::temp_dir().join("pseudoprimes-sysfs-does-not-exist");
+        assert!(Topology::from_sysfs(&root).is_err());
+    }
+
+    #[test]
+    pub fn test_this_machine() {
+        let topology = Topology::discover();
+        assert!(!topology.nodes.is_empty());
+        assert!(topology.cpu_count() > 0);
+    }
+}
@@ -0,0 +1,58 @@
+// signals.rs Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Raising signals affects the whole process, so this runs in a test binary of its own,
+// where no other test can be watching for them.
+
+#![cfg(unix)]
+
+extern crate libc;
+extern crate pseudoprimes;
+
+use pseudoprimes::cancel::{CancelReason, CancellationToken};
+
+/// the handler currently installed for `signal`
+fn handler(signal: libc::c_int) -> libc::sighandler_t {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(signal, std::ptr::null(), &mut action);
+        action.sa_sigaction
+    }
+}
+
+#[test]
+pub fn test_signal() {
+    assert_eq!(handler(libc::SIGTERM), libc::SIG_DFL);
+
+    let token = CancellationToken::on_signals();
+    let child = token.child(None);
+    let unwatched = CancellationToken::new();
+    assert_ne!(handler(libc::SIGTERM), libc::SIG_DFL);
+
+    unsafe {
+        libc::raise(libc::SIGTERM);
+    }
+
+    assert!(child.is_cancelled());
+    assert_eq!(child.reason(), Some(CancelReason::Signal(libc::SIGTERM)));
+    assert!(!unwatched.is_cancelled());
+
+    // a token made after the signal doesn't see it, and keeps the handlers installed
+    let later = CancellationToken::on_signals();
+    drop(token);
+    drop(child);
+    assert!(!later.is_cancelled());
+    assert_ne!(handler(libc::SIGINT), libc::SIG_DFL);
+
+    unsafe {
+        libc::raise(libc::SIGINT);
+    }
+    assert_eq!(later.reason(), None);
+    assert!(later.is_cancelled());
+    assert_eq!(later.reason(), Some(CancelReason::Signal(libc::SIGINT)));
+
+    // with nothing watching, the default handlers are back
+    drop(later);
+    assert_eq!(handler(libc::SIGINT), libc::SIG_DFL);
+    assert_eq!(handler(libc::SIGTERM), libc::SIG_DFL);
+}